@@ -5,9 +5,11 @@ use serde_json::json;
 use twitch_api::eventsub::EventType;
 
 use crate::data::{
-	Config, StreamData, StreamResponse, Token, UserData, UserResponse, ValidationResponse,
+	AuthMode, BadgeResponse, ClipResponse, Config, StreamData, StreamResponse, SubscriptionResponse,
+	Token, UserData, UserResponse, ValidationResponse, VideoResponse,
 };
 use crate::err::Error;
+use crate::metrics::metrics;
 
 pub struct Api {
 	c: reqwest::Client,
@@ -17,39 +19,36 @@ pub struct Api {
 }
 
 fn createClient(config: &Config, token: &Token) -> Result<Client, Error> {
-	let defaultHeaders = reqwest::header::HeaderMap::from_iter(
-		[
-			(
-				header::AUTHORIZATION,
-				header::HeaderValue::from_str(&format!("Bearer {}", token.access_token))
-					.expect("failed serializing header value"),
-			),
-			(
-				header::HeaderName::from_str("Client-Id").unwrap(),
-				header::HeaderValue::from_str(&config.clientId).unwrap(),
-			),
-			(
-				header::CONTENT_TYPE,
-				header::HeaderValue::from_str("application/json").unwrap(),
-			),
-		]
-		.into_iter(),
-	);
+	let defaultHeaders = reqwest::header::HeaderMap::from_iter([
+		(
+			header::AUTHORIZATION,
+			header::HeaderValue::from_str(&format!("Bearer {}", token.access_token))
+				.expect("failed serializing header value"),
+		),
+		(
+			header::HeaderName::from_str("Client-Id").unwrap(),
+			header::HeaderValue::from_str(&config.clientId).unwrap(),
+		),
+		(
+			header::CONTENT_TYPE,
+			header::HeaderValue::from_str("application/json").unwrap(),
+		),
+	]);
 	let c = reqwest::Client::builder()
 		.default_headers(defaultHeaders)
 		.build()
 		.expect("failed to create client");
 
-	return Ok(c);
+	Ok(c)
 }
 
 impl Api {
 	pub fn init(token: Token, config: &Config) -> Self {
 		let config = config.clone();
-		let mut base = "https://api.twitch.tv/helix";
-		if let Some(b) = &config.baseUrl {
-			base = b;
-		}
+		let base = config
+			.baseUrl
+			.as_deref()
+			.unwrap_or(crate::config::DEFAULT_BASE_URL);
 
 		let c = createClient(&config, &token).unwrap();
 
@@ -57,11 +56,24 @@ impl Api {
 			c,
 			base: base.to_string(),
 			token,
-			config: config,
+			config,
 		}
 	}
 
+	pub fn setToken(&mut self, token: Token) {
+		self.c = createClient(&self.config, &token).unwrap();
+		self.token = token;
+	}
+
 	pub async fn refreshToken(&mut self) -> Result<Token, Error> {
+		if self.config.authMode == AuthMode::App {
+			// nothing to refresh with, just reissue
+			let token =
+				crate::token::getAppToken(&self.config.clientId, &self.config.clientSecret).await?;
+			self.setToken(token.clone());
+			return Ok(token);
+		}
+
 		let authClient = reqwest::Client::new();
 
 		let req = authClient
@@ -74,7 +86,10 @@ impl Api {
 			])
 			.build()
 			.expect("failed to build token refresh req");
-		let resp = authClient.execute(req).await.expect("failed token refresh");
+		let resp = authClient
+			.execute(req)
+			.await
+			.map_err(|err| Error::Auth(err.to_string()))?;
 		let status = resp.status();
 
 		log::trace!("[TKNR] {}", &status);
@@ -82,21 +97,75 @@ impl Api {
 			return Err(Error::ExpiredAuth);
 		}
 
-		let text = &resp.text().await.unwrap();
-		let token: Token = serde_json::from_str(text).expect("failed to parse token response");
+		let text = resp
+			.text()
+			.await
+			.map_err(|err| Error::Auth(err.to_string()))?;
+		if !status.is_success() {
+			return Err(Error::Auth(format!("{status}: {}", text.trim())));
+		}
+		let token: Token = serde_json::from_str(&text)
+			.map_err(|err| Error::Auth(format!("malformed token response: {err}")))?;
 		self.token = token.clone();
 		self.c = createClient(&self.config, &self.token).unwrap();
 
 		Ok(token)
 	}
 
+	/// Executes a request, recording its latency, status and the rate limit left for metrics
+	async fn execute(&self, req: reqwest::Request) -> reqwest::Result<reqwest::Response> {
+		let url = req.url().as_str();
+		let endpoint = url
+			.strip_prefix(self.base.as_str())
+			.map_or(req.url().path(), |path| {
+				path.split('?').next().unwrap_or_default()
+			})
+			.trim_start_matches('/')
+			.to_string();
+		let start = std::time::Instant::now();
+		let resp = self.c.execute(req).await?;
+
+		metrics().helixRequest(&endpoint, resp.status().as_u16(), start.elapsed());
+		if let Some(remaining) = resp
+			.headers()
+			.get("ratelimit-remaining")
+			.and_then(|value| value.to_str().ok())
+			.and_then(|value| value.parse().ok())
+		{
+			metrics().rateLimitRemaining(remaining);
+		}
+
+		Ok(resp)
+	}
+
+	/// Runs a helix request and parses its json body, failures come back as errors rather than panics
+	async fn fetch<T: serde::de::DeserializeOwned>(&self, req: reqwest::Request) -> Result<T, Error> {
+		let resp = self
+			.execute(req)
+			.await
+			.map_err(|err| Error::Helix(err.to_string()))?;
+		let status = resp.status();
+		if status == StatusCode::UNAUTHORIZED {
+			return Err(Error::UnAuthorised);
+		}
+		let text = resp
+			.text()
+			.await
+			.map_err(|err| Error::Helix(err.to_string()))?;
+		if !status.is_success() {
+			return Err(Error::Helix(format!("{status}: {text}")));
+		}
+
+		serde_json::from_str(&text).map_err(|err| Error::Helix(err.to_string()))
+	}
+
 	pub async fn validate(&self) -> Result<ValidationResponse, Error> {
 		let req = self
 			.c
 			.get("https://id.twitch.tv/oauth2/validate")
 			.build()
 			.expect("failed to build login get");
-		let resp = self.c.execute(req).await.expect("failed to validate token");
+		let resp = self.execute(req).await.expect("failed to validate token");
 		let status = resp.status();
 		let text = resp.text().await.unwrap();
 
@@ -106,6 +175,7 @@ impl Api {
 		}
 
 		let resp: ValidationResponse = serde_json::from_str(&text).unwrap();
+		metrics().tokenExpiresIn(resp.expires_in);
 
 		Ok(resp)
 	}
@@ -117,23 +187,83 @@ impl Api {
 			.collect::<Vec<(&str, &str)>>();
 		let req = self
 			.c
-			.get("https://api.twitch.tv/helix/streams")
+			.get(format!("{}/streams", self.base))
 			.query(&logins)
 			.build()
 			.expect("failed to build streams get");
-		let resp = self
+		let json: StreamResponse = self.fetch(req).await?;
+
+		Ok(json.data)
+	}
+
+	/// A page of videos of a user, newest first; `kind` is `archive`, `highlight`, `upload` or `all`
+	pub async fn getVideos(
+		&self,
+		userId: &str,
+		kind: &str,
+		first: usize,
+		after: Option<&str>,
+	) -> Result<VideoResponse, Error> {
+		let mut query = vec![
+			("user_id", userId.to_string()),
+			("type", kind.to_string()),
+			("first", first.to_string()),
+		];
+		if let Some(after) = after {
+			query.push(("after", after.to_string()));
+		}
+		let req = self
 			.c
-			.execute(req)
-			.await
-			.expect("failed to fetch streams data");
-		let status = resp.status();
-		if status == StatusCode::UNAUTHORIZED {
-			return Err(Error::UnAuthorised);
+			.get(format!("{}/videos", self.base))
+			.query(&query)
+			.build()
+			.expect("failed to build videos get");
+
+		self.fetch(req).await
+	}
+
+	/// A page of the most viewed clips of a broadcaster, within the time range when given
+	pub async fn getClips(
+		&self,
+		broadcasterId: &str,
+		range: Option<(chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>)>,
+		first: usize,
+		after: Option<&str>,
+	) -> Result<ClipResponse, Error> {
+		let mut query = vec![
+			("broadcaster_id", broadcasterId.to_string()),
+			("first", first.to_string()),
+		];
+		if let Some((startedAt, endedAt)) = range {
+			query.push(("started_at", startedAt.to_rfc3339()));
+			query.push(("ended_at", endedAt.to_rfc3339()));
 		}
-		let text = resp.text().await.unwrap();
-		let json: StreamResponse = serde_json::from_str(&text).unwrap();
+		if let Some(after) = after {
+			query.push(("after", after.to_string()));
+		}
+		let req = self
+			.c
+			.get(format!("{}/clips", self.base))
+			.query(&query)
+			.build()
+			.expect("failed to build clips get");
 
-		Ok(json.data)
+		self.fetch(req).await
+	}
+
+	/// Chat badge sets of the broadcaster, or the global ones without one
+	pub async fn getBadges(&self, broadcasterId: Option<&str>) -> Result<BadgeResponse, Error> {
+		let req = match broadcasterId {
+			Some(id) => self
+				.c
+				.get(format!("{}/chat/badges", self.base))
+				.query(&[("broadcaster_id", id)]),
+			None => self.c.get(format!("{}/chat/badges/global", self.base)),
+		}
+		.build()
+		.expect("failed to build badges get");
+
+		self.fetch(req).await
 	}
 
 	pub async fn getUsers(&self, login: &[&str]) -> Result<Vec<UserData>, Error> {
@@ -143,21 +273,11 @@ impl Api {
 			.collect::<Vec<(&str, &str)>>();
 		let getLogin = self
 			.c
-			.get("https://api.twitch.tv/helix/users")
+			.get(format!("{}/users", self.base))
 			.query(&logins)
 			.build()
 			.expect("failed to build login get");
-		let loginResp = self
-			.c
-			.execute(getLogin)
-			.await
-			.expect("failed to fetch login data");
-		let status = loginResp.status();
-		if status == StatusCode::UNAUTHORIZED {
-			return Err(Error::UnAuthorised);
-		}
-		let text = loginResp.text().await.unwrap();
-		let json: UserResponse = serde_json::from_str(&text).unwrap();
+		let json: UserResponse = self.fetch(getLogin).await?;
 
 		Ok(json.data)
 	}
@@ -165,21 +285,11 @@ impl Api {
 	pub async fn getUser(&self, login: &str) -> Result<UserData, Error> {
 		let getLogin = self
 			.c
-			.get("https://api.twitch.tv/helix/users")
+			.get(format!("{}/users", self.base))
 			.query(&[("login", login)])
 			.build()
 			.expect("failed to build login get");
-		let loginResp = self
-			.c
-			.execute(getLogin)
-			.await
-			.expect("failed to fetch login data");
-		let status = loginResp.status();
-		if status == StatusCode::UNAUTHORIZED {
-			return Err(Error::UnAuthorised);
-		}
-		let text = loginResp.text().await.unwrap();
-		let json: UserResponse = serde_json::from_str(&text).unwrap();
+		let json: UserResponse = self.fetch(getLogin).await?;
 
 		if let Some(user) = json.data.into_iter().nth(0) {
 			Ok(user)
@@ -211,33 +321,74 @@ impl Api {
 			.body(body)
 			.build()
 			.unwrap();
-		let resp = self.c.execute(sub).await.unwrap();
+		let resp = self.execute(sub).await.unwrap();
 		let status = resp.status();
 		let text = resp.text().await.unwrap();
 		if status != StatusCode::ACCEPTED {
 			log::error!("[subF] {} {:?}", status, text);
-		} else {
-			log::info!("[SUBK] {} {}", eType, condition);
+			return Err(Error::Subscription(format!("{eType} {status} {text}")));
 		}
+		log::info!("[SUBK] {} {}", eType, condition);
 
 		Ok(())
 	}
 
-	pub async fn getSubscribtions(&self) -> Result<(), Error> {
+	pub async fn getSubscribtions(&self) -> Result<SubscriptionResponse, Error> {
 		let sub = self
 			.c
 			.get(format!("{}/eventsub/subscriptions", self.base))
 			.build()
 			.unwrap();
-		let resp = self.c.execute(sub).await.unwrap();
+		let resp = self
+			.execute(sub)
+			.await
+			.map_err(|err| Error::Helix(err.to_string()))?;
 		let status = resp.status();
-		let text = resp.text().await.unwrap();
-		log::info!("[SUBL] {} {:?}", status, text);
+		let text = resp
+			.text()
+			.await
+			.map_err(|err| Error::Helix(err.to_string()))?;
+		log::trace!("[SUBL] {} {:?}", status, text);
+		if status == StatusCode::UNAUTHORIZED {
+			return Err(Error::UnAuthorised);
+		}
+		if !status.is_success() {
+			return Err(Error::Helix(format!("{status}: {text}")));
+		}
+
+		serde_json::from_str(&text).map_err(|err| Error::Helix(err.to_string()))
+	}
+
+	pub async fn deleteSubscription(&self, id: &str) -> Result<(), Error> {
+		let req = self
+			.c
+			.delete(format!("{}/eventsub/subscriptions", self.base))
+			.query(&[("id", id)])
+			.build()
+			.unwrap();
+		let resp = self.execute(req).await.unwrap();
+		let status = resp.status();
+		if status == StatusCode::UNAUTHORIZED {
+			return Err(Error::UnAuthorised);
+		}
+		if status != StatusCode::NO_CONTENT {
+			log::error!(
+				"[SUBD] {} {:?}",
+				status,
+				resp.text().await.unwrap_or_default()
+			);
+		} else {
+			log::info!("[SUBD] {id}");
+		}
 
 		Ok(())
 	}
 }
 
+/// Pause before the n-th retry, grows linearly so rate limited endpoints get a breather
+const RETRY_STEP: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// Runs `f` until it succeeds, at most `retries` more times after the first failure
 pub async fn try_request<T, E, Fut, F: FnMut() -> Fut>(mut f: F, retries: i32) -> Result<T, E>
 where
 	Fut: Future<Output = Result<T, E>>,
@@ -249,10 +400,39 @@ where
 		if result.is_ok() {
 			break result;
 		} else {
-			if count > retries {
+			if count >= retries {
 				break result;
 			}
 			count += 1;
+			tokio::time::sleep(RETRY_STEP * count as u32).await;
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	async fn attempts(retries: i32, failures: usize) -> (Result<(), ()>, usize) {
+		let mut count = 0;
+		let result = try_request(
+			|| {
+				count += 1;
+				let result = if count > failures { Ok(()) } else { Err(()) };
+				async move { result }
+			},
+			retries,
+		)
+		.await;
+
+		(result, count)
+	}
+
+	#[tokio::test]
+	async fn retriesAsOftenAsAsked() {
+		assert_eq!(attempts(0, 5).await, (Err(()), 1));
+		assert_eq!(attempts(1, 5).await, (Err(()), 2));
+		assert_eq!(attempts(3, 1).await, (Ok(()), 2));
+		assert_eq!(attempts(3, 0).await, (Ok(()), 1));
+	}
+}
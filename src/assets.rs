@@ -0,0 +1,358 @@
+use std::{
+	collections::{BTreeMap, HashSet},
+	path::PathBuf,
+	sync::Mutex,
+};
+
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::{
+	api::Api,
+	data::{Config, EmoteProvider, UserData},
+};
+
+pub const MANIFEST: &str = "manifest.json";
+
+/// An archived image, `file` is named after the sha256 of its content so repeats are stored once
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+pub struct Asset {
+	pub file: String,
+	pub name: String,
+	pub url: String,
+	pub fetchedAt: DateTime<Utc>,
+}
+
+fn providerName(provider: EmoteProvider) -> &'static str {
+	match provider {
+		EmoteProvider::Bttv => "bttv",
+		EmoteProvider::Ffz => "ffz",
+		EmoteProvider::SevenTv => "7tv",
+	}
+}
+
+fn extension(contentType: Option<&str>) -> &'static str {
+	match contentType.unwrap_or_default() {
+		"image/png" => "png",
+		"image/gif" => "gif",
+		"image/webp" => "webp",
+		"image/avif" => "avif",
+		"image/jpeg" => "jpg",
+		_ => "bin",
+	}
+}
+
+/// (id, name, image url) of the emotes in a third party emote set response
+fn thirdPartyEmotes(provider: EmoteProvider, body: &Value) -> Vec<(String, String, String)> {
+	let text = |value: &Value| match value {
+		Value::String(text) => Some(text.clone()),
+		Value::Number(number) => Some(number.to_string()),
+		_ => None,
+	};
+	match provider {
+		// a channel carries its own and shared emotes, the global set is a bare list
+		EmoteProvider::Bttv => [&body["channelEmotes"], &body["sharedEmotes"], body]
+			.into_iter()
+			.filter_map(Value::as_array)
+			.flatten()
+			.filter_map(|emote| {
+				let id = text(&emote["id"])?;
+				let url = format!("https://cdn.betterttv.net/emote/{id}/3x");
+				Some((id, text(&emote["code"])?, url))
+			})
+			.collect(),
+		EmoteProvider::Ffz => body["sets"]
+			.as_object()
+			.into_iter()
+			.flat_map(|sets| sets.values())
+			.filter_map(|set| set["emoticons"].as_array())
+			.flatten()
+			.filter_map(|emote| {
+				// urls are keyed by scale, the largest is wanted
+				let urls = emote["urls"].as_object()?;
+				let url = urls
+					.iter()
+					.max_by_key(|(scale, _)| scale.parse::<u32>().unwrap_or_default())?
+					.1
+					.as_str()?;
+				let url = match url.starts_with("//") {
+					true => format!("https:{url}"),
+					false => url.to_string(),
+				};
+				Some((text(&emote["id"])?, text(&emote["name"])?, url))
+			})
+			.collect(),
+		EmoteProvider::SevenTv => [&body["emote_set"]["emotes"], &body["emotes"]]
+			.into_iter()
+			.filter_map(Value::as_array)
+			.flatten()
+			.filter_map(|emote| {
+				let host = emote["data"]["host"]["url"].as_str()?;
+				let url = format!("https:{host}/4x.webp");
+				Some((text(&emote["id"])?, text(&emote["name"])?, url))
+			})
+			.collect(),
+	}
+}
+
+/// Where the emote sets of a channel, or the global ones, live with each provider
+fn thirdPartyUrl(provider: EmoteProvider, userId: Option<&str>) -> String {
+	match (provider, userId) {
+		(EmoteProvider::Bttv, Some(id)) => {
+			format!("https://api.betterttv.net/3/cached/users/twitch/{id}")
+		}
+		(EmoteProvider::Bttv, None) => "https://api.betterttv.net/3/cached/emotes/global".to_string(),
+		(EmoteProvider::Ffz, Some(id)) => format!("https://api.frankerfacez.com/v1/room/id/{id}"),
+		(EmoteProvider::Ffz, None) => "https://api.frankerfacez.com/v1/set/global".to_string(),
+		(EmoteProvider::SevenTv, Some(id)) => format!("https://7tv.io/v3/users/twitch/{id}"),
+		(EmoteProvider::SevenTv, None) => "https://7tv.io/v3/emote-sets/global".to_string(),
+	}
+}
+
+/// Content-addressed image store next to the chat archives, `manifest.json` maps asset keys to files;
+/// keys are `emote/<id>`, `badge/<set>/<version>` for global badges, `badge/<broadcaster id>/<set>/<version>`
+/// for channel badges and `<provider>/<id>` for third party emotes
+pub struct Store {
+	dir: PathBuf,
+	providers: Vec<EmoteProvider>,
+	c: reqwest::Client,
+	manifest: Mutex<BTreeMap<String, Asset>>,
+	/// keys being downloaded, so a burst of the same emote is fetched once
+	pending: Mutex<HashSet<String>>,
+	/// one manifest write at a time, a later one always carries the newer content
+	saving: tokio::sync::Mutex<()>,
+}
+
+impl Store {
+	/// None when asset archiving isn't configured
+	pub fn new(config: &Config) -> Option<Self> {
+		let settings = config.assets.as_ref()?;
+		let dir = match &settings.dir {
+			Some(dir) => PathBuf::from(dir),
+			None => crate::chat::root(config).join("assets"),
+		};
+		let manifest = std::fs::read(dir.join(MANIFEST))
+			.ok()
+			.and_then(|bytes| match serde_json::from_slice(&bytes) {
+				Ok(manifest) => Some(manifest),
+				Err(err) => {
+					warn!("[ASST] ignoring unreadable manifest: {err}");
+					None
+				}
+			})
+			.unwrap_or_default();
+
+		Some(Store {
+			dir,
+			providers: settings.thirdParty.clone().unwrap_or_default(),
+			c: reqwest::Client::new(),
+			manifest: Mutex::new(manifest),
+			pending: Mutex::new(HashSet::new()),
+			saving: tokio::sync::Mutex::new(()),
+		})
+	}
+
+	pub fn has(&self, key: &str) -> bool {
+		self.manifest.lock().unwrap().contains_key(key)
+	}
+
+	/// Claims a key for download, false when it's archived or on its way already
+	fn claim(&self, key: &str) -> bool {
+		!self.has(key) && self.pending.lock().unwrap().insert(key.to_string())
+	}
+
+	async fn download(&self, url: &str) -> Result<(Vec<u8>, &'static str), reqwest::Error> {
+		let resp = self.c.get(url).send().await?.error_for_status()?;
+		let ext = extension(
+			resp
+				.headers()
+				.get(reqwest::header::CONTENT_TYPE)
+				.and_then(|value| value.to_str().ok()),
+		);
+
+		Ok((resp.bytes().await?.to_vec(), ext))
+	}
+
+	/// Downloads the image behind `key` unless it's archived already, true when it was added
+	async fn fetch(&self, key: &str, name: &str, url: &str) -> bool {
+		if !self.claim(key) {
+			return false;
+		}
+		let added = match self.download(url).await {
+			Ok((bytes, ext)) => {
+				let file = format!("{}.{ext}", hex::encode(Sha256::digest(&bytes)));
+				let path = self.dir.join(&file);
+				let written = match path.exists() {
+					true => Ok(()),
+					false => tokio::fs::create_dir_all(&self.dir)
+						.await
+						.and(tokio::fs::write(&path, &bytes).await),
+				};
+				match written {
+					Ok(()) => {
+						self.record(
+							key,
+							Asset {
+								file,
+								name: name.to_string(),
+								url: url.to_string(),
+								fetchedAt: Utc::now(),
+							},
+						);
+						true
+					}
+					Err(err) => {
+						warn!("[ASST] failed to write {}: {err}", path.display());
+						false
+					}
+				}
+			}
+			Err(err) => {
+				warn!("[ASST] failed to fetch {key} from {url}: {err}");
+				false
+			}
+		};
+		self.pending.lock().unwrap().remove(key);
+
+		added
+	}
+
+	fn record(&self, key: &str, asset: Asset) {
+		self.manifest.lock().unwrap().insert(key.to_string(), asset);
+	}
+
+	/// Writes the manifest once a batch of assets is in, off the runtime threads
+	async fn save(&self) {
+		let _saving = self.saving.lock().await;
+		let manifest = serde_json::to_vec_pretty(&*self.manifest.lock().unwrap()).unwrap();
+		let dir = self.dir.clone();
+		let saved = tokio::task::spawn_blocking(move || {
+			// written aside and moved over so a crash never leaves half a manifest
+			let tmp = dir.join(format!("{MANIFEST}.tmp"));
+			std::fs::write(&tmp, manifest).and_then(|_| std::fs::rename(&tmp, dir.join(MANIFEST)))
+		})
+		.await
+		.unwrap();
+		if let Err(err) = saved {
+			warn!("[ASST] failed to save manifest: {err}");
+		}
+	}
+
+	/// Archives twitch emotes seen in chat, `emotes` are (id, name) pairs
+	pub async fn emotes(&self, emotes: &[(String, String)]) {
+		let mut added = false;
+		for (id, name) in emotes {
+			// the default format is the animated one when there is one
+			let url = format!("https://static-cdn.jtvnw.net/emoticons/v2/{id}/default/dark/3.0");
+			if self.fetch(&format!("emote/{id}"), name, &url).await {
+				info!("[ASST] emote {name} ({id})");
+				added = true;
+			}
+		}
+		if added {
+			self.save().await;
+		}
+	}
+
+	/// Archives the global badges and those of every channel
+	pub async fn badges(&self, api: &tokio::sync::Mutex<Api>, users: &[UserData]) {
+		let mut total = 0;
+		let scopes = std::iter::once(None).chain(users.iter().map(Some));
+		for user in scopes {
+			let sets = match api
+				.lock()
+				.await
+				.getBadges(user.map(|user| user.id.as_str()))
+				.await
+			{
+				Ok(sets) => sets.data,
+				Err(err) => {
+					warn!("[ASST] failed to get badges: {:?}", err);
+					continue;
+				}
+			};
+			let prefix = match user {
+				Some(user) => format!("badge/{}", user.id),
+				None => "badge".to_string(),
+			};
+
+			let mut added = 0;
+			for set in &sets {
+				for version in &set.versions {
+					let key = format!("{prefix}/{}/{}", set.set_id, version.id);
+					added += self
+						.fetch(&key, &version.title, &version.image_url_4x)
+						.await as usize;
+				}
+			}
+			if added > 0 {
+				info!(
+					"[ASST] {added} new badges of {}",
+					user.map_or("twitch", |user| user.login.as_str())
+				);
+			}
+			total += added;
+		}
+		if total > 0 {
+			self.save().await;
+		}
+	}
+
+	/// Archives the configured third party emote sets of every channel plus the global ones
+	pub async fn thirdParty(&self, users: &[UserData]) {
+		let mut total = 0;
+		for provider in &self.providers {
+			let name = providerName(*provider);
+			let scopes = std::iter::once(None).chain(users.iter().map(Some));
+			for user in scopes {
+				let url = thirdPartyUrl(*provider, user.map(|user| user.id.as_str()));
+				let body = async {
+					let resp = self
+						.c
+						.get(&url)
+						.send()
+						.await
+						.map_err(|err| err.to_string())?;
+					// channels without an account there get a 404
+					if resp.status() == reqwest::StatusCode::NOT_FOUND {
+						return Ok(None);
+					}
+					let text = resp
+						.error_for_status()
+						.map_err(|err| err.to_string())?
+						.text()
+						.await
+						.map_err(|err| err.to_string())?;
+					serde_json::from_str::<Value>(&text)
+						.map(Some)
+						.map_err(|err| err.to_string())
+				};
+				let emotes = match body.await {
+					Ok(Some(body)) => thirdPartyEmotes(*provider, &body),
+					Ok(None) => continue,
+					Err(err) => {
+						warn!("[ASST] failed to get {name} emotes from {url}: {err}");
+						continue;
+					}
+				};
+
+				let mut added = 0;
+				for (id, emote, url) in &emotes {
+					added += self.fetch(&format!("{name}/{id}"), emote, url).await as usize;
+				}
+				if added > 0 {
+					info!(
+						"[ASST] {added} new {name} emotes of {}",
+						user.map_or("global", |user| user.login.as_str())
+					);
+				}
+				total += added;
+			}
+		}
+		if total > 0 {
+			self.save().await;
+		}
+	}
+}
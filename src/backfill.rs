@@ -0,0 +1,343 @@
+use std::{
+	collections::HashSet,
+	path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+
+use crate::{
+	api::Api,
+	data::{BackfillConfig, BackfillKind, ClipData, Config, UserData, VideoData},
+	db::Db,
+	err::Error,
+	record, replay,
+	storage::Storage,
+	subtitles,
+};
+
+pub const DEFAULT_MAX_CLIPS: usize = 20;
+const DEFAULT_KINDS: &[BackfillKind] = &[BackfillKind::Archive, BackfillKind::Highlight];
+/// Largest page helix hands out
+const PAGE_SIZE: usize = 100;
+
+/// A vod or clip of a channel that can be backfilled
+#[derive(Debug, Clone)]
+pub struct Video {
+	pub id: String,
+	pub kind: BackfillKind,
+	pub login: String,
+	pub title: String,
+	pub createdAt: DateTime<Utc>,
+	pub seconds: u64,
+	pub url: String,
+	/// broadcast an archive was recorded from
+	pub streamId: Option<String>,
+}
+
+fn kindName(kind: BackfillKind) -> &'static str {
+	match kind {
+		BackfillKind::Archive => "archive",
+		BackfillKind::Highlight => "highlight",
+		BackfillKind::Upload => "upload",
+		BackfillKind::Clip => "clip",
+	}
+}
+
+impl Video {
+	fn fromVideo(video: VideoData, kind: BackfillKind) -> Self {
+		Video {
+			seconds: parseDuration(&video.duration).unwrap_or_default(),
+			id: video.id,
+			kind,
+			login: video.user_login,
+			title: video.title,
+			createdAt: video.created_at,
+			url: video.url,
+			streamId: video.stream_id,
+		}
+	}
+
+	fn fromClip(clip: ClipData, login: &str) -> Self {
+		Video {
+			id: clip.id,
+			kind: BackfillKind::Clip,
+			login: login.to_string(),
+			title: clip.title,
+			createdAt: clip.created_at,
+			seconds: clip.duration.round() as u64,
+			url: clip.url,
+			streamId: None,
+		}
+	}
+
+	pub fn kindName(&self) -> &'static str {
+		kindName(self.kind)
+	}
+}
+
+/// Seconds of a helix video duration like `1h2m3s`
+pub fn parseDuration(text: &str) -> Option<u64> {
+	let mut total = 0;
+	let mut number = String::new();
+	for c in text.chars() {
+		match c {
+			'0'..='9' => number.push(c),
+			'h' | 'm' | 's' => {
+				let value: u64 = number.parse().ok()?;
+				number.clear();
+				total += value
+					* match c {
+						'h' => 3600,
+						'm' => 60,
+						_ => 1,
+					};
+			}
+			_ => return None,
+		}
+	}
+
+	number.is_empty().then_some(total)
+}
+
+/// `root/<login>/<kind>/<created>_<id>.<ext>`, next to the live recordings of the channel
+pub fn path(root: &Path, video: &Video) -> PathBuf {
+	let ext = match video.kind {
+		BackfillKind::Clip => "mp4",
+		_ => "ts",
+	};
+	root.join(&video.login).join(video.kindName()).join(format!(
+		"{}_{}.{ext}",
+		video.createdAt.format("%Y-%m-%d_%H-%M-%S"),
+		crate::template::sanitise(&video.id)
+	))
+}
+
+fn wanted(settings: &BackfillConfig, video: &Video) -> bool {
+	settings.since.is_none_or(|since| video.createdAt >= since)
+		&& settings.until.is_none_or(|until| video.createdAt < until)
+		&& settings.minSeconds.is_none_or(|min| video.seconds >= min)
+		&& settings.maxSeconds.is_none_or(|max| video.seconds <= max)
+}
+
+/// Every wanted vod of the user plus the top clips, paging through helix
+async fn list(
+	api: &tokio::sync::Mutex<Api>,
+	settings: &BackfillConfig,
+	user: &UserData,
+) -> Result<Vec<Video>, Error> {
+	let kinds = settings.types.as_deref().unwrap_or(DEFAULT_KINDS);
+	let mut videos = Vec::new();
+
+	for kind in kinds.iter().filter(|kind| **kind != BackfillKind::Clip) {
+		let mut cursor = None;
+		loop {
+			let page = api
+				.lock()
+				.await
+				.getVideos(&user.id, kindName(*kind), PAGE_SIZE, cursor.as_deref())
+				.await?;
+			// newest first, a page reaching past `since` is the last one needed
+			let done = page
+				.data
+				.last()
+				.is_none_or(|video| settings.since.is_some_and(|since| video.created_at < since));
+			videos.extend(
+				page
+					.data
+					.into_iter()
+					.map(|video| Video::fromVideo(video, *kind)),
+			);
+			cursor = page.pagination.cursor;
+			if done || cursor.is_none() {
+				break;
+			}
+		}
+	}
+
+	if kinds.contains(&BackfillKind::Clip) {
+		let max = settings.maxClips.unwrap_or(DEFAULT_MAX_CLIPS);
+		let range = settings
+			.since
+			.map(|since| (since, settings.until.unwrap_or_else(Utc::now)));
+		let mut clips = Vec::new();
+		let mut cursor = None;
+		loop {
+			let page = api
+				.lock()
+				.await
+				.getClips(&user.id, range, PAGE_SIZE, cursor.as_deref())
+				.await?;
+			clips.extend(
+				page
+					.data
+					.into_iter()
+					.map(|clip| Video::fromClip(clip, &user.login))
+					.filter(|clip| wanted(settings, clip)),
+			);
+			cursor = page.pagination.cursor;
+			if clips.len() >= max || cursor.is_none() {
+				break;
+			}
+		}
+		clips.truncate(max);
+		videos.extend(clips);
+	}
+
+	Ok(
+		videos
+			.into_iter()
+			.filter(|video| wanted(settings, video))
+			.collect(),
+	)
+}
+
+#[derive(Debug, Default)]
+pub struct Summary {
+	pub downloaded: usize,
+	pub skipped: usize,
+	pub failed: usize,
+}
+
+async fn download(config: &Config, video: &Video, path: &Path) -> bool {
+	// streamlink writes next to the final name, a half download never looks finished
+	let mut partial = path.as_os_str().to_os_string();
+	partial.push(".partial");
+	let partial = PathBuf::from(partial);
+	if let Some(parent) = path.parent() {
+		tokio::fs::create_dir_all(parent).await.ok();
+	}
+
+	let result = record::streamlinkVideo(
+		config.streamlinkToken.as_deref(),
+		&video.login,
+		&video.url,
+		None,
+		&partial,
+		&record::Handle::default(),
+	)
+	.await;
+	match result {
+		Ok(status) if status.success() => match tokio::fs::rename(&partial, path).await {
+			Ok(()) => return true,
+			Err(err) => error!("[BKFL] failed to move {}: {err}", partial.display()),
+		},
+		Ok(status) => warn!("[BKFL] {} {}: streamlink {status}", video.login, video.id),
+		Err(err) => error!(
+			"[BKFL] {} {}: failed to run streamlink: {err}",
+			video.login, video.id
+		),
+	}
+	tokio::fs::remove_file(&partial).await.ok();
+
+	false
+}
+
+/// Downloads the wanted vods and clips of the channels that weren't fetched before;
+/// `dryRun` only logs what would be downloaded
+pub async fn run(
+	config: &Config,
+	api: &tokio::sync::Mutex<Api>,
+	db: &Db,
+	storage: &Storage,
+	logins: &[String],
+	dryRun: bool,
+) -> Result<Summary, Error> {
+	if logins.is_empty() {
+		return Ok(Summary::default());
+	}
+	let settings = config.backfill.clone().unwrap_or_default();
+	let root = PathBuf::from(&config.root);
+	let logins = logins.iter().map(String::as_str).collect::<Vec<_>>();
+	let users = api.lock().await.getUsers(&logins).await?;
+	let live = api
+		.lock()
+		.await
+		.getStream(&logins)
+		.await?
+		.into_iter()
+		.map(|stream| stream.id)
+		.collect::<HashSet<_>>();
+
+	let chatSource = replay::Source::gql(config, None);
+	let mut summary = Summary::default();
+	for user in &users {
+		let videos = match list(api, &settings, user).await {
+			Ok(videos) => videos,
+			Err(err) => {
+				error!("[BKFL] failed to list videos of {}: {:?}", user.login, err);
+				continue;
+			}
+		};
+
+		for video in videos {
+			let path = path(&root, &video);
+			// a broadcast still airing is the live recording's, one recorded in full needs no vod
+			if video
+				.streamId
+				.as_ref()
+				.is_some_and(|id| live.contains(id) || db.isRecorded(id))
+				|| path.exists()
+				|| db.isDownloaded(&video.id)
+			{
+				summary.skipped += 1;
+				continue;
+			}
+			info!(
+				"[BKFL] {} {} {} ({} min, {}): {}",
+				video.login,
+				video.kindName(),
+				video.id,
+				video.seconds / 60,
+				video.createdAt.format("%Y-%m-%d"),
+				path.display()
+			);
+			if dryRun {
+				summary.downloaded += 1;
+				continue;
+			}
+
+			// live recordings come first, what's left is downloaded on a later run
+			if let Err(free) = storage.canRecord() {
+				error!("[BKFL] stopping, only {} MiB free", free / 1024 / 1024);
+				return Ok(summary);
+			}
+
+			storage.begin(&path);
+			let downloaded = download(config, &video, &path).await;
+			storage.finish(&path);
+			if !downloaded {
+				summary.failed += 1;
+				continue;
+			}
+			summary.downloaded += 1;
+
+			// clips are too short to carry chat of their own
+			let mut path = path;
+			if settings.chat.unwrap_or(false) && video.kind != BackfillKind::Clip {
+				let chat = replay::path(&path);
+				match replay::fetch(&chatSource, &video.login, &video.id, &chat).await {
+					Ok(_) => {
+						if let Some(subtitles) = &config.subtitles {
+							path = subtitles::forVideo(
+								subtitles,
+								&chat,
+								&path,
+								video.createdAt,
+								video.createdAt + chrono::Duration::seconds(video.seconds as i64),
+							)
+							.await;
+						}
+					}
+					Err(err) => warn!(
+						"[BKFL] {} {}: no chat replay: {:?}",
+						video.login, video.id, err
+					),
+				}
+			}
+			db.downloaded(&video, &path);
+		}
+	}
+
+	Ok(summary)
+}
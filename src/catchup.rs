@@ -0,0 +1,164 @@
+use std::{
+	path::{Path, PathBuf},
+	process::ExitStatus,
+	sync::Arc,
+	time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+
+use crate::{
+	api::Api,
+	control::ActiveRecording,
+	data::{Config, RecordingManifest},
+	record,
+	template::RecordingInfo,
+};
+
+/// Twitch lists the vod of a fresh broadcast a little while after it goes live
+const VOD_ATTEMPTS: u32 = 10;
+const VOD_RETRY: Duration = Duration::from_secs(30);
+/// Starting later than this gets caught up
+const MIN_LATE_SECS: i64 = 10;
+/// The live recording's media start is checked this often until it stops moving
+const SETTLE_CHECK: Duration = Duration::from_secs(10);
+/// Moving less than this between checks counts as settled
+const SETTLE_SECS: i64 = 2;
+/// Longest wait for the live recording to deliver anything at all
+const SETTLE_LIMIT: Duration = Duration::from_secs(300);
+
+/// `<recording without extension>.catchup.<ext>`, shares the retention group of the recording
+pub fn path(firstPart: &Path) -> PathBuf {
+	let mut name = firstPart.file_stem().unwrap_or_default().to_os_string();
+	name.push(".catchup");
+	if let Some(ext) = firstPart.extension() {
+		name.push(".");
+		name.push(ext);
+	}
+	firstPart.with_file_name(name)
+}
+
+pub fn isCatchUp(path: &Path) -> bool {
+	path
+		.file_name()
+		.is_some_and(|name| name.to_string_lossy().contains(".catchup."))
+}
+
+/// Where the live recording begins on the broadcast timeline: the start of a resumed recording as its manifest has it,
+/// otherwise the first frame of `live` once streamlink is done rewinding through the dvr window
+async fn liveStart(
+	info: &RecordingInfo,
+	previous: &[PathBuf],
+	live: &record::Handle,
+) -> DateTime<Utc> {
+	if let Some(first) = previous.first() {
+		return tokio::fs::read(record::manifestPath(first))
+			.await
+			.ok()
+			.and_then(|bytes| serde_json::from_slice::<RecordingManifest>(&bytes).ok())
+			.and_then(|manifest| Some(manifest.parts.first()?.startedAt))
+			.unwrap_or(info.startedAt);
+	}
+
+	let waited = Instant::now();
+	let mut seen: Option<DateTime<Utc>> = None;
+	loop {
+		tokio::time::sleep(SETTLE_CHECK).await;
+		let now = live.mediaStartedAt();
+		match (seen, now) {
+			(Some(before), Some(now)) if (before - now).num_seconds() < SETTLE_SECS => return now,
+			(_, None) if waited.elapsed() >= SETTLE_LIMIT => return info.startedAt,
+			_ => seen = now,
+		}
+	}
+}
+
+/// How much of the vod to fetch so it ends where the live recording begins, None when live-restart reached the start
+fn missed(info: &RecordingInfo, liveStart: DateTime<Utc>) -> Option<Duration> {
+	let late = (liveStart - info.streamStartedAt?).num_seconds();
+	(late >= MIN_LATE_SECS).then(|| Duration::from_secs(late as u64))
+}
+
+/// Archive vod of the broadcast, waiting for twitch to list it
+async fn findVod(api: &tokio::sync::Mutex<Api>, userId: &str, streamId: &str) -> Option<String> {
+	for attempt in 1..=VOD_ATTEMPTS {
+		match api.lock().await.getVideos(userId, "archive", 5, None).await {
+			Ok(videos) => {
+				if let Some(video) = videos
+					.data
+					.into_iter()
+					.find(|video| video.stream_id.as_deref() == Some(streamId))
+				{
+					return Some(video.id);
+				}
+			}
+			Err(err) => warn!("[CTCH] failed to list videos: {:?}", err),
+		}
+		if attempt < VOD_ATTEMPTS {
+			tokio::time::sleep(VOD_RETRY).await;
+		}
+	}
+
+	None
+}
+
+/// Downloads the start of the broadcast the live recording couldn't rewind to from its in-progress vod,
+/// up to where the live recording begins; None when live-restart got the whole broadcast or there is no vod
+/// to catch up from
+pub async fn run(
+	config: &Config,
+	api: &Arc<tokio::sync::Mutex<Api>>,
+	info: &RecordingInfo,
+	userId: &str,
+	path: &Path,
+	previous: &[PathBuf],
+	recording: &ActiveRecording,
+) -> Option<std::io::Result<ExitStatus>> {
+	let handle = &recording.catchUp;
+	if info.streamId.is_empty() || info.streamStartedAt.is_none() {
+		return None;
+	}
+	let Some(duration) = missed(info, liveStart(info, previous, &recording.handle).await) else {
+		info!(
+			"[CTCH] {}: live recording covers the start of the stream",
+			info.login
+		);
+		return None;
+	};
+	let Some(vodId) = findVod(api, userId, &info.streamId).await else {
+		warn!(
+			"[CTCH] {}: no vod of stream {}, start stays missing",
+			info.login, info.streamId
+		);
+		return None;
+	};
+	if handle.stopped() {
+		return None;
+	}
+
+	info!(
+		"[CTCH] {}: fetching the first {} s from vod {vodId} into {}",
+		info.login,
+		duration.as_secs(),
+		path.display()
+	);
+	if let Some(parent) = path.parent() {
+		tokio::fs::create_dir_all(parent).await.ok();
+	}
+	let result = record::streamlinkVideo(
+		config.streamlinkToken.as_deref(),
+		&info.login,
+		&format!("twitch.tv/videos/{vodId}"),
+		Some(duration),
+		path,
+		handle,
+	)
+	.await;
+	match &result {
+		Ok(status) => info!("[CTCH] {}: {status}", info.login),
+		Err(err) => warn!("[CTCH] {}: failed to run streamlink: {err}", info.login),
+	}
+
+	Some(result)
+}
@@ -0,0 +1,88 @@
+use std::{
+	collections::HashMap,
+	fs::File,
+	io::Write,
+	path::{Path, PathBuf},
+	sync::Mutex,
+};
+
+use log::error;
+
+use crate::data::Config;
+
+/// `<chatter>: <text>`, what a chat line carries
+pub fn message(chatter: &str, text: &str) -> String {
+	format!("{chatter}: {text}")
+}
+
+/// A line of the chat archive, live capture and vod replays share it
+pub fn line(time: chrono::DateTime<chrono::Utc>, channel: &str, message: &str) -> String {
+	format!("[{time} ld::chat::{channel}] [CHAT] {message:?}\n")
+}
+
+/// `chatRoot/<login>.log`, where live chat of a channel is archived
+pub fn logPath(config: &Config, channel: &str) -> PathBuf {
+	logFile(&root(config), channel)
+}
+
+/// `chatRoot`, or the default chat folder
+pub fn root(config: &Config) -> PathBuf {
+	PathBuf::from(
+		config
+			.chatRoot
+			.as_deref()
+			.unwrap_or(crate::config::DEFAULT_CHAT_ROOT),
+	)
+}
+
+fn logFile(root: &Path, channel: &str) -> PathBuf {
+	let mut path = root.join(channel);
+	path.set_extension("log");
+	path
+}
+
+/// Appends chat lines to `chatRoot/<login>.log`, independent of the diagnostic logger
+pub struct Archive {
+	root: PathBuf,
+	files: Mutex<HashMap<String, File>>,
+}
+
+impl Archive {
+	pub fn new(config: &Config) -> Self {
+		Archive {
+			root: root(config),
+			files: Mutex::new(HashMap::new()),
+		}
+	}
+
+	pub fn path(&self, channel: &str) -> PathBuf {
+		logFile(&self.root, channel)
+	}
+
+	pub fn write(&self, channel: &str, message: &str) {
+		let mut files = self.files.lock().unwrap();
+		if !files.contains_key(channel) {
+			let opened = std::fs::create_dir_all(&self.root).and_then(|_| {
+				std::fs::OpenOptions::new()
+					.create(true)
+					.append(true)
+					.open(self.path(channel))
+			});
+			match opened {
+				Ok(file) => {
+					files.insert(channel.to_string(), file);
+				}
+				Err(err) => {
+					error!("[CHAT] failed to open log for {channel}: {err}");
+					return;
+				}
+			}
+		}
+
+		let line = line(chrono::Utc::now(), channel, message);
+		if let Err(err) = files.get_mut(channel).unwrap().write_all(line.as_bytes()) {
+			error!("[CHAT] failed to write log for {channel}: {err}");
+			files.remove(channel);
+		}
+	}
+}
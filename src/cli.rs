@@ -0,0 +1,324 @@
+use std::path::PathBuf;
+
+use crate::{
+	api::Api,
+	data::{AuthMode, Config},
+	err::Error,
+	record,
+	template::RecordingInfo,
+	token,
+};
+
+#[derive(clap::Parser, Debug)]
+#[command(name = "ld", version, about = "twitch live downloader")]
+pub struct Cli {
+	/// path to the config file
+	#[arg(long, global = true, default_value = "config.json")]
+	pub config: PathBuf,
+
+	/// path to the token file, overrides `tokenPath` and `LD_TOKEN_PATH`
+	#[arg(long, global = true)]
+	pub token: Option<PathBuf>,
+
+	/// folder for diagnostic logs, overrides `logDir`
+	#[arg(long, global = true)]
+	pub log_dir: Option<PathBuf>,
+
+	/// validate the config and exit, same as the `check-config` command
+	#[arg(long, global = true)]
+	pub check_config: bool,
+
+	#[command(subcommand)]
+	pub command: Option<Command>,
+}
+
+#[derive(clap::Subcommand, Debug, Clone)]
+pub enum Command {
+	/// watch configured broadcasters and record them when they go live (default)
+	Run,
+	/// run the device login and store the token, replacing any existing one
+	Auth,
+	/// validate the config and exit
+	CheckConfig,
+	/// list eventsub subscriptions of the app
+	ListSubscriptions,
+	/// record a single channel right away and exit when the stream ends
+	Record { login: String },
+	/// show token expiry and live status of configured broadcasters
+	Status,
+	/// list past recordings from the state db, newest first
+	History {
+		/// only recordings of this channel
+		login: Option<String>,
+		#[arg(long, default_value_t = 20)]
+		limit: usize,
+	},
+	/// download the chat of a vod into the chat archive format
+	ChatReplay {
+		login: String,
+		video_id: String,
+		/// file to write, `<chatRoot>/<login>/<video id>.log` by default
+		#[arg(long)]
+		output: Option<PathBuf>,
+		/// read pages from `<dir>/<video id>.<page>.json` instead of twitch
+		#[arg(long, conflicts_with = "record_fixtures")]
+		fixtures: Option<PathBuf>,
+		/// also save every page fetched from twitch into the folder, for use with `--fixtures`
+		#[arg(long)]
+		record_fixtures: Option<PathBuf>,
+	},
+	/// download past vods, highlights and clips that aren't on disk yet
+	Backfill {
+		/// only this channel instead of all configured broadcasters
+		login: Option<String>,
+		/// list what would be downloaded without downloading
+		#[arg(long)]
+		dry_run: bool,
+	},
+	/// render a chat log into subtitles for a video per `subtitles`
+	Subtitles {
+		video: PathBuf,
+		/// chat log covering the video, `<video>.chat.log` by default
+		#[arg(long)]
+		chat: Option<PathBuf>,
+		/// when the video starts, rfc3339
+		#[arg(long)]
+		start: chrono::DateTime<chrono::Utc>,
+		/// when the video ends, rfc3339; the rest of the chat log by default
+		#[arg(long)]
+		end: Option<chrono::DateTime<chrono::Utc>>,
+	},
+}
+
+impl Cli {
+	/// Applies command line overrides on top of the loaded config
+	pub fn apply(&self, config: &mut Config) {
+		if let Some(path) = &self.token {
+			config.tokenPath = Some(path.to_string_lossy().to_string());
+		}
+		if let Some(path) = &self.log_dir {
+			config.logDir = Some(path.to_string_lossy().to_string());
+		}
+	}
+
+	pub fn command(&self) -> Command {
+		match (&self.command, self.check_config) {
+			(_, true) => Command::CheckConfig,
+			(Some(command), _) => command.clone(),
+			(None, _) => Command::Run,
+		}
+	}
+}
+
+async fn connect(config: &Config) -> Result<Api, Error> {
+	let store = token::store(config);
+	let token = token::fetchToken(store.as_ref(), config).await?;
+	let mut api = Api::init(token, config);
+
+	if let Err(Error::UnAuthorised) = api.validate().await {
+		let token = api.refreshToken().await?;
+		token::writeRefreshToken(store.as_ref(), &token).await?;
+	}
+
+	Ok(api)
+}
+
+pub async fn auth(config: &Config) -> Result<(), Error> {
+	if config.authMode == AuthMode::App {
+		println!("app auth mode doesn't need a device login");
+		return Ok(());
+	}
+
+	let store = token::store(config);
+	token::authorise(store.as_ref(), config).await?;
+	println!("token saved");
+
+	Ok(())
+}
+
+pub async fn listSubscriptions(config: &Config) -> Result<(), Error> {
+	let subs = connect(config).await?.getSubscribtions().await?;
+
+	println!(
+		"{} subscriptions, cost {}/{}",
+		subs.total, subs.total_cost, subs.max_total_cost
+	);
+	for sub in subs.data {
+		println!(
+			"{} {} v{} {} {} {}",
+			sub.id, sub.sub_type, sub.version, sub.status, sub.created_at, sub.condition
+		);
+	}
+
+	Ok(())
+}
+
+pub async fn record(config: &Config, login: &str) -> Result<(), Error> {
+	let status = record::record(
+		config,
+		&RecordingInfo::bare(login),
+		&[],
+		&record::Handle::default(),
+		|path| println!("recording {login} into {}", path.display()),
+	)
+	.await
+	.map_err(|err| Error::Recording(err.to_string()))?;
+	println!("streamlink finished with {status}");
+
+	match status.success() {
+		true => Ok(()),
+		false => Err(Error::Recording(format!("streamlink exited with {status}"))),
+	}
+}
+
+pub async fn status(config: &Config) -> Result<(), Error> {
+	let api = connect(config).await?;
+
+	let validation = api.validate().await?;
+	let expiry = chrono::Utc::now() + chrono::Duration::seconds(validation.expires_in as i64);
+	println!(
+		"token: {} expires at {} (scopes: {})",
+		validation.login.as_deref().unwrap_or("app"),
+		expiry.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+		validation.scopes.unwrap_or_default().join(" ")
+	);
+
+	let streams = api
+		.getStream(
+			&config
+				.broadcasters
+				.iter()
+				.map(String::as_str)
+				.collect::<Vec<_>>(),
+		)
+		.await?;
+	for broadcaster in &config.broadcasters {
+		match streams.iter().find(|s| &s.user_login == broadcaster) {
+			Some(stream) => println!(
+				"{broadcaster}: live since {} - {} [{}]",
+				stream.started_at, stream.title, stream.game_name
+			),
+			None => println!("{broadcaster}: offline"),
+		}
+	}
+
+	Ok(())
+}
+
+pub async fn history(config: &Config, login: Option<&str>, limit: usize) -> Result<(), Error> {
+	let db = crate::db::Db::open(config).map_err(|err| Error::StateDb(err.to_string()))?;
+
+	for recording in db.recordings(login, limit) {
+		println!(
+			"{} {} {} -> {} [{}{}] {}",
+			recording.id,
+			recording.channel,
+			recording
+				.startedAt
+				.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+			recording
+				.endedAt
+				.map(|ended| ended.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
+				.unwrap_or("-".to_string()),
+			recording.status,
+			recording
+				.exitCode
+				.map(|code| format!(", exit {code}"))
+				.unwrap_or_default(),
+			recording.title
+		);
+		for path in &recording.paths {
+			println!("    {}", path.display());
+		}
+	}
+
+	Ok(())
+}
+
+pub async fn backfill(config: &Config, login: Option<&str>, dryRun: bool) -> Result<(), Error> {
+	let api = tokio::sync::Mutex::new(connect(config).await?);
+	let db = crate::db::Db::open(config).map_err(|err| Error::StateDb(err.to_string()))?;
+	let logins = match login {
+		Some(login) => vec![login.to_string()],
+		None => config
+			.broadcasters
+			.iter()
+			.filter(|login| crate::config::recordsVideo(config, login))
+			.cloned()
+			.collect(),
+	};
+
+	let storage = crate::storage::Storage::new(config);
+	let summary = crate::backfill::run(config, &api, &db, &storage, &logins, dryRun).await?;
+	println!(
+		"{} {}, {} skipped, {} failed",
+		summary.downloaded,
+		match dryRun {
+			true => "to download",
+			false => "downloaded",
+		},
+		summary.skipped,
+		summary.failed
+	);
+
+	Ok(())
+}
+
+pub async fn chatReplay(
+	config: &Config,
+	login: &str,
+	videoId: &str,
+	output: Option<PathBuf>,
+	fixtures: Option<PathBuf>,
+	recordFixtures: Option<PathBuf>,
+) -> Result<(), Error> {
+	let source = match fixtures {
+		Some(dir) => crate::replay::Source::Fixtures(dir),
+		None => crate::replay::Source::gql(config, recordFixtures),
+	};
+	let output = output.unwrap_or_else(|| {
+		PathBuf::from(
+			config
+				.chatRoot
+				.as_deref()
+				.unwrap_or(crate::config::DEFAULT_CHAT_ROOT),
+		)
+		.join(login)
+		.join(format!("{videoId}.log"))
+	});
+
+	let count = crate::replay::fetch(&source, login, videoId, &output).await?;
+	println!("{count} messages written to {}", output.display());
+
+	Ok(())
+}
+
+pub async fn subtitles(
+	config: &Config,
+	video: &std::path::Path,
+	chat: Option<PathBuf>,
+	start: chrono::DateTime<chrono::Utc>,
+	end: Option<chrono::DateTime<chrono::Utc>>,
+) -> Result<(), Error> {
+	let settings = config.subtitles.clone().unwrap_or_default();
+	let chat = chat.unwrap_or_else(|| crate::replay::path(video));
+
+	let output = crate::subtitles::forVideo(
+		&settings,
+		&chat,
+		video,
+		start,
+		end.unwrap_or(chrono::DateTime::<chrono::Utc>::MAX_UTC),
+	)
+	.await;
+	println!(
+		"{}",
+		match output.as_path() == video {
+			true => crate::subtitles::path(video, settings.format),
+			false => output,
+		}
+		.display()
+	);
+
+	Ok(())
+}
@@ -0,0 +1,823 @@
+use std::{fmt, path::Path};
+
+use crate::data::{AuthMode, ChatSource, Config};
+
+pub const DEFAULT_CHAT_ROOT: &str = "chat";
+pub const DEFAULT_SOCKET_URL: &str =
+	"wss://eventsub.wss.twitch.tv/ws?keepalive_timeout_seconds=300";
+pub const DEFAULT_BASE_URL: &str = "https://api.twitch.tv/helix";
+pub const DEFAULT_GQL_URL: &str = "https://gql.twitch.tv/gql";
+pub const DEFAULT_IRC_URL: &str = "wss://irc-ws.chat.twitch.tv:443";
+pub const DEFAULT_POLL_INTERVAL: u64 = 60;
+
+#[derive(Debug)]
+pub struct ConfigError {
+	pub path: String,
+	pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}: {}", self.path, self.message)
+	}
+}
+
+fn issue(path: impl Into<String>, message: impl Into<String>) -> ConfigError {
+	ConfigError {
+		path: path.into(),
+		message: message.into(),
+	}
+}
+
+#[derive(Clone, Copy)]
+enum EnvKind {
+	Text,
+	List,
+	Number,
+}
+
+/// Fields that can be set from `LD_<FIELD>` or read from the file at `LD_<FIELD>_FILE`
+const ENV_FIELDS: &[(&str, EnvKind)] = &[
+	("clientId", EnvKind::Text),
+	("clientSecret", EnvKind::Text),
+	("broadcasters", EnvKind::List),
+	("chatChannels", EnvKind::List),
+	("account", EnvKind::Text),
+	("streamlinkToken", EnvKind::Text),
+	("root", EnvKind::Text),
+	("chatRoot", EnvKind::Text),
+	("socketUrl", EnvKind::Text),
+	("baseUrl", EnvKind::Text),
+	("gqlUrl", EnvKind::Text),
+	("chatSource", EnvKind::Text),
+	("ircUrl", EnvKind::Text),
+	("tokenPath", EnvKind::Text),
+	("stateDb", EnvKind::Text),
+	("tokenPassphrase", EnvKind::Text),
+	("logDir", EnvKind::Text),
+	("extraScopes", EnvKind::List),
+	("authMode", EnvKind::Text),
+	("pollInterval", EnvKind::Number),
+	("filenameTemplate", EnvKind::Text),
+	("upload.endpoint", EnvKind::Text),
+	("upload.bucket", EnvKind::Text),
+	("upload.accessKey", EnvKind::Text),
+	("upload.secretKey", EnvKind::Text),
+	("control.listen", EnvKind::Text),
+	("control.token", EnvKind::Text),
+];
+
+/// `clientSecret` -> `LD_CLIENT_SECRET`, `upload.secretKey` -> `LD_UPLOAD_SECRET_KEY`
+pub fn envName(field: &str) -> String {
+	let mut name = "LD_".to_string();
+	for c in field.chars() {
+		match c {
+			'.' => name.push('_'),
+			c if c.is_ascii_uppercase() => {
+				name.push('_');
+				name.push(c);
+			}
+			c => name.push(c.to_ascii_uppercase()),
+		}
+	}
+	name
+}
+
+/// Sets a dotted field path, creating intermediate maps as needed
+fn insertPath(
+	values: &mut serde_json::Map<String, serde_json::Value>,
+	field: &str,
+	value: serde_json::Value,
+) {
+	match field.split_once('.') {
+		None => {
+			values.insert(field.to_string(), value);
+		}
+		Some((head, rest)) => {
+			let entry = values
+				.entry(head.to_string())
+				.or_insert_with(|| serde_json::Value::Object(Default::default()));
+			if !entry.is_object() {
+				*entry = serde_json::Value::Object(Default::default());
+			}
+			insertPath(entry.as_object_mut().unwrap(), rest, value);
+		}
+	}
+}
+
+fn parseFile(path: &Path, content: &[u8]) -> Result<serde_json::Value, ConfigError> {
+	let location = path.display().to_string();
+	match path.extension().and_then(|ext| ext.to_str()) {
+		Some("toml") => std::str::from_utf8(content)
+			.map_err(|err| issue(&location, err.to_string()))
+			.and_then(|text| toml::from_str(text).map_err(|err| issue(&location, err.to_string()))),
+		Some("yaml" | "yml") => {
+			serde_yaml::from_slice(content).map_err(|err| issue(&location, err.to_string()))
+		}
+		_ => serde_json::from_slice(content).map_err(|err| issue(&location, err.to_string())),
+	}
+}
+
+/// Overlays `LD_*` variables on top of the file values, `*_FILE` variants read the value from a path (docker secrets)
+fn applyEnv(
+	values: &mut serde_json::Map<String, serde_json::Value>,
+	errors: &mut Vec<ConfigError>,
+) {
+	for (field, kind) in ENV_FIELDS {
+		let name = envName(field);
+		let fileName = format!("{name}_FILE");
+
+		let raw = match (std::env::var(&name), std::env::var(&fileName)) {
+			(Ok(value), _) => value,
+			(Err(_), Ok(path)) => match std::fs::read_to_string(&path) {
+				Ok(value) => value.trim_end_matches(['\r', '\n']).to_string(),
+				Err(err) => {
+					errors.push(issue(
+						format!("{fileName} ({field})"),
+						format!("failed to read `{path}`: {err}"),
+					));
+					continue;
+				}
+			},
+			_ => continue,
+		};
+
+		let value = match kind {
+			EnvKind::Text => serde_json::Value::String(raw),
+			EnvKind::List => serde_json::Value::Array(
+				raw
+					.split(',')
+					.map(str::trim)
+					.filter(|item| !item.is_empty())
+					.map(|item| serde_json::Value::String(item.to_string()))
+					.collect(),
+			),
+			EnvKind::Number => match raw.trim().parse::<u64>() {
+				Ok(number) => serde_json::Value::from(number),
+				Err(err) => {
+					errors.push(issue(format!("{name} ({field})"), err.to_string()));
+					continue;
+				}
+			},
+		};
+		insertPath(values, field, value);
+	}
+}
+
+fn hasEnvOverrides() -> bool {
+	ENV_FIELDS.iter().any(|(field, _)| {
+		let name = envName(field);
+		std::env::var_os(&name).is_some() || std::env::var_os(format!("{name}_FILE")).is_some()
+	})
+}
+
+/// Reads json, toml or yaml (by extension), overlays `LD_*` env variables, validates and fills defaults,
+/// reporting every problem found instead of the first one
+pub fn load(path: &Path) -> Result<Config, Vec<ConfigError>> {
+	let values = match std::fs::read(path) {
+		Ok(content) => parseFile(path, &content).map_err(|err| vec![err])?,
+		// a config made purely of env variables is fine
+		Err(err) if err.kind() == std::io::ErrorKind::NotFound && hasEnvOverrides() => {
+			serde_json::Value::Object(Default::default())
+		}
+		Err(err) => return Err(vec![issue(path.display().to_string(), err.to_string())]),
+	};
+	let serde_json::Value::Object(mut values) = values else {
+		return Err(vec![issue(
+			path.display().to_string(),
+			"expected a map of options at the top level",
+		)]);
+	};
+
+	let mut errors = Vec::new();
+	applyEnv(&mut values, &mut errors);
+	if !errors.is_empty() {
+		return Err(errors);
+	}
+
+	let mut config = deserialize(serde_json::Value::Object(values))?;
+
+	let errors = validate(&config);
+	if !errors.is_empty() {
+		return Err(errors);
+	}
+	applyDefaults(&mut config);
+
+	Ok(config)
+}
+
+/// Drops the value at `path`, false when it can't be dropped without shifting the paths of others
+fn removePath(values: &mut serde_json::Value, path: &serde_path_to_error::Path) -> bool {
+	use serde_path_to_error::Segment;
+
+	let segments = path.iter().collect::<Vec<_>>();
+	let Some((last, parents)) = segments.split_last() else {
+		return false;
+	};
+	let mut value = values;
+	for segment in parents {
+		value = match (segment, value) {
+			(Segment::Map { key }, serde_json::Value::Object(map)) => match map.get_mut(key) {
+				Some(value) => value,
+				None => return false,
+			},
+			(Segment::Seq { index }, serde_json::Value::Array(items)) => match items.get_mut(*index) {
+				Some(value) => value,
+				None => return false,
+			},
+			_ => return false,
+		};
+	}
+	match (last, value) {
+		(Segment::Map { key }, serde_json::Value::Object(map)) => map.remove(key).is_some(),
+		_ => false,
+	}
+}
+
+/// Deserialises the config, dropping every value of the wrong type so all of them get reported;
+/// stops early when a dropped value was required or sits in a list
+fn deserialize(mut values: serde_json::Value) -> Result<Config, Vec<ConfigError>> {
+	let mut errors = Vec::new();
+	let mut dropped = Vec::new();
+	loop {
+		let err = match serde_path_to_error::deserialize::<_, Config>(&values) {
+			Ok(config) if errors.is_empty() => return Ok(config),
+			Ok(_) => return Err(errors),
+			Err(err) => err,
+		};
+		let path = err.path().to_string();
+		let message = err.inner().to_string();
+		// the field went missing because it was dropped, its real problem is reported already
+		if dropped
+			.iter()
+			.any(|field: &String| message == format!("missing field `{field}`"))
+		{
+			return Err(errors);
+		}
+		let removed = removePath(&mut values, err.path());
+		if let Some(field) = path.rsplit('.').next() {
+			dropped.push(field.to_string());
+		}
+		let message = suggestField(&message).unwrap_or(message);
+		errors.push(issue(path, message));
+		if !removed {
+			return Err(errors);
+		}
+	}
+}
+
+/// Levenshtein distance, ignoring case
+fn distance(a: &str, b: &str) -> usize {
+	let a = a.to_lowercase().chars().collect::<Vec<_>>();
+	let b = b.to_lowercase().chars().collect::<Vec<_>>();
+	let mut previous = (0..=b.len()).collect::<Vec<_>>();
+	for (i, ca) in a.iter().enumerate() {
+		let mut current = vec![i + 1];
+		for (j, cb) in b.iter().enumerate() {
+			let substitution = previous[j] + usize::from(ca != cb);
+			current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+		}
+		previous = current;
+	}
+
+	previous[b.len()]
+}
+
+/// Turns serde's "unknown field `x`, expected one of ..." into a hint at the option that was probably meant
+fn suggestField(message: &str) -> Option<String> {
+	let rest = message.strip_prefix("unknown field `")?;
+	let (field, expected) = rest.split_once('`')?;
+	let closest = expected
+		.split('`')
+		.skip(1)
+		.step_by(2)
+		.map(|known| (distance(field, known), known))
+		.min()?;
+	// typos, not a different option altogether
+	if closest.0 > (field.len() / 3).max(1) {
+		return None;
+	}
+
+	Some(format!(
+		"unknown option `{field}`, did you mean `{}`?",
+		closest.1
+	))
+}
+
+fn applyDefaults(config: &mut Config) {
+	config
+		.chatRoot
+		.get_or_insert_with(|| DEFAULT_CHAT_ROOT.to_string());
+	config
+		.socketUrl
+		.get_or_insert_with(|| DEFAULT_SOCKET_URL.to_string());
+	config
+		.baseUrl
+		.get_or_insert_with(|| DEFAULT_BASE_URL.to_string());
+	config
+		.gqlUrl
+		.get_or_insert_with(|| DEFAULT_GQL_URL.to_string());
+	config
+		.ircUrl
+		.get_or_insert_with(|| DEFAULT_IRC_URL.to_string());
+	config.pollInterval.get_or_insert(DEFAULT_POLL_INTERVAL);
+	config
+		.filenameTemplate
+		.get_or_insert_with(|| crate::template::DEFAULT_TEMPLATE.to_string());
+}
+
+/// Whether live streams of the broadcaster are recorded, not just its chat logged
+pub fn recordsVideo(config: &Config, login: &str) -> bool {
+	config
+		.broadcasterOptions
+		.as_ref()
+		.and_then(|options| options.get(login))
+		.and_then(|options| options.video)
+		.unwrap_or(true)
+}
+
+/// Twitch logins are 1-25 characters of ascii alphanumerics and underscores
+pub fn isValidLogin(login: &str) -> bool {
+	!login.is_empty()
+		&& login.len() <= 25
+		&& login.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn checkUrl(errors: &mut Vec<ConfigError>, field: &str, value: &str, schemes: &[&str]) {
+	match url::Url::parse(value) {
+		Ok(url) if schemes.contains(&url.scheme()) => {}
+		Ok(url) => errors.push(issue(
+			field,
+			format!(
+				"unsupported scheme `{}`, expected one of {}",
+				url.scheme(),
+				schemes.join(", ")
+			),
+		)),
+		Err(err) => errors.push(issue(field, format!("invalid url: {err}"))),
+	}
+}
+
+fn checkDir(errors: &mut Vec<ConfigError>, field: &str, value: &str) {
+	if value.trim().is_empty() {
+		errors.push(issue(field, "must not be empty"));
+	} else if Path::new(value).exists() && !Path::new(value).is_dir() {
+		errors.push(issue(
+			field,
+			format!("`{value}` exists and is not a directory"),
+		));
+	}
+}
+
+pub fn validate(config: &Config) -> Vec<ConfigError> {
+	let mut errors = Vec::new();
+
+	if config.clientId.trim().is_empty() {
+		errors.push(issue("clientId", "must not be empty"));
+	}
+	if config.clientSecret.trim().is_empty() {
+		errors.push(issue("clientSecret", "must not be empty"));
+	}
+
+	let chatChannels = config.chatChannels.as_deref().unwrap_or_default();
+	if config.broadcasters.is_empty() && chatChannels.is_empty() {
+		errors.push(issue(
+			"broadcasters",
+			"at least one broadcaster or chat channel is required",
+		));
+	}
+	for (i, login) in config.broadcasters.iter().enumerate() {
+		if !isValidLogin(login) {
+			errors.push(issue(
+				format!("broadcasters[{i}]"),
+				format!("`{login}` is not a valid twitch login"),
+			));
+		}
+	}
+	for (i, login) in chatChannels.iter().enumerate() {
+		if !isValidLogin(login) {
+			errors.push(issue(
+				format!("chatChannels[{i}]"),
+				format!("`{login}` is not a valid twitch login"),
+			));
+		}
+	}
+	for login in config
+		.broadcasterOptions
+		.iter()
+		.flat_map(|options| options.keys())
+	{
+		if !config.broadcasters.contains(login) {
+			errors.push(issue(
+				format!("broadcasterOptions.{login}"),
+				"is not one of the broadcasters",
+			));
+		}
+	}
+	if config.authMode == AuthMode::App
+		&& config.chatSource == ChatSource::Eventsub
+		&& (!chatChannels.is_empty()
+			|| config
+				.broadcasters
+				.iter()
+				.any(|login| !recordsVideo(config, login)))
+	{
+		errors.push(issue(
+			"chatChannels",
+			"chat can only be read in user auth mode or with `chatSource: irc`",
+		));
+	}
+
+	match (&config.account, config.authMode) {
+		(None, AuthMode::User) => errors.push(issue("account", "required in user auth mode")),
+		(Some(login), _) if !isValidLogin(login) => errors.push(issue(
+			"account",
+			format!("`{login}` is not a valid twitch login"),
+		)),
+		_ => {}
+	}
+
+	checkDir(&mut errors, "root", &config.root);
+	if let Some(chatRoot) = &config.chatRoot {
+		checkDir(&mut errors, "chatRoot", chatRoot);
+	}
+	if let Some(socketUrl) = &config.socketUrl {
+		checkUrl(&mut errors, "socketUrl", socketUrl, &["ws", "wss"]);
+	}
+	if let Some(baseUrl) = &config.baseUrl {
+		checkUrl(&mut errors, "baseUrl", baseUrl, &["http", "https"]);
+	}
+	if let Some(gqlUrl) = &config.gqlUrl {
+		checkUrl(&mut errors, "gqlUrl", gqlUrl, &["http", "https"]);
+	}
+	if let Some(ircUrl) = &config.ircUrl {
+		checkUrl(&mut errors, "ircUrl", ircUrl, &["ws", "wss"]);
+	}
+	if let Some(tokenPath) = &config.tokenPath
+		&& Path::new(tokenPath).is_dir()
+	{
+		errors.push(issue("tokenPath", format!("`{tokenPath}` is a directory")));
+	}
+	if let Some(stateDb) = &config.stateDb
+		&& Path::new(stateDb).is_dir()
+	{
+		errors.push(issue("stateDb", format!("`{stateDb}` is a directory")));
+	}
+	if let Some(template) = &config.filenameTemplate
+		&& let Err(err) = crate::template::validate(template)
+	{
+		errors.push(issue("filenameTemplate", err));
+	}
+	if let Some(storage) = &config.storage {
+		let warnFree = storage
+			.warnFreeGb
+			.unwrap_or(crate::storage::DEFAULT_WARN_FREE_GB);
+		let minFree = storage
+			.minFreeGb
+			.unwrap_or(crate::storage::DEFAULT_MIN_FREE_GB);
+		if minFree < 0.0 {
+			errors.push(issue("storage.minFreeGb", "must not be negative"));
+		}
+		if warnFree < minFree {
+			errors.push(issue(
+				"storage.warnFreeGb",
+				format!("must not be lower than storage.minFreeGb ({minFree})"),
+			));
+		}
+		if storage.checkInterval == Some(0) {
+			errors.push(issue("storage.checkInterval", "must be greater than 0"));
+		}
+		for (channel, policy) in storage.retention.iter().flatten() {
+			if channel != "*" && !isValidLogin(channel) {
+				errors.push(issue(
+					format!("storage.retention.{channel}"),
+					"expected a twitch login or `*`",
+				));
+			}
+			if policy.maxTotalGb.is_some_and(|gb| gb <= 0.0) {
+				errors.push(issue(
+					format!("storage.retention.{channel}.maxTotalGb"),
+					"must be greater than 0",
+				));
+			}
+			if policy.keepLast == Some(0) {
+				errors.push(issue(
+					format!("storage.retention.{channel}.keepLast"),
+					"must be greater than 0",
+				));
+			}
+		}
+	}
+	if let Some(split) = &config.split {
+		if split.maxMinutes.is_none() && split.maxGb.is_none() {
+			errors.push(issue("split", "needs maxMinutes or maxGb"));
+		}
+		if split.maxMinutes == Some(0) {
+			errors.push(issue("split.maxMinutes", "must be greater than 0"));
+		}
+		if split.maxGb.is_some_and(|gb| gb <= 0.0) {
+			errors.push(issue("split.maxGb", "must be greater than 0"));
+		}
+	}
+	if let Some(dir) = config
+		.assets
+		.as_ref()
+		.and_then(|assets| assets.dir.as_ref())
+	{
+		checkDir(&mut errors, "assets.dir", dir);
+	}
+	if let Some(subtitles) = &config.subtitles {
+		if subtitles
+			.messageSeconds
+			.is_some_and(|seconds| seconds <= 0.0)
+		{
+			errors.push(issue("subtitles.messageSeconds", "must be greater than 0"));
+		}
+		if subtitles.maxLines == Some(0) {
+			errors.push(issue("subtitles.maxLines", "must be greater than 0"));
+		}
+	}
+	if let Some(backfill) = &config.backfill {
+		if backfill
+			.types
+			.as_ref()
+			.is_some_and(|types| types.is_empty())
+		{
+			errors.push(issue("backfill.types", "must list at least one type"));
+		}
+		if let (Some(since), Some(until)) = (backfill.since, backfill.until)
+			&& since >= until
+		{
+			errors.push(issue("backfill.until", "must be after since"));
+		}
+		if let (Some(min), Some(max)) = (backfill.minSeconds, backfill.maxSeconds)
+			&& min > max
+		{
+			errors.push(issue("backfill.maxSeconds", "must not be below minSeconds"));
+		}
+		if backfill.maxClips == Some(0) {
+			errors.push(issue("backfill.maxClips", "must be greater than 0"));
+		}
+		if backfill.intervalHours == Some(0) {
+			errors.push(issue("backfill.intervalHours", "must be greater than 0"));
+		}
+	}
+	if let Some(upload) = &config.upload {
+		checkUrl(
+			&mut errors,
+			"upload.endpoint",
+			&upload.endpoint,
+			&["http", "https"],
+		);
+		if upload.bucket.trim().is_empty() {
+			errors.push(issue("upload.bucket", "must not be empty"));
+		}
+		if upload.accessKey.trim().is_empty() {
+			errors.push(issue("upload.accessKey", "must not be empty"));
+		}
+		if upload.secretKey.trim().is_empty() {
+			errors.push(issue("upload.secretKey", "must not be empty"));
+		}
+		if upload
+			.partSizeMb
+			.is_some_and(|size| size < crate::upload::MIN_PART_SIZE_MB)
+		{
+			errors.push(issue(
+				"upload.partSizeMb",
+				format!("must be at least {}", crate::upload::MIN_PART_SIZE_MB),
+			));
+		}
+		if upload.retries.is_some_and(|retries| retries < 0) {
+			errors.push(issue("upload.retries", "must not be negative"));
+		}
+	}
+	for (i, hook) in config.hooks.iter().flatten().enumerate() {
+		if hook
+			.command
+			.first()
+			.is_none_or(|program| program.trim().is_empty())
+		{
+			errors.push(issue(format!("hooks[{i}].command"), "must not be empty"));
+		}
+		if hook.events.as_ref().is_some_and(|events| events.is_empty()) {
+			errors.push(issue(
+				format!("hooks[{i}].events"),
+				"must not be empty, omit it to run on every event",
+			));
+		}
+		if hook.timeout == Some(0) {
+			errors.push(issue(
+				format!("hooks[{i}].timeout"),
+				"must be greater than 0",
+			));
+		}
+	}
+	for (i, target) in config.notify.iter().flatten().enumerate() {
+		checkUrl(
+			&mut errors,
+			&format!("notify[{i}].url"),
+			&target.url,
+			&["http", "https"],
+		);
+		if target
+			.events
+			.as_ref()
+			.is_some_and(|events| events.is_empty())
+		{
+			errors.push(issue(
+				format!("notify[{i}].events"),
+				"must not be empty, omit it to send every event",
+			));
+		}
+		if target.retries.is_some_and(|retries| retries < 0) {
+			errors.push(issue(
+				format!("notify[{i}].retries"),
+				"must not be negative",
+			));
+		}
+	}
+	if let Some(control) = &config.control {
+		if let Err(err) = control.listen.parse::<std::net::SocketAddr>() {
+			errors.push(issue(
+				"control.listen",
+				format!("`{}` is not a socket address: {err}", control.listen),
+			));
+		}
+		if control
+			.token
+			.as_ref()
+			.is_some_and(|token| token.trim().is_empty())
+		{
+			errors.push(issue(
+				"control.token",
+				"must not be empty, omit it to disable auth",
+			));
+		}
+	}
+	for (i, output) in config.logging.iter().flatten().enumerate() {
+		let levels = output
+			.level
+			.iter()
+			.map(|level| (format!("logging[{i}].level"), level))
+			.chain(
+				output
+					.targets
+					.iter()
+					.flatten()
+					.map(|(target, level)| (format!("logging[{i}].targets.{target}"), level)),
+			);
+		for (field, level) in levels {
+			if level.parse::<log::LevelFilter>().is_err() {
+				errors.push(issue(
+					field,
+					format!("unknown level `{level}`, expected off, error, warn, info, debug or trace"),
+				));
+			}
+		}
+		if let Some(rotate) = &output.rotate {
+			if output.path.is_none() {
+				errors.push(issue(
+					format!("logging[{i}].rotate"),
+					"only applies to file outputs",
+				));
+			}
+			if rotate.maxMb.is_none() && rotate.every.is_none() {
+				errors.push(issue(
+					format!("logging[{i}].rotate"),
+					"needs maxMb or every",
+				));
+			}
+			if rotate.maxMb == Some(0) {
+				errors.push(issue(
+					format!("logging[{i}].rotate.maxMb"),
+					"must be greater than 0",
+				));
+			}
+		}
+	}
+	if config.pollInterval == Some(0) {
+		errors.push(issue("pollInterval", "must be greater than 0"));
+	}
+
+	errors
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn loadText(name: &str, text: &str) -> Result<Config, Vec<ConfigError>> {
+		let path = std::env::temp_dir().join(format!("ld-config-{}-{name}", std::process::id()));
+		std::fs::write(&path, text).unwrap();
+		let result = load(&path);
+		std::fs::remove_file(&path).ok();
+		result
+	}
+
+	fn messages(result: Result<Config, Vec<ConfigError>>) -> Vec<String> {
+		result
+			.unwrap_err()
+			.iter()
+			.map(ToString::to_string)
+			.collect()
+	}
+
+	#[test]
+	fn loadsValidConfigs() {
+		let configs = [
+			(
+				"valid.json",
+				r#"{ "clientId": "id", "clientSecret": "secret", "account": "me", "broadcasters": ["streamer"], "root": "rec", "split": { "maxMinutes": 60 } }"#,
+			),
+			(
+				"valid.toml",
+				"clientId = \"id\"\nclientSecret = \"secret\"\naccount = \"me\"\nbroadcasters = [\"streamer\"]\nroot = \"rec\"\n[split]\nmaxMinutes = 60\n",
+			),
+			(
+				"valid.yaml",
+				"clientId: id\nclientSecret: secret\naccount: me\nbroadcasters: [streamer]\nroot: rec\nsplit:\n  maxMinutes: 60\n",
+			),
+		];
+		for (name, text) in configs {
+			let config = loadText(name, text).unwrap_or_else(|errors| panic!("{name}: {errors:?}"));
+			assert_eq!(config.broadcasters, ["streamer"], "{name}");
+			assert_eq!(config.split.unwrap().maxMinutes, Some(60), "{name}");
+			// defaults are filled in
+			assert_eq!(config.baseUrl.as_deref(), Some(DEFAULT_BASE_URL), "{name}");
+		}
+	}
+
+	#[test]
+	fn reportsMistypedOptions() {
+		let configs = [
+			(
+				"typo.json",
+				r#"{ "clientId": "id", "clientSecret": "secret", "account": "me", "broadcaster": ["streamer"], "root": "rec", "split": { "maxMinute": 60 } }"#,
+			),
+			(
+				"typo.toml",
+				"clientId = \"id\"\nclientSecret = \"secret\"\naccount = \"me\"\nbroadcaster = [\"streamer\"]\nroot = \"rec\"\n[split]\nmaxMinute = 60\n",
+			),
+			(
+				"typo.yaml",
+				"clientId: id\nclientSecret: secret\naccount: me\nbroadcaster: [streamer]\nroot: rec\nsplit:\n  maxMinute: 60\n",
+			),
+		];
+		for (name, text) in configs {
+			let mut errors = messages(loadText(name, text));
+			errors.sort();
+			assert_eq!(
+				errors,
+				[
+					"broadcaster: unknown option `broadcaster`, did you mean `broadcasters`?",
+					"split.maxMinute: unknown option `maxMinute`, did you mean `maxMinutes`?",
+				],
+				"{name}"
+			);
+		}
+	}
+
+	#[test]
+	fn reportsUnrelatedOptionsWithTheKnownOnes() {
+		let errors = messages(loadText(
+			"unrelated.json",
+			r#"{ "clientId": "id", "clientSecret": "secret", "account": "me", "root": "rec", "split": { "everyHour": true } }"#,
+		));
+
+		assert_eq!(
+			errors,
+			["split.everyHour: unknown field `everyHour`, expected `maxMinutes` or `maxGb`"]
+		);
+	}
+
+	#[test]
+	fn reportsEveryError() {
+		let configs = [
+			(
+				"errors.json",
+				r#"{ "clientId": "id", "clientSecret": "secret", "account": "me", "broadcasters": ["streamer", "foo bar"], "root": "rec", "pollInterval": "soon", "catchUp": "yes", "splt": {} }"#,
+			),
+			(
+				"errors.toml",
+				"clientId = \"id\"\nclientSecret = \"secret\"\naccount = \"me\"\nbroadcasters = [\"streamer\", \"foo bar\"]\nroot = \"rec\"\npollInterval = \"soon\"\ncatchUp = \"yes\"\n[splt]\n",
+			),
+			(
+				"errors.yaml",
+				"clientId: id\nclientSecret: secret\naccount: me\nbroadcasters: [streamer, foo bar]\nroot: rec\npollInterval: soon\ncatchUp: \"yes\"\nsplt: {}\n",
+			),
+		];
+		for (name, text) in configs {
+			let mut errors = messages(loadText(name, text));
+			errors.sort();
+			assert_eq!(
+				errors,
+				[
+					"catchUp: invalid type: string \"yes\", expected a boolean",
+					"pollInterval: invalid type: string \"soon\", expected u64",
+					"splt: unknown option `splt`, did you mean `split`?",
+				],
+				"{name}"
+			);
+		}
+	}
+}
@@ -0,0 +1,347 @@
+use std::{
+	collections::{HashMap, HashSet},
+	path::PathBuf,
+	sync::{Arc, RwLock},
+};
+
+use axum::{
+	Json, Router,
+	extract::{Path, Request, State},
+	http::StatusCode,
+	middleware::Next,
+	response::{IntoResponse, Response},
+	routing::get,
+};
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use serde_json::json;
+
+use crate::{
+	Thread,
+	data::{Config, ControlConfig, InternalMessage},
+	record,
+};
+
+/// A recording in progress, registered by the download thread for its whole lifetime
+pub struct ActiveRecording {
+	pub channel: String,
+	pub startedAt: DateTime<Utc>,
+	pub paths: std::sync::Mutex<Vec<PathBuf>>,
+	pub handle: record::Handle,
+	/// vod download of a late start, see `catchup`
+	pub catchUp: record::Handle,
+}
+
+/// Runtime state shared between the main loop and the http api
+pub struct Runtime {
+	pub recordings: std::sync::Mutex<HashMap<String, Arc<ActiveRecording>>>,
+	/// current eventsub websocket session
+	pub session: std::sync::Mutex<Option<String>>,
+	/// watched channels, starts from config and changes through the api
+	pub broadcasters: RwLock<Vec<String>>,
+	/// broadcasters with `video: false`, only their chat is logged
+	chatOnly: HashSet<String>,
+}
+
+impl Runtime {
+	pub fn new(config: &Config) -> Self {
+		Runtime {
+			recordings: std::sync::Mutex::new(HashMap::new()),
+			session: std::sync::Mutex::new(None),
+			broadcasters: RwLock::new(config.broadcasters.clone()),
+			chatOnly: config
+				.broadcasters
+				.iter()
+				.filter(|login| !crate::config::recordsVideo(config, login))
+				.cloned()
+				.collect(),
+		}
+	}
+
+	pub fn isRecording(&self, channel: &str) -> bool {
+		self.recordings.lock().unwrap().contains_key(channel)
+	}
+
+	pub fn broadcasters(&self) -> Vec<String> {
+		self.broadcasters.read().unwrap().clone()
+	}
+
+	pub fn recordsVideo(&self, login: &str) -> bool {
+		!self.chatOnly.contains(login)
+	}
+
+	/// Broadcasters watched for streams to record
+	pub fn videoBroadcasters(&self) -> Vec<String> {
+		self
+			.broadcasters()
+			.into_iter()
+			.filter(|login| self.recordsVideo(login))
+			.collect()
+	}
+}
+
+#[derive(Clone)]
+struct App {
+	runtime: Arc<Runtime>,
+	api: Arc<tokio::sync::Mutex<crate::api::Api>>,
+	threadPool: Arc<tokio::sync::Mutex<Vec<Arc<Thread>>>>,
+	tx: tokio::sync::broadcast::Sender<InternalMessage>,
+	token: Option<String>,
+}
+
+type Reply = Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)>;
+
+fn fail(status: StatusCode, message: impl Into<String>) -> (StatusCode, Json<serde_json::Value>) {
+	(status, Json(json!({ "error": message.into() })))
+}
+
+fn send(app: &App, message: InternalMessage) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+	app
+		.tx
+		.send(message)
+		.map(|_| ())
+		.map_err(|_| fail(StatusCode::SERVICE_UNAVAILABLE, "main loop is not running"))
+}
+
+async fn auth(State(app): State<App>, req: Request, next: Next) -> Response {
+	let Some(token) = &app.token else {
+		return next.run(req).await;
+	};
+	let authorised = req
+		.headers()
+		.get("authorization")
+		.and_then(|value| value.to_str().ok())
+		.and_then(|value| value.strip_prefix("Bearer "))
+		.is_some_and(|value| value == token);
+
+	match authorised {
+		true => next.run(req).await,
+		false => fail(StatusCode::UNAUTHORIZED, "missing or invalid bearer token").into_response(),
+	}
+}
+
+async fn status(State(app): State<App>) -> Reply {
+	let threads = app
+		.threadPool
+		.lock()
+		.await
+		.iter()
+		.map(|thread| {
+			json!({
+				"id": thread.id,
+				"label": format!("{:?}", thread.label),
+				"finished": thread.handle.is_finished(),
+			})
+		})
+		.collect::<Vec<_>>();
+
+	Ok(Json(json!({
+		"session": *app.runtime.session.lock().unwrap(),
+		"broadcasters": app.runtime.broadcasters(),
+		"recordings": app.runtime.recordings.lock().unwrap().len(),
+		"threads": threads,
+	})))
+}
+
+async fn recordings(State(app): State<App>) -> Reply {
+	let recordings = app
+		.runtime
+		.recordings
+		.lock()
+		.unwrap()
+		.values()
+		.map(|recording| {
+			let paths = recording.paths.lock().unwrap().clone();
+			let bytes = paths
+				.iter()
+				.filter_map(|path| std::fs::metadata(path).ok())
+				.map(|meta| meta.len())
+				.sum::<u64>();
+			json!({
+				"channel": recording.channel,
+				"startedAt": recording.startedAt,
+				"bytes": bytes,
+				"backend": record::BACKEND,
+				"pid": recording.handle.pid(),
+				"paths": paths,
+			})
+		})
+		.collect::<Vec<_>>();
+
+	Ok(Json(json!(recordings)))
+}
+
+async fn startRecording(State(app): State<App>, Path(login): Path<String>) -> Reply {
+	let login = login.to_lowercase();
+	if !crate::config::isValidLogin(&login) {
+		return Err(fail(
+			StatusCode::BAD_REQUEST,
+			format!("`{login}` is not a valid login"),
+		));
+	}
+	if app.runtime.isRecording(&login) {
+		return Err(fail(
+			StatusCode::CONFLICT,
+			format!("{login} is already recording"),
+		));
+	}
+	info!("[CTRL] manual start for {login}");
+	send(
+		&app,
+		InternalMessage::StreamLive {
+			channel: login.clone(),
+		},
+	)?;
+
+	Ok(Json(json!({ "channel": login, "status": "starting" })))
+}
+
+async fn stopRecording(State(app): State<App>, Path(login): Path<String>) -> Reply {
+	let login = login.to_lowercase();
+	if !app.runtime.isRecording(&login) {
+		return Err(fail(
+			StatusCode::NOT_FOUND,
+			format!("{login} is not recording"),
+		));
+	}
+	info!("[CTRL] manual stop for {login}");
+	send(
+		&app,
+		InternalMessage::StopRecording {
+			channel: login.clone(),
+		},
+	)?;
+
+	Ok(Json(json!({ "channel": login, "status": "stopping" })))
+}
+
+async fn subscriptions(State(app): State<App>) -> Reply {
+	let subscriptions = app
+		.api
+		.lock()
+		.await
+		.getSubscribtions()
+		.await
+		.map_err(|err| fail(StatusCode::BAD_GATEWAY, format!("{:?}", err)))?;
+
+	Ok(Json(json!({
+		"session": *app.runtime.session.lock().unwrap(),
+		"subscriptions": subscriptions,
+	})))
+}
+
+async fn token(State(app): State<App>) -> Reply {
+	let validation = app
+		.api
+		.lock()
+		.await
+		.validate()
+		.await
+		.map_err(|err| fail(StatusCode::BAD_GATEWAY, format!("{:?}", err)))?;
+
+	Ok(Json(json!({
+		"login": validation.login,
+		"expiresIn": validation.expires_in,
+		"expiresAt": Utc::now() + chrono::Duration::seconds(validation.expires_in as i64),
+		"scopes": validation.scopes.unwrap_or_default(),
+	})))
+}
+
+async fn metrics(State(app): State<App>) -> impl IntoResponse {
+	(
+		[("content-type", "text/plain; version=0.0.4")],
+		crate::metrics::metrics().render(&app.runtime),
+	)
+}
+
+async fn broadcasters(State(app): State<App>) -> Reply {
+	Ok(Json(json!(app.runtime.broadcasters())))
+}
+
+async fn addBroadcaster(State(app): State<App>, Path(login): Path<String>) -> Reply {
+	let login = login.to_lowercase();
+	if !crate::config::isValidLogin(&login) {
+		return Err(fail(
+			StatusCode::BAD_REQUEST,
+			format!("`{login}` is not a valid login"),
+		));
+	}
+	if app.runtime.broadcasters().contains(&login) {
+		return Err(fail(
+			StatusCode::CONFLICT,
+			format!("{login} is already watched"),
+		));
+	}
+	send(
+		&app,
+		InternalMessage::AddBroadcaster {
+			login: login.clone(),
+		},
+	)?;
+
+	Ok(Json(json!({ "broadcaster": login, "status": "adding" })))
+}
+
+async fn removeBroadcaster(State(app): State<App>, Path(login): Path<String>) -> Reply {
+	let login = login.to_lowercase();
+	if !app.runtime.broadcasters().contains(&login) {
+		return Err(fail(
+			StatusCode::NOT_FOUND,
+			format!("{login} is not watched"),
+		));
+	}
+	send(
+		&app,
+		InternalMessage::RemoveBroadcaster {
+			login: login.clone(),
+		},
+	)?;
+
+	Ok(Json(json!({ "broadcaster": login, "status": "removing" })))
+}
+
+/// Serves the status and control api until the listener fails
+pub(crate) async fn serve(
+	config: ControlConfig,
+	runtime: Arc<Runtime>,
+	api: Arc<tokio::sync::Mutex<crate::api::Api>>,
+	threadPool: Arc<tokio::sync::Mutex<Vec<Arc<Thread>>>>,
+	tx: tokio::sync::broadcast::Sender<InternalMessage>,
+) {
+	let app = App {
+		runtime,
+		api,
+		threadPool,
+		tx,
+		token: config.token.clone(),
+	};
+	let router = Router::new()
+		.route("/status", get(status))
+		.route("/recordings", get(recordings))
+		.route(
+			"/recordings/{login}",
+			axum::routing::post(startRecording).delete(stopRecording),
+		)
+		.route("/subscriptions", get(subscriptions))
+		.route("/token", get(token))
+		.route("/metrics", get(metrics))
+		.route("/broadcasters", get(broadcasters))
+		.route(
+			"/broadcasters/{login}",
+			axum::routing::put(addBroadcaster).delete(removeBroadcaster),
+		)
+		.layer(axum::middleware::from_fn_with_state(app.clone(), auth))
+		.with_state(app);
+
+	let listener = match tokio::net::TcpListener::bind(&config.listen).await {
+		Ok(listener) => listener,
+		Err(err) => {
+			error!("[CTRL] failed to listen on {}: {err}", config.listen);
+			return;
+		}
+	};
+	info!("[CTRL] listening on {}", config.listen);
+	if let Err(err) = axum::serve(listener, router).await {
+		error!("[CTRL] server stopped: {err}");
+	}
+}
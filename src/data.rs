@@ -1,21 +1,312 @@
 use serde;
 
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum AuthMode {
+	/// device code login for a user account, needed for eventsub websocket and chat
+	#[default]
+	User,
+	/// client credentials grant, stream status is polled from helix and chat is disabled
+	App,
+}
+
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
 	pub clientId: String,
 	pub clientSecret: String,
+	#[serde(default)]
 	pub broadcasters: Vec<String>,
-	pub account: String,
-	pub streamlinkToken: String,
+	/// per broadcaster settings keyed by login
+	pub broadcasterOptions: Option<std::collections::HashMap<String, BroadcasterOptions>>,
+	/// channels whose chat is logged without watching them for streams
+	pub chatChannels: Option<Vec<String>>,
+	pub account: Option<String>,
+	/// where live chat is read from
+	#[serde(default)]
+	pub chatSource: ChatSource,
+	/// twitch irc over websocket, anonymous chat reading
+	pub ircUrl: Option<String>,
+	pub streamlinkToken: Option<String>,
 	pub root: String,
 	pub chatRoot: Option<String>,
 	pub socketUrl: Option<String>,
 	pub baseUrl: Option<String>,
+	/// twitch web api, chat replays of vods only come from there
+	pub gqlUrl: Option<String>,
+	pub tokenPath: Option<String>,
+	pub tokenPassphrase: Option<String>,
+	/// sqlite file with recording and event history
+	pub stateDb: Option<String>,
+	pub logDir: Option<String>,
+	pub extraScopes: Option<Vec<String>>,
+	#[serde(default)]
+	pub authMode: AuthMode,
+	pub pollInterval: Option<u64>,
+	pub filenameTemplate: Option<String>,
+	pub storage: Option<StorageConfig>,
+	pub split: Option<SplitConfig>,
+	/// download what aired before a late start from the in-progress vod
+	pub catchUp: Option<bool>,
+	pub backfill: Option<BackfillConfig>,
+	/// chat rendered into a subtitle track of every recording
+	pub subtitles: Option<SubtitleConfig>,
+	/// emote and badge images referenced by chat
+	pub assets: Option<AssetConfig>,
+	pub upload: Option<UploadConfig>,
+	pub hooks: Option<Vec<HookConfig>>,
+	pub notify: Option<Vec<NotifyConfig>>,
+	pub control: Option<ControlConfig>,
+	/// diagnostic log outputs, replaces the default stdout + `live-downloader.log` + `vendor.log` set
+	pub logging: Option<Vec<LogOutput>>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum LogFormat {
+	#[default]
+	Text,
+	/// one json object per line
+	Json,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum RotateEvery {
+	Hour,
+	Day,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct RotateConfig {
+	pub maxMb: Option<u64>,
+	pub every: Option<RotateEvery>,
+	/// rotated files kept around, all of them when omitted
+	pub keep: Option<usize>,
+	pub compress: Option<bool>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct LogOutput {
+	/// file relative to `logDir`, stdout when omitted
+	pub path: Option<String>,
+	/// `off`, `error`, `warn`, `info`, `debug` or `trace`
+	pub level: Option<String>,
+	/// per target levels on top of `level`, e.g. `{ "ld": "trace", "tungstenite": "off" }`
+	pub targets: Option<std::collections::BTreeMap<String, String>>,
+	#[serde(default)]
+	pub format: LogFormat,
+	pub rotate: Option<RotateConfig>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ControlConfig {
+	/// address the http api listens on, e.g. `127.0.0.1:8080`
+	pub listen: String,
+	/// bearer token required on every request when set
+	pub token: Option<String>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum NotifyFormat {
+	/// the notification as json
+	#[default]
+	Generic,
+	Discord,
+	Ntfy,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum NotifyEvent {
+	StreamLive,
+	RecordingFinished,
+	RecordingFailed,
+	AuthFailed,
+	StorageLow,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct NotifyConfig {
+	pub url: String,
+	#[serde(default)]
+	pub format: NotifyFormat,
+	/// events sent to this target, all of them when omitted
+	pub events: Option<Vec<NotifyEvent>>,
+	pub retries: Option<i32>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum HookEvent {
+	Start,
+	Finish,
+	Fail,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct HookConfig {
+	/// program and its arguments, run without a shell
+	pub command: Vec<String>,
+	/// events the hook runs on, all of them when omitted
+	pub events: Option<Vec<HookEvent>>,
+	/// seconds before the hook is killed
+	pub timeout: Option<u64>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct UploadConfig {
+	pub endpoint: String,
+	pub bucket: String,
+	pub region: Option<String>,
+	pub accessKey: String,
+	pub secretKey: String,
+	pub prefix: Option<String>,
+	/// `bucket` goes into the path rather than the host, needed for minio and most s3 compatible stores
+	pub pathStyle: Option<bool>,
+	pub partSizeMb: Option<u64>,
+	pub retries: Option<i32>,
+	pub deleteLocal: Option<bool>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum ChatSource {
+	/// `channel.chat.message` subscriptions, falling back to irc for channels they fail for
+	#[default]
+	Eventsub,
+	/// anonymous irc only, needs no account or chat scope
+	Irc,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct BroadcasterOptions {
+	/// record live streams, `false` logs the chat only
+	pub video: Option<bool>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct SplitConfig {
+	pub maxMinutes: Option<u64>,
+	pub maxGb: Option<f64>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EmoteProvider {
+	#[serde(rename = "bttv")]
+	Bttv,
+	#[serde(rename = "ffz")]
+	Ffz,
+	#[serde(rename = "7tv")]
+	SevenTv,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct AssetConfig {
+	/// `<chatRoot>/assets` by default
+	pub dir: Option<String>,
+	/// third party emote sets of the broadcasters and their global sets
+	pub thirdParty: Option<Vec<EmoteProvider>>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum SubtitleFormat {
+	#[default]
+	Ass,
+	Vtt,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum SubtitlePosition {
+	#[default]
+	TopLeft,
+	TopRight,
+	BottomLeft,
+	BottomRight,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct SubtitleConfig {
+	#[serde(default)]
+	pub format: SubtitleFormat,
+	/// how long a message stays on screen
+	pub messageSeconds: Option<f64>,
+	/// messages on screen at once, older ones scroll out early
+	pub maxLines: Option<usize>,
+	/// colour usernames like twitch does for users without a colour set
+	pub colors: Option<bool>,
+	#[serde(default)]
+	pub position: SubtitlePosition,
+	/// replace the recording with an mkv carrying the subtitle track, needs ffmpeg
+	pub mux: Option<bool>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub enum BackfillKind {
+	Archive,
+	Highlight,
+	Upload,
+	Clip,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct BackfillConfig {
+	/// archives and highlights when omitted
+	pub types: Option<Vec<BackfillKind>>,
+	/// only videos created at or after
+	pub since: Option<chrono::DateTime<chrono::Utc>>,
+	/// only videos created before
+	pub until: Option<chrono::DateTime<chrono::Utc>>,
+	pub minSeconds: Option<u64>,
+	pub maxSeconds: Option<u64>,
+	/// most viewed clips per channel
+	pub maxClips: Option<usize>,
+	/// hours between runs alongside live recording, only `ld backfill` runs it when omitted
+	pub intervalHours: Option<u64>,
+	/// fetch the chat replay of every downloaded vod
+	pub chat: Option<bool>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct StorageConfig {
+	pub warnFreeGb: Option<f64>,
+	pub minFreeGb: Option<f64>,
+	pub checkInterval: Option<u64>,
+	/// policies by channel login, `*` applies to channels without their own
+	pub retention: Option<std::collections::HashMap<String, RetentionPolicy>>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct RetentionPolicy {
+	pub maxAgeDays: Option<u64>,
+	pub maxTotalGb: Option<f64>,
+	pub keepLast: Option<usize>,
+	/// move expired recordings here instead of deleting them
+	pub moveTo: Option<String>,
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
 pub struct Token {
 	pub access_token: String,
+	/// client credentials tokens come without one
+	#[serde(default)]
 	pub refresh_token: String,
 	pub expires_in: u32,
 }
@@ -23,15 +314,42 @@ pub struct Token {
 #[derive(serde::Deserialize, Debug)]
 pub struct DeviceCodeResponse {
 	pub device_code: String,
+	pub user_code: String,
 	pub verification_uri: String,
+	pub interval: u64,
+	pub expires_in: u64,
 }
 
 #[derive(serde::Deserialize, Debug)]
+pub struct OAuthErrorResponse {
+	pub status: u16,
+	pub message: String,
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
 pub struct UserData {
 	pub id: String,
 	pub login: String,
 }
 
+#[derive(serde::Deserialize, Debug)]
+pub struct BadgeVersion {
+	pub id: String,
+	pub image_url_4x: String,
+	pub title: String,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct BadgeSet {
+	pub set_id: String,
+	pub versions: Vec<BadgeVersion>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct BadgeResponse {
+	pub data: Vec<BadgeSet>,
+}
+
 #[derive(serde::Deserialize, Debug)]
 pub struct UserResponse {
 	pub data: Vec<UserData>,
@@ -42,6 +360,7 @@ pub struct StreamData {
 	pub id: String,
 	pub user_id: String,
 	pub user_login: String,
+	pub user_name: String,
 	pub game_name: String,
 	#[serde(rename = "type")]
 	pub stream_type: String,
@@ -53,22 +372,252 @@ pub struct StreamData {
 pub struct StreamResponse {
 	pub data: Vec<StreamData>,
 }
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct VideoData {
+	pub id: String,
+	/// broadcast the vod was recorded from, only set for archives
+	pub stream_id: Option<String>,
+	pub user_id: String,
+	pub user_login: String,
+	pub title: String,
+	pub created_at: chrono::DateTime<chrono::Utc>,
+	pub url: String,
+	/// `1h2m3s`
+	pub duration: String,
+	#[serde(rename = "type")]
+	pub video_type: String,
+}
+
+#[derive(serde::Deserialize, Debug, Default)]
+pub struct Pagination {
+	/// absent on the last page
+	pub cursor: Option<String>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct VideoResponse {
+	pub data: Vec<VideoData>,
+	#[serde(default)]
+	pub pagination: Pagination,
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct ClipData {
+	pub id: String,
+	pub url: String,
+	pub broadcaster_id: String,
+	pub broadcaster_name: String,
+	pub title: String,
+	pub view_count: u64,
+	pub created_at: chrono::DateTime<chrono::Utc>,
+	/// seconds
+	pub duration: f64,
+	/// vod the clip was cut from, empty when it's gone
+	pub video_id: String,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct ClipResponse {
+	pub data: Vec<ClipData>,
+	#[serde(default)]
+	pub pagination: Pagination,
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct ReplayCommenter {
+	pub login: String,
+	pub displayName: String,
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct ReplayFragment {
+	pub text: String,
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct ReplayMessage {
+	pub fragments: Vec<ReplayFragment>,
+}
+
+/// Chat message of a vod as the gql `VideoCommentsByOffsetOrCursor` query returns it
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct ReplayComment {
+	pub id: String,
+	/// gone for deleted accounts
+	pub commenter: Option<ReplayCommenter>,
+	pub contentOffsetSeconds: u64,
+	pub createdAt: chrono::DateTime<chrono::Utc>,
+	pub message: ReplayMessage,
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct ReplayEdge {
+	pub cursor: Option<String>,
+	pub node: ReplayComment,
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct ReplayPageInfo {
+	pub hasNextPage: bool,
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct ReplayComments {
+	pub edges: Vec<ReplayEdge>,
+	pub pageInfo: ReplayPageInfo,
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct ReplayVideo {
+	pub comments: Option<ReplayComments>,
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct ReplayData {
+	pub video: Option<ReplayVideo>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct ReplayResponse {
+	pub data: Option<ReplayData>,
+	#[serde(default)]
+	pub errors: Vec<serde_json::Value>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug)]
+pub struct SubscriptionData {
+	pub id: String,
+	pub status: String,
+	#[serde(rename = "type")]
+	pub sub_type: String,
+	pub version: String,
+	pub condition: serde_json::Value,
+	pub created_at: chrono::DateTime<chrono::Utc>,
+	pub cost: u32,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug)]
+pub struct SubscriptionResponse {
+	pub data: Vec<SubscriptionData>,
+	pub total: u32,
+	pub total_cost: u32,
+	pub max_total_cost: u32,
+}
+
 #[derive(serde::Deserialize, Debug)]
 pub struct ValidationResponse {
 	pub expires_in: u64,
-	// login: String,
+	pub scopes: Option<Vec<String>>,
+	pub login: Option<String>,
 	// client_id: String,
 }
 
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+pub struct ManifestPart {
+	pub part: u32,
+	pub path: String,
+	pub bytes: u64,
+	pub startedAt: chrono::DateTime<chrono::Utc>,
+	pub endedAt: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// Sidecar tying the parts of a split recording together
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+pub struct RecordingManifest {
+	pub channel: String,
+	pub streamId: String,
+	pub title: String,
+	pub category: String,
+	pub startedAt: chrono::DateTime<chrono::Utc>,
+	/// channel chat log, lines can be matched to parts by timestamp
+	pub chat: Option<String>,
+	pub parts: Vec<ManifestPart>,
+}
+
+/// Stretch of the stream missing from the recording
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct RecordingGap {
+	pub from: chrono::DateTime<chrono::Utc>,
+	pub to: chrono::DateTime<chrono::Utc>,
+	pub seconds: i64,
+}
+
+/// Json handed to hooks on stdin
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct HookPayload {
+	pub event: HookEvent,
+	pub channel: String,
+	pub streamId: String,
+	pub title: String,
+	pub category: String,
+	pub paths: Vec<String>,
+	pub startedAt: chrono::DateTime<chrono::Utc>,
+	pub endedAt: Option<chrono::DateTime<chrono::Utc>>,
+	pub durationSeconds: Option<i64>,
+	/// streamlink exit code, or the error when it couldn't run
+	pub exitCode: Option<i32>,
+	pub error: Option<String>,
+	pub gaps: Vec<RecordingGap>,
+}
+
+/// Body of a generic webhook, discord and ntfy get `message` only
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct Notification {
+	pub event: NotifyEvent,
+	pub channel: Option<String>,
+	pub message: String,
+	pub timestamp: chrono::DateTime<chrono::Utc>,
+	pub details: serde_json::Value,
+}
+
+/// A chat line on its way to the archive, it has its own channel so a chat burst never crowds out control messages;
+/// `emotes` are the (id, name) pairs of twitch emotes in the message
+#[derive(Debug, Clone)]
+pub struct ChatMessage {
+	pub msg: String,
+	pub channel: String,
+	pub emotes: Vec<(String, String)>,
+}
+
 #[derive(Debug, Clone)]
 pub enum InternalMessage {
-	Init { session: String },
-	StreamLive { channel: String },
-	StreamStop { channel: String },
-	Chat { msg: String, channel: String },
+	Init {
+		session: String,
+	},
+	StreamLive {
+		channel: String,
+	},
+	StreamStop {
+		channel: String,
+	},
+	StorageLow {
+		free: u64,
+		critical: bool,
+	},
+	AuthFailed {
+		reason: String,
+	},
+	StopRecording {
+		channel: String,
+	},
+	AddBroadcaster {
+		login: String,
+	},
+	/// the user behind an added broadcaster was looked up
+	WatchBroadcaster {
+		user: UserData,
+	},
+	RemoveBroadcaster {
+		login: String,
+	},
 
-	Debug { info: String },
-	Reconnect { session: String, url: String },
+	Debug {
+		info: String,
+	},
+	Reconnect {
+		session: String,
+		url: String,
+	},
 
 	DontHandle,
 }
@@ -0,0 +1,398 @@
+use std::{
+	path::{Path, PathBuf},
+	sync::Mutex,
+};
+
+use chrono::{DateTime, Utc};
+use log::error;
+use rusqlite::{Connection, OptionalExtension, params};
+
+use crate::{
+	backfill::Video,
+	data::{Config, RecordingGap},
+	template::RecordingInfo,
+};
+
+pub const DEFAULT_PATH: &str = "state.db";
+
+/// Schema steps, `PRAGMA user_version` holds how many were applied
+const MIGRATIONS: &[&str] = &[
+	"
+	CREATE TABLE broadcasts (
+		stream_id TEXT PRIMARY KEY,
+		channel TEXT NOT NULL,
+		title TEXT NOT NULL,
+		category TEXT NOT NULL,
+		started_at TEXT,
+		first_seen_at TEXT NOT NULL,
+		last_seen_at TEXT NOT NULL
+	);
+	CREATE TABLE recordings (
+		id INTEGER PRIMARY KEY,
+		channel TEXT NOT NULL,
+		stream_id TEXT NOT NULL,
+		title TEXT NOT NULL,
+		started_at TEXT NOT NULL,
+		ended_at TEXT,
+		status TEXT NOT NULL,
+		exit_code INTEGER,
+		error TEXT,
+		paths TEXT NOT NULL DEFAULT '[]',
+		gaps TEXT NOT NULL DEFAULT '[]'
+	);
+	CREATE INDEX recordings_channel ON recordings (channel, started_at);
+	CREATE TABLE events (
+		id INTEGER PRIMARY KEY,
+		received_at TEXT NOT NULL,
+		session TEXT,
+		kind TEXT NOT NULL,
+		subscription TEXT,
+		payload TEXT NOT NULL
+	);
+	CREATE TABLE token_refreshes (
+		id INTEGER PRIMARY KEY,
+		at TEXT NOT NULL,
+		success INTEGER NOT NULL,
+		error TEXT
+	);
+	CREATE TABLE state (
+		key TEXT PRIMARY KEY,
+		value TEXT NOT NULL
+	);
+",
+	"
+	CREATE TABLE downloads (
+		video_id TEXT PRIMARY KEY,
+		channel TEXT NOT NULL,
+		kind TEXT NOT NULL,
+		title TEXT NOT NULL,
+		created_at TEXT NOT NULL,
+		path TEXT NOT NULL,
+		downloaded_at TEXT NOT NULL
+	);
+",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordingStatus {
+	Recording,
+	Finished,
+	Failed,
+	Stopped,
+	/// still marked as recording when `ld` started, the process died mid-recording
+	Interrupted,
+}
+
+impl RecordingStatus {
+	fn as_str(self) -> &'static str {
+		match self {
+			RecordingStatus::Recording => "recording",
+			RecordingStatus::Finished => "finished",
+			RecordingStatus::Failed => "failed",
+			RecordingStatus::Stopped => "stopped",
+			RecordingStatus::Interrupted => "interrupted",
+		}
+	}
+}
+
+#[derive(Debug, Clone)]
+pub struct RecordingRow {
+	pub id: i64,
+	pub channel: String,
+	pub streamId: String,
+	pub title: String,
+	pub startedAt: DateTime<Utc>,
+	pub endedAt: Option<DateTime<Utc>>,
+	pub status: String,
+	pub exitCode: Option<i32>,
+	pub paths: Vec<PathBuf>,
+}
+
+const RECORDING_COLUMNS: &str =
+	"id, channel, stream_id, title, started_at, ended_at, status, exit_code, paths";
+
+fn recordingRow(row: &rusqlite::Row) -> rusqlite::Result<RecordingRow> {
+	let paths: String = row.get(8)?;
+	Ok(RecordingRow {
+		id: row.get(0)?,
+		channel: row.get(1)?,
+		streamId: row.get(2)?,
+		title: row.get(3)?,
+		startedAt: row.get(4)?,
+		endedAt: row.get(5)?,
+		status: row.get(6)?,
+		exitCode: row.get(7)?,
+		paths: serde_json::from_str(&paths).unwrap_or_default(),
+	})
+}
+
+/// Eventsub message on its way into the db, see `Db::event`
+#[derive(Debug)]
+pub struct Event {
+	pub receivedAt: DateTime<Utc>,
+	pub session: Option<String>,
+	pub kind: String,
+	pub subscription: Option<String>,
+	pub payload: String,
+}
+
+/// Embedded sqlite history of broadcasts, recordings, eventsub events and token refreshes
+pub struct Db {
+	conn: Mutex<Connection>,
+}
+
+pub fn path(config: &Config) -> PathBuf {
+	PathBuf::from(config.stateDb.as_deref().unwrap_or(DEFAULT_PATH))
+}
+
+/// State writes never take recording down with them, failures are only logged
+fn logged<T>(what: &str, result: rusqlite::Result<T>) -> Option<T> {
+	result
+		.map_err(|err| error!("[STDB] failed to {what}: {err}"))
+		.ok()
+}
+
+impl Db {
+	pub fn open(config: &Config) -> rusqlite::Result<Self> {
+		let path = path(config);
+		if let Some(parent) = path
+			.parent()
+			.filter(|parent| !parent.as_os_str().is_empty())
+		{
+			std::fs::create_dir_all(parent).ok();
+		}
+		let conn = Connection::open(&path)?;
+		conn.pragma_update(None, "journal_mode", "WAL")?;
+		conn.busy_timeout(std::time::Duration::from_secs(5))?;
+
+		let version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+		for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+			conn.execute_batch(migration)?;
+			conn.pragma_update(None, "user_version", i + 1)?;
+		}
+
+		Ok(Db {
+			conn: Mutex::new(conn),
+		})
+	}
+
+	pub fn broadcastSeen(&self, info: &RecordingInfo) {
+		if info.streamId.is_empty() {
+			return;
+		}
+		let now = Utc::now();
+		logged(
+			"record broadcast",
+			self.conn.lock().unwrap().execute(
+				"INSERT INTO broadcasts (stream_id, channel, title, category, started_at, first_seen_at, last_seen_at)
+				VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6)
+				ON CONFLICT (stream_id) DO UPDATE SET title = ?3, category = ?4, last_seen_at = ?6",
+				params![
+					info.streamId,
+					info.login,
+					info.title,
+					info.category,
+					info.streamStartedAt,
+					now
+				],
+			),
+		);
+	}
+
+	pub fn recordingStarted(&self, info: &RecordingInfo) -> Option<i64> {
+		let conn = self.conn.lock().unwrap();
+		logged(
+			"record recording start",
+			conn.execute(
+				"INSERT INTO recordings (channel, stream_id, title, started_at, status) VALUES (?1, ?2, ?3, ?4, ?5)",
+				params![
+					info.login,
+					info.streamId,
+					info.title,
+					info.startedAt,
+					RecordingStatus::Recording.as_str()
+				],
+			),
+		)?;
+		Some(conn.last_insert_rowid())
+	}
+
+	/// Puts an interrupted recording back to recording, it keeps its id, start and paths
+	pub fn recordingResumed(&self, id: i64) {
+		logged(
+			"record recording resume",
+			self.conn.lock().unwrap().execute(
+				"UPDATE recordings SET status = ?2, ended_at = NULL WHERE id = ?1",
+				params![id, RecordingStatus::Recording.as_str()],
+			),
+		);
+	}
+
+	pub fn recordingPaths(&self, id: i64, paths: &[PathBuf]) {
+		logged(
+			"record recording paths",
+			self.conn.lock().unwrap().execute(
+				"UPDATE recordings SET paths = ?2 WHERE id = ?1",
+				params![id, serde_json::to_string(paths).unwrap()],
+			),
+		);
+	}
+
+	pub fn recordingEnded(
+		&self,
+		id: i64,
+		status: RecordingStatus,
+		exitCode: Option<i32>,
+		error: Option<&str>,
+		gaps: &[RecordingGap],
+	) {
+		logged(
+			"record recording end",
+			self.conn.lock().unwrap().execute(
+				"UPDATE recordings SET ended_at = ?2, status = ?3, exit_code = ?4, error = ?5, gaps = ?6 WHERE id = ?1",
+				params![
+					id,
+					Utc::now(),
+					status.as_str(),
+					exitCode,
+					error,
+					serde_json::to_string(gaps).unwrap()
+				],
+			),
+		);
+	}
+
+	/// Marks recordings left running by a previous process as interrupted and returns them
+	pub fn takeInterrupted(&self) -> Vec<RecordingRow> {
+		let conn = self.conn.lock().unwrap();
+		let rows = logged(
+			"list interrupted recordings",
+			conn
+				.prepare(&format!(
+					"UPDATE recordings SET status = ?1 WHERE status = ?2 RETURNING {RECORDING_COLUMNS}"
+				))
+				.and_then(|mut statement| {
+					statement
+						.query_map(
+							params![
+								RecordingStatus::Interrupted.as_str(),
+								RecordingStatus::Recording.as_str()
+							],
+							recordingRow,
+						)?
+						.collect::<rusqlite::Result<Vec<_>>>()
+				}),
+		);
+
+		rows.unwrap_or_default()
+	}
+
+	/// Newest recordings first, optionally of a single channel
+	pub fn recordings(&self, channel: Option<&str>, limit: usize) -> Vec<RecordingRow> {
+		let conn = self.conn.lock().unwrap();
+		let rows = logged(
+			"list recordings",
+			conn
+				.prepare(&format!(
+					"SELECT {RECORDING_COLUMNS} FROM recordings WHERE ?1 IS NULL OR channel = ?1
+					ORDER BY started_at DESC LIMIT ?2"
+				))
+				.and_then(|mut statement| {
+					statement
+						.query_map(params![channel, limit as i64], recordingRow)?
+						.collect::<rusqlite::Result<Vec<_>>>()
+				}),
+		);
+
+		rows.unwrap_or_default()
+	}
+
+	pub fn event(&self, event: &Event) {
+		logged(
+			"record event",
+			self.conn.lock().unwrap().execute(
+				"INSERT INTO events (received_at, session, kind, subscription, payload) VALUES (?1, ?2, ?3, ?4, ?5)",
+				params![
+					event.receivedAt,
+					event.session,
+					event.kind,
+					event.subscription,
+					event.payload
+				],
+			),
+		);
+	}
+
+	pub fn tokenRefresh(&self, error: Option<&str>) {
+		logged(
+			"record token refresh",
+			self.conn.lock().unwrap().execute(
+				"INSERT INTO token_refreshes (at, success, error) VALUES (?1, ?2, ?3)",
+				params![Utc::now(), error.is_none(), error],
+			),
+		);
+	}
+
+	/// Whether the broadcast was recorded live through to its end
+	pub fn isRecorded(&self, streamId: &str) -> bool {
+		let conn = self.conn.lock().unwrap();
+		logged(
+			"look up recording",
+			conn
+				.query_row(
+					"SELECT 1 FROM recordings WHERE stream_id = ?1 AND status = ?2 LIMIT 1",
+					params![streamId, RecordingStatus::Finished.as_str()],
+					|_| Ok(()),
+				)
+				.optional(),
+		)
+		.flatten()
+		.is_some()
+	}
+
+	/// Whether a backfilled video was downloaded before, its file may be gone to retention since
+	pub fn isDownloaded(&self, videoId: &str) -> bool {
+		let conn = self.conn.lock().unwrap();
+		logged(
+			"look up download",
+			conn
+				.query_row(
+					"SELECT 1 FROM downloads WHERE video_id = ?1",
+					params![videoId],
+					|_| Ok(()),
+				)
+				.optional(),
+		)
+		.flatten()
+		.is_some()
+	}
+
+	pub fn downloaded(&self, video: &Video, path: &Path) {
+		logged(
+			"record download",
+			self.conn.lock().unwrap().execute(
+				"INSERT OR REPLACE INTO downloads (video_id, channel, kind, title, created_at, path, downloaded_at)
+				VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+				params![
+					video.id,
+					video.login,
+					video.kindName(),
+					video.title,
+					video.createdAt,
+					path.to_string_lossy(),
+					Utc::now()
+				],
+			),
+		);
+	}
+
+	pub fn set(&self, key: &str, value: &str) {
+		logged(
+			"store state",
+			self.conn.lock().unwrap().execute(
+				"INSERT INTO state (key, value) VALUES (?1, ?2) ON CONFLICT (key) DO UPDATE SET value = ?2",
+				params![key, value],
+			),
+		);
+	}
+}
@@ -4,4 +4,17 @@ pub enum Error {
 
 	UnAuthorised,
 	ExpiredAuth,
+	AuthDenied,
+	/// an oauth request that didn't go through or came back unreadable
+	Auth(String),
+
+	TokenStorage(String),
+	StateDb(String),
+	ChatReplay(String),
+	/// eventsub refused a subscription, status and body of the response
+	Subscription(String),
+	/// a helix request that didn't go through or came back with an error status
+	Helix(String),
+	/// streamlink couldn't be started or exited with an error
+	Recording(String),
 }
@@ -0,0 +1,167 @@
+use std::{
+	path::PathBuf,
+	process::{ExitStatus, Stdio},
+	time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+	data::{Config, HookConfig, HookEvent, HookPayload, RecordingGap, RecordingManifest},
+	template::RecordingInfo,
+};
+
+pub const DEFAULT_TIMEOUT: u64 = 300;
+
+/// Anything shorter is streamlink reconnect noise rather than a real hole
+const MIN_GAP_SECS: i64 = 5;
+
+/// Runs configured commands around recordings, each gets the payload as json on stdin
+pub struct Hooks {
+	hooks: Vec<HookConfig>,
+}
+
+fn gap(from: DateTime<Utc>, to: DateTime<Utc>) -> Option<RecordingGap> {
+	let seconds = (to - from).num_seconds();
+	(seconds >= MIN_GAP_SECS).then_some(RecordingGap { from, to, seconds })
+}
+
+/// Missed stream start plus holes between parts listed in the manifest; the recording begins with its first frame,
+/// which predates the recording start when streamlink rewound to the start of the stream
+pub fn gaps(info: &RecordingInfo, paths: &[PathBuf]) -> Vec<RecordingGap> {
+	let mut gaps = Vec::new();
+	let manifest = paths
+		.first()
+		.map(|first| crate::record::manifestPath(first))
+		.and_then(|path| std::fs::read(path).ok())
+		.and_then(|bytes| serde_json::from_slice::<RecordingManifest>(&bytes).ok());
+	let recordedFrom = manifest
+		.as_ref()
+		.and_then(|manifest| Some(manifest.parts.first()?.startedAt))
+		.or(info.mediaStartedAt)
+		.unwrap_or(info.startedAt);
+	if let Some(gap) = info
+		.streamStartedAt
+		.and_then(|streamStart| gap(streamStart, recordedFrom))
+	{
+		gaps.push(gap);
+	}
+
+	if let Some(manifest) = manifest {
+		gaps.extend(manifest.parts.windows(2).filter_map(|pair| {
+			pair[0]
+				.endedAt
+				.and_then(|ended| gap(ended, pair[1].startedAt))
+		}));
+	}
+
+	gaps
+}
+
+pub fn payload(
+	event: HookEvent,
+	info: &RecordingInfo,
+	paths: &[PathBuf],
+	result: Option<&std::io::Result<ExitStatus>>,
+) -> HookPayload {
+	let endedAt = (event != HookEvent::Start).then(Utc::now);
+	HookPayload {
+		event,
+		channel: info.login.clone(),
+		streamId: info.streamId.clone(),
+		title: info.title.clone(),
+		category: info.category.clone(),
+		paths: paths
+			.iter()
+			.map(|path| path.to_string_lossy().to_string())
+			.collect(),
+		startedAt: info.startedAt,
+		endedAt,
+		durationSeconds: endedAt.map(|ended| (ended - info.startedAt).num_seconds()),
+		exitCode: result.and_then(|result| result.as_ref().ok()?.code()),
+		error: result.and_then(|result| Some(result.as_ref().err()?.to_string())),
+		gaps: gaps(info, paths),
+	}
+}
+
+/// Whether a finished streamlink run counts as a failed recording
+pub fn failed(result: &std::io::Result<ExitStatus>) -> bool {
+	!result.as_ref().is_ok_and(|status| status.success())
+}
+
+async fn runHook(hook: &HookConfig, input: &[u8]) -> std::io::Result<Option<ExitStatus>> {
+	let mut child = tokio::process::Command::new(&hook.command[0])
+		.args(&hook.command[1..])
+		.stdin(Stdio::piped())
+		.stdout(Stdio::inherit())
+		.stderr(Stdio::inherit())
+		.kill_on_drop(true)
+		.spawn()?;
+
+	let mut stdin = child.stdin.take().unwrap();
+	// a hook ignoring stdin closes the pipe early, that's not an error
+	stdin.write_all(input).await.ok();
+	drop(stdin);
+
+	let timeout = Duration::from_secs(hook.timeout.unwrap_or(DEFAULT_TIMEOUT));
+	match tokio::time::timeout(timeout, child.wait()).await {
+		Ok(status) => status.map(Some),
+		Err(_) => {
+			child.kill().await?;
+			Ok(None)
+		}
+	}
+}
+
+impl Hooks {
+	pub fn new(config: &Config) -> Self {
+		Hooks {
+			hooks: config.hooks.clone().unwrap_or_default(),
+		}
+	}
+
+	fn matching(&self, event: HookEvent) -> impl Iterator<Item = &HookConfig> {
+		self.hooks.iter().filter(move |hook| {
+			hook
+				.events
+				.as_ref()
+				.is_none_or(|events| events.contains(&event))
+		})
+	}
+
+	/// Runs every hook subscribed to the payload event concurrently and waits for all of them
+	pub async fn run(&self, payload: &HookPayload) {
+		let input = serde_json::to_vec(payload).unwrap();
+		let futures = self.matching(payload.event).map(|hook| {
+			let input = &input;
+			async move {
+				let name = &hook.command[0];
+				match runHook(hook, input).await {
+					Ok(Some(status)) if status.success() => {
+						info!(
+							"[HOOK] {:?} {name} for {}: {status}",
+							payload.event, payload.channel
+						)
+					}
+					Ok(Some(status)) => {
+						warn!(
+							"[HOOK] {:?} {name} for {}: {status}",
+							payload.event, payload.channel
+						)
+					}
+					Ok(None) => error!(
+						"[HOOK] {:?} {name} for {} timed out and was killed",
+						payload.event, payload.channel
+					),
+					Err(err) => error!(
+						"[HOOK] {:?} {name} for {} failed to run: {err}",
+						payload.event, payload.channel
+					),
+				}
+			}
+		});
+		futures::future::join_all(futures).await;
+	}
+}
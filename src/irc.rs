@@ -0,0 +1,463 @@
+use std::{
+	collections::{BTreeSet, HashMap},
+	sync::Mutex,
+	time::{Duration, Instant},
+};
+
+use futures::{SinkExt, StreamExt};
+use log::{info, trace, warn};
+use tokio::sync::mpsc;
+use tungstenite::Message;
+
+use crate::data::{ChatMessage, Config};
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Twitch pings about every 5 minutes, a connection quiet for longer is dead
+const IDLE_TIMEOUT: Duration = Duration::from_secs(360);
+
+/// A line of twitch irc with its IRCv3 tags
+#[derive(Debug, Clone, Default)]
+pub struct IrcMessage {
+	pub tags: HashMap<String, String>,
+	pub prefix: Option<String>,
+	pub command: String,
+	pub params: Vec<String>,
+}
+
+fn unescapeTag(value: &str) -> String {
+	let mut out = String::with_capacity(value.len());
+	let mut chars = value.chars();
+	while let Some(c) = chars.next() {
+		if c != '\\' {
+			out.push(c);
+			continue;
+		}
+		match chars.next() {
+			Some(':') => out.push(';'),
+			Some('s') => out.push(' '),
+			Some('r') => out.push('\r'),
+			Some('n') => out.push('\n'),
+			Some(other) => out.push(other),
+			None => {}
+		}
+	}
+
+	out
+}
+
+/// `[@tags] [:prefix] COMMAND [params] [:trailing]`
+pub fn parse(line: &str) -> Option<IrcMessage> {
+	let mut rest = line.trim_end_matches(['\r', '\n']);
+	let mut message = IrcMessage::default();
+	if let Some(tagged) = rest.strip_prefix('@') {
+		let (tags, after) = tagged.split_once(' ')?;
+		message.tags = tags
+			.split(';')
+			.map(|tag| match tag.split_once('=') {
+				Some((key, value)) => (key.to_string(), unescapeTag(value)),
+				None => (tag.to_string(), String::new()),
+			})
+			.collect();
+		rest = after.trim_start();
+	}
+	if let Some(prefixed) = rest.strip_prefix(':') {
+		let (prefix, after) = prefixed.split_once(' ')?;
+		message.prefix = Some(prefix.to_string());
+		rest = after.trim_start();
+	}
+
+	let (head, trailing) = match rest.split_once(" :") {
+		Some((head, trailing)) => (head, Some(trailing)),
+		None => (rest, None),
+	};
+	let mut words = head.split(' ').filter(|word| !word.is_empty());
+	message.command = words.next()?.to_string();
+	message.params = words
+		.map(String::from)
+		.chain(trailing.map(String::from))
+		.collect();
+
+	Some(message)
+}
+
+/// (id, name) of every emote in the message in order, from an `emotes` tag like `25:0-4,12-16/1902:6-10`;
+/// positions count code points, not bytes
+pub fn emotes(tag: &str, text: &str) -> Vec<(String, String)> {
+	let chars = text.chars().collect::<Vec<_>>();
+	let mut found = tag
+		.split('/')
+		.filter_map(|emote| emote.split_once(':'))
+		.flat_map(|(id, ranges)| ranges.split(',').map(move |range| (id, range)))
+		.filter_map(|(id, range)| {
+			let (from, to) = range.split_once('-')?;
+			let (from, to) = (from.parse::<usize>().ok()?, to.parse::<usize>().ok()?);
+			let name = chars.get(from..=to)?.iter().collect::<String>();
+			Some((from, id.to_string(), name))
+		})
+		.collect::<Vec<_>>();
+	found.sort_by_key(|(from, _, _)| *from);
+
+	found.into_iter().map(|(_, id, name)| (id, name)).collect()
+}
+
+/// Chat record of a PRIVMSG, the same eventsub chat notifications turn into
+pub fn chat(message: &IrcMessage) -> Option<ChatMessage> {
+	if message.command != "PRIVMSG" {
+		return None;
+	}
+	let channel = message.params.first()?.trim_start_matches('#');
+	let text = message.params.get(1)?;
+	// `/me` arrives wrapped as a ctcp action
+	let text = text
+		.strip_prefix("\u{1}ACTION ")
+		.map_or(text.as_str(), |action| action.trim_end_matches('\u{1}'));
+	let chatter = match message.tags.get("display-name") {
+		Some(name) if !name.is_empty() => name.as_str(),
+		_ => message.prefix.as_deref()?.split('!').next()?,
+	};
+
+	Some(ChatMessage {
+		msg: crate::chat::message(chatter, text),
+		channel: channel.to_string(),
+		emotes: message
+			.tags
+			.get("emotes")
+			.map(|tag| emotes(tag, text))
+			.unwrap_or_default(),
+	})
+}
+
+enum Command {
+	Join(String),
+	Part(String),
+}
+
+/// Anonymous twitch chat reader over irc on websocket, joined channels survive reconnects
+pub struct Client {
+	url: String,
+	chat: mpsc::UnboundedSender<ChatMessage>,
+	channels: Mutex<BTreeSet<String>>,
+	commands: mpsc::UnboundedSender<Command>,
+	inbox: tokio::sync::Mutex<mpsc::UnboundedReceiver<Command>>,
+}
+
+impl Client {
+	pub fn new(config: &Config, chat: mpsc::UnboundedSender<ChatMessage>) -> Self {
+		let (commands, inbox) = mpsc::unbounded_channel();
+		Client {
+			url: config
+				.ircUrl
+				.clone()
+				.unwrap_or(crate::config::DEFAULT_IRC_URL.to_string()),
+			chat,
+			channels: Mutex::new(BTreeSet::new()),
+			commands,
+			inbox: tokio::sync::Mutex::new(inbox),
+		}
+	}
+
+	pub fn join(&self, channel: &str) {
+		if self.channels.lock().unwrap().insert(channel.to_string()) {
+			info!("[IRCC] reading chat of {channel} over irc");
+			self.commands.send(Command::Join(channel.to_string())).ok();
+		}
+	}
+
+	pub fn part(&self, channel: &str) {
+		if self.channels.lock().unwrap().remove(channel) {
+			self.commands.send(Command::Part(channel.to_string())).ok();
+		}
+	}
+
+	/// Stays connected while there are channels to read, reconnecting with a growing delay
+	pub async fn run(&self) {
+		let mut inbox = self.inbox.lock().await;
+		let mut backoff = MIN_BACKOFF;
+		loop {
+			// no connection until there's a channel to join
+			while self.channels.lock().unwrap().is_empty() {
+				if inbox.recv().await.is_none() {
+					return;
+				}
+			}
+
+			let started = Instant::now();
+			match self.session(&mut inbox).await {
+				Ok(()) => info!("[IRCC] disconnected, reconnecting"),
+				Err(err) => warn!("[IRCC] connection failed: {err}"),
+			}
+			// a connection that held up for a while starts the delay over
+			if started.elapsed() > MAX_BACKOFF {
+				backoff = MIN_BACKOFF;
+			}
+			tokio::time::sleep(backoff).await;
+			backoff = (backoff * 2).min(MAX_BACKOFF);
+		}
+	}
+
+	async fn session(
+		&self,
+		inbox: &mut mpsc::UnboundedReceiver<Command>,
+	) -> Result<(), tungstenite::Error> {
+		let (mut socket, _) = tokio_tungstenite::connect_async(&self.url).await?;
+		// whatever was queued while disconnected is covered by joining the whole set
+		while inbox.try_recv().is_ok() {}
+
+		// justinfan logins read chat without credentials
+		let nick = format!(
+			"justinfan{}",
+			10000 + chrono::Utc::now().timestamp_subsec_micros() % 90000
+		);
+		let channels = self
+			.channels
+			.lock()
+			.unwrap()
+			.iter()
+			.map(|channel| format!("#{channel}"))
+			.collect::<Vec<_>>()
+			.join(",");
+		for line in [
+			"CAP REQ :twitch.tv/tags twitch.tv/commands".to_string(),
+			format!("NICK {nick}"),
+			format!("JOIN {channels}"),
+		] {
+			socket.send(Message::text(line)).await?;
+		}
+
+		loop {
+			tokio::select! {
+				command = inbox.recv() => {
+					let line = match command {
+						Some(Command::Join(channel)) => format!("JOIN #{channel}"),
+						Some(Command::Part(channel)) => format!("PART #{channel}"),
+						None => return Ok(()),
+					};
+					socket.send(Message::text(line)).await?;
+				}
+				frame = socket.next() => {
+					let text = match frame {
+						Some(Ok(Message::Text(text))) => text,
+						Some(Ok(Message::Close(_))) | None => return Ok(()),
+						Some(Ok(_)) => continue,
+						Some(Err(err)) => return Err(err),
+					};
+					for message in text.lines().filter_map(parse) {
+						match message.command.as_str() {
+							"PING" => {
+								let server = message.params.last().map_or("tmi.twitch.tv", String::as_str);
+								socket.send(Message::text(format!("PONG :{server}"))).await?;
+							}
+							"RECONNECT" => return Ok(()),
+							"001" => info!("[IRCC] connected as {nick}"),
+							"NOTICE" => warn!("[IRCC] {}", message.params.join(" ")),
+							_ => match chat(&message) {
+								Some(chat) => {
+									self.chat.send(chat).ok();
+								}
+								None => trace!("[IRCC] {} {:?}", message.command, message.params),
+							},
+						}
+					}
+				}
+				_ = tokio::time::sleep(IDLE_TIMEOUT) => {
+					warn!("[IRCC] nothing heard for {} s", IDLE_TIMEOUT.as_secs());
+					return Ok(());
+				}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+
+	use tokio::net::TcpListener;
+
+	use super::*;
+
+	#[test]
+	fn parsesTagsPrefixAndTrailing() {
+		let message = parse(
+			"@badge-info=;display-name=Viewer_One;emotes= :viewer_one!viewer_one@viewer_one.tmi.twitch.tv PRIVMSG #streamer :hi there\r\n",
+		)
+		.unwrap();
+
+		assert_eq!(message.tags["display-name"], "Viewer_One");
+		assert_eq!(message.tags["badge-info"], "");
+		assert_eq!(
+			message.prefix.as_deref(),
+			Some("viewer_one!viewer_one@viewer_one.tmi.twitch.tv")
+		);
+		assert_eq!(message.command, "PRIVMSG");
+		assert_eq!(message.params, ["#streamer", "hi there"]);
+	}
+
+	#[test]
+	fn parsesBareCommands() {
+		let ping = parse("PING :tmi.twitch.tv").unwrap();
+		assert_eq!(ping.prefix, None);
+		assert_eq!(ping.command, "PING");
+		assert_eq!(ping.params, ["tmi.twitch.tv"]);
+
+		let welcome = parse(":tmi.twitch.tv 001 justinfan12345 :Welcome, GLHF!").unwrap();
+		assert_eq!(welcome.command, "001");
+		assert_eq!(welcome.params, ["justinfan12345", "Welcome, GLHF!"]);
+
+		let ack = parse(":tmi.twitch.tv CAP * ACK").unwrap();
+		assert_eq!(ack.params, ["*", "ACK"]);
+
+		assert!(parse("").is_none());
+		assert!(parse("@only-tags").is_none());
+	}
+
+	#[test]
+	fn unescapesTagValues() {
+		assert_eq!(unescapeTag(r"a\sb\:c\\d"), r"a b;c\d");
+		assert_eq!(unescapeTag(r"line\rbreak\n"), "line\rbreak\n");
+		// unknown escapes drop the backslash, a trailing one is dropped entirely
+		assert_eq!(unescapeTag(r"\x\"), "x");
+	}
+
+	#[test]
+	fn emotesInOrderByCodePoints() {
+		assert_eq!(
+			emotes("25:0-4,12-16/1902:6-10", "Kappa Keepo Kappa"),
+			[
+				("25".to_string(), "Kappa".to_string()),
+				("1902".to_string(), "Keepo".to_string()),
+				("25".to_string(), "Kappa".to_string()),
+			]
+		);
+		// positions count characters, the emote after the multibyte one is found all the same
+		assert_eq!(
+			emotes("25:2-6", "✓ Kappa"),
+			[("25".to_string(), "Kappa".to_string())]
+		);
+		assert!(emotes("25:10-14", "Kappa").is_empty());
+		assert!(emotes("", "Kappa").is_empty());
+	}
+
+	#[test]
+	fn chatFromPrivmsg() {
+		let message = parse(
+			"@display-name=Viewer_One;emotes=25:0-4 :viewer_one!viewer_one@viewer_one.tmi.twitch.tv PRIVMSG #streamer :Kappa hi",
+		)
+		.unwrap();
+		let record = chat(&message).unwrap();
+		assert_eq!(record.channel, "streamer");
+		assert_eq!(record.msg, "Viewer_One: Kappa hi");
+		assert_eq!(record.emotes, [("25".to_string(), "Kappa".to_string())]);
+
+		// `/me` loses its ctcp wrapping, a missing display name falls back to the login
+		let message = parse(
+			"@display-name= :viewer_two!viewer_two@viewer_two.tmi.twitch.tv PRIVMSG #streamer :\u{1}ACTION waves\u{1}",
+		)
+		.unwrap();
+		let record = chat(&message).unwrap();
+		assert_eq!(record.msg, "viewer_two: waves");
+		assert!(record.emotes.is_empty());
+
+		assert!(chat(&parse("PING :tmi.twitch.tv").unwrap()).is_none());
+	}
+
+	type Server = tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>;
+
+	async fn accept(listener: &TcpListener) -> Server {
+		let (stream, _) = listener.accept().await.unwrap();
+		tokio_tungstenite::accept_async(stream).await.unwrap()
+	}
+
+	async fn receive(server: &mut Server) -> String {
+		loop {
+			match server.next().await.unwrap().unwrap() {
+				Message::Text(text) => return text.to_string(),
+				_ => continue,
+			}
+		}
+	}
+
+	async fn send(server: &mut Server, line: &str) {
+		server.send(Message::text(line)).await.unwrap();
+	}
+
+	/// Login lines of a fresh connection, the channels it joins
+	async fn handshake(server: &mut Server) -> String {
+		assert_eq!(
+			receive(server).await,
+			"CAP REQ :twitch.tv/tags twitch.tv/commands"
+		);
+		assert!(receive(server).await.starts_with("NICK justinfan"));
+		let join = receive(server).await;
+		send(
+			server,
+			":tmi.twitch.tv 001 justinfan12345 :Welcome, GLHF!\r\n",
+		)
+		.await;
+		join
+	}
+
+	#[tokio::test]
+	async fn readsChatAndRejoinsAfterReconnect() {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let config: Config = serde_json::from_value(serde_json::json!({
+			"clientId": "id",
+			"clientSecret": "secret",
+			"root": "/tmp",
+			"ircUrl": format!("ws://{}", listener.local_addr().unwrap()),
+		}))
+		.unwrap();
+		let (tx, mut rx) = mpsc::unbounded_channel();
+		let client = Arc::new(Client::new(&config, tx));
+		client.join("streamer");
+		let runner = client.clone();
+		tokio::spawn(async move { runner.run().await });
+
+		let test = async {
+			let mut server = accept(&listener).await;
+			assert_eq!(handshake(&mut server).await, "JOIN #streamer");
+
+			send(&mut server, "PING :tmi.twitch.tv\r\n").await;
+			assert_eq!(receive(&mut server).await, "PONG :tmi.twitch.tv");
+
+			send(
+				&mut server,
+				"@display-name=Viewer_One;emotes=25:0-4,12-16/1902:6-10 :viewer_one!viewer_one@viewer_one.tmi.twitch.tv PRIVMSG #streamer :Kappa Keepo Kappa\r\n\
+				:viewer_two!viewer_two@viewer_two.tmi.twitch.tv PRIVMSG #streamer :\u{1}ACTION waves\u{1}\r\n",
+			)
+			.await;
+			let chat = rx.recv().await.unwrap();
+			assert_eq!(chat.channel, "streamer");
+			assert_eq!(chat.msg, "Viewer_One: Kappa Keepo Kappa");
+			assert_eq!(
+				chat.emotes,
+				[
+					("25".to_string(), "Kappa".to_string()),
+					("1902".to_string(), "Keepo".to_string()),
+					("25".to_string(), "Kappa".to_string()),
+				]
+			);
+			assert_eq!(rx.recv().await.unwrap().msg, "viewer_two: waves");
+
+			// channels joined later go out on the open connection
+			client.join("other");
+			assert_eq!(receive(&mut server).await, "JOIN #other");
+
+			// asked to reconnect, the new connection joins everything again
+			send(&mut server, ":tmi.twitch.tv RECONNECT\r\n").await;
+			let mut server = accept(&listener).await;
+			assert_eq!(handshake(&mut server).await, "JOIN #other,#streamer");
+
+			// same after the server drops the connection
+			client.part("other");
+			assert_eq!(receive(&mut server).await, "PART #other");
+			server.close(None).await.unwrap();
+			let mut server = accept(&listener).await;
+			assert_eq!(handshake(&mut server).await, "JOIN #streamer");
+		};
+		tokio::time::timeout(Duration::from_secs(20), test)
+			.await
+			.expect("irc exchange timed out");
+	}
+}
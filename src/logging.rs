@@ -0,0 +1,397 @@
+use std::{
+	fs::File,
+	io::Write,
+	path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+
+use crate::data::{Config, LogFormat, LogOutput, RotateConfig, RotateEvery};
+
+/// Stdout at info, our own targets into `live-downloader.log` and everything else into `vendor.log`
+fn defaultOutputs() -> Vec<LogOutput> {
+	vec![
+		LogOutput {
+			level: Some("info".to_string()),
+			..Default::default()
+		},
+		LogOutput {
+			path: Some("live-downloader.log".to_string()),
+			level: Some("off".to_string()),
+			targets: Some([("ld".to_string(), "trace".to_string())].into()),
+			..Default::default()
+		},
+		LogOutput {
+			path: Some("vendor.log".to_string()),
+			level: Some("trace".to_string()),
+			targets: Some([("ld".to_string(), "off".to_string())].into()),
+			..Default::default()
+		},
+	]
+}
+
+fn levelName(level: log::Level) -> &'static str {
+	match level {
+		log::Level::Error => "ERROR",
+		log::Level::Warn => "WARN_",
+		log::Level::Info => "INFO_",
+		log::Level::Debug => "DEBUG",
+		log::Level::Trace => "TRACE",
+	}
+}
+
+/// Log file that moves itself aside once it grows past a size or a period ends
+struct RotatingFile {
+	path: PathBuf,
+	file: File,
+	size: u64,
+	opened: DateTime<Utc>,
+	rotate: RotateConfig,
+	/// rotation only happens between lines, fern can hand a line over in several writes
+	lineStart: bool,
+}
+
+fn period(every: RotateEvery, time: DateTime<Utc>) -> String {
+	match every {
+		RotateEvery::Hour => time.format("%Y%m%d%H").to_string(),
+		RotateEvery::Day => time.format("%Y%m%d").to_string(),
+	}
+}
+
+fn compress(path: &Path) -> std::io::Result<()> {
+	let mut gzPath = path.as_os_str().to_os_string();
+	gzPath.push(".gz");
+	let mut encoder =
+		flate2::write::GzEncoder::new(File::create(&gzPath)?, flate2::Compression::default());
+	std::io::copy(&mut File::open(path)?, &mut encoder)?;
+	encoder.finish()?.sync_all()?;
+	std::fs::remove_file(path)
+}
+
+/// Removes the oldest rotated siblings of `path` past `keep`
+fn prune(path: &Path, keep: usize) {
+	let (Some(dir), Some(stem)) = (path.parent(), path.file_stem()) else {
+		return;
+	};
+	let prefix = format!("{}.", stem.to_string_lossy());
+	let Ok(entries) = std::fs::read_dir(if dir.as_os_str().is_empty() {
+		Path::new(".")
+	} else {
+		dir
+	}) else {
+		return;
+	};
+
+	let mut rotated = entries
+		.flatten()
+		.map(|entry| entry.path())
+		.filter(|file| {
+			file.file_name() != path.file_name()
+				&& file
+					.file_name()
+					.is_some_and(|name| name.to_string_lossy().starts_with(&prefix))
+		})
+		.collect::<Vec<_>>();
+	// names carry the rotation time, newest sort last
+	rotated.sort();
+	let excess = rotated.len().saturating_sub(keep);
+	for file in &rotated[..excess] {
+		std::fs::remove_file(file).ok();
+	}
+}
+
+impl RotatingFile {
+	fn open(path: PathBuf, rotate: RotateConfig) -> std::io::Result<Self> {
+		let file = std::fs::OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(&path)?;
+		let meta = file.metadata()?;
+		// an existing file belongs to the period it was last written in
+		let opened = meta
+			.modified()
+			.map(DateTime::<Utc>::from)
+			.unwrap_or(Utc::now());
+
+		Ok(RotatingFile {
+			path,
+			file,
+			size: meta.len(),
+			opened,
+			rotate,
+			lineStart: true,
+		})
+	}
+
+	fn due(&self, incoming: usize) -> bool {
+		let bySize = self
+			.rotate
+			.maxMb
+			.is_some_and(|mb| self.size > 0 && self.size + incoming as u64 > mb * 1024 * 1024);
+		let byTime = self
+			.rotate
+			.every
+			.is_some_and(|every| period(every, self.opened) != period(every, Utc::now()));
+		bySize || byTime
+	}
+
+	fn rotateNow(&mut self) -> std::io::Result<()> {
+		self.file.flush()?;
+		let stem = self.path.file_stem().unwrap_or_default().to_string_lossy();
+		let mut name = format!("{stem}.{}", self.opened.format("%Y-%m-%d_%H-%M-%S%.3f"));
+		if let Some(ext) = self.path.extension() {
+			name.push('.');
+			name.push_str(&ext.to_string_lossy());
+		}
+		let rotated = self.path.with_file_name(name);
+		std::fs::rename(&self.path, &rotated)?;
+
+		self.file = std::fs::OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(&self.path)?;
+		self.size = 0;
+		self.opened = Utc::now();
+
+		let path = self.path.clone();
+		let compressed = self.rotate.compress.unwrap_or(false);
+		let keep = self.rotate.keep;
+		// compressing a big log shouldn't stall whoever is logging
+		std::thread::spawn(move || {
+			if compressed && let Err(err) = compress(&rotated) {
+				log::error!("[LOGS] failed to compress {}: {err}", rotated.display());
+			}
+			if let Some(keep) = keep {
+				prune(&path, keep);
+			}
+		});
+
+		Ok(())
+	}
+}
+
+impl Write for RotatingFile {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		if self.lineStart && self.due(buf.len()) {
+			self.rotateNow()?;
+		}
+		self.file.write_all(buf)?;
+		self.size += buf.len() as u64;
+		self.lineStart = buf.ends_with(b"\n");
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		self.file.flush()
+	}
+}
+
+fn dispatch(output: &LogOutput, logDir: &Path) -> Result<fern::Dispatch, fern::InitError> {
+	let parse = |level: &str| level.parse().expect("log levels are validated with config");
+	let mut dispatch = fern::Dispatch::new().level(
+		output
+			.level
+			.as_deref()
+			.map_or(log::LevelFilter::Info, parse),
+	);
+	for (target, level) in output.targets.iter().flatten() {
+		dispatch = dispatch.level_for(target.clone(), parse(level));
+	}
+
+	dispatch = match output.format {
+		LogFormat::Text => dispatch.format(|out, message, record| {
+			out.finish(format_args!(
+				"[{} {}] [{}] {}",
+				Utc::now(),
+				levelName(record.level()),
+				record.target(),
+				message
+			))
+		}),
+		LogFormat::Json => dispatch.format(|out, message, record| {
+			out.finish(format_args!(
+				"{}",
+				serde_json::json!({
+					"ts": Utc::now(),
+					"level": record.level().as_str(),
+					"target": record.target(),
+					"message": message.to_string(),
+				})
+			))
+		}),
+	};
+
+	let Some(path) = &output.path else {
+		return Ok(dispatch.chain(std::io::stdout()));
+	};
+	let path = logDir.join(path);
+	if let Some(parent) = path.parent() {
+		std::fs::create_dir_all(parent)?;
+	}
+
+	Ok(match &output.rotate {
+		Some(rotate) => {
+			dispatch.chain(Box::new(RotatingFile::open(path, rotate.clone())?) as Box<dyn Write + Send>)
+		}
+		None => dispatch.chain(fern::log_file(path)?),
+	})
+}
+
+/// Installs the diagnostic logger, chat is archived separately by `chat::Archive`
+pub fn setup(config: &Config) -> Result<(), fern::InitError> {
+	let logDir = PathBuf::from(config.logDir.as_deref().unwrap_or("."));
+	std::fs::create_dir_all(&logDir)?;
+
+	let outputs = config.logging.clone().unwrap_or_else(defaultOutputs);
+	let mut root = fern::Dispatch::new();
+	for output in &outputs {
+		root = root.chain(dispatch(output, &logDir)?);
+	}
+	root.apply()?;
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn dir(name: &str) -> PathBuf {
+		let dir = std::env::temp_dir().join(format!("ld-logging-{}-{name}", std::process::id()));
+		std::fs::remove_dir_all(&dir).ok();
+		std::fs::create_dir_all(&dir).unwrap();
+		dir
+	}
+
+	/// Files in `dir` apart from the live log, oldest first
+	fn rotated(dir: &Path) -> Vec<String> {
+		let mut names = std::fs::read_dir(dir)
+			.unwrap()
+			.flatten()
+			.map(|entry| entry.file_name().to_string_lossy().to_string())
+			.filter(|name| name != "ld.log")
+			.collect::<Vec<_>>();
+		names.sort();
+		names
+	}
+
+	#[test]
+	fn rotatesBySize() {
+		let dir = dir("size");
+		let rotate = RotateConfig {
+			maxMb: Some(1),
+			..Default::default()
+		};
+		let mut file = RotatingFile::open(dir.join("ld.log"), rotate).unwrap();
+		let line = [b"x".repeat(600 * 1024), b"\n".to_vec()].concat();
+		file.write_all(&line).unwrap();
+		assert!(rotated(&dir).is_empty());
+		// a line is never split across files, even past the size
+		file.write_all(&line[..1024]).unwrap();
+		file.write_all(&line[1024..]).unwrap();
+		assert!(rotated(&dir).is_empty());
+
+		file.write_all(&line).unwrap();
+		let names = rotated(&dir);
+		assert_eq!(names.len(), 1);
+		assert!(names[0].starts_with("ld.") && names[0].ends_with(".log"));
+		assert_eq!(
+			std::fs::metadata(dir.join(&names[0])).unwrap().len(),
+			2 * line.len() as u64
+		);
+		assert_eq!(
+			std::fs::metadata(dir.join("ld.log")).unwrap().len(),
+			line.len() as u64
+		);
+		std::fs::remove_dir_all(&dir).ok();
+	}
+
+	#[test]
+	fn rotatesWhenThePeriodEnds() {
+		let dir = dir("age");
+		let path = dir.join("ld.log");
+		let yesterday = Utc::now() - chrono::Duration::days(1);
+		std::fs::write(&path, "old\n").unwrap();
+		File::options()
+			.append(true)
+			.open(&path)
+			.unwrap()
+			.set_modified(yesterday.into())
+			.unwrap();
+		let rotate = RotateConfig {
+			every: Some(RotateEvery::Day),
+			..Default::default()
+		};
+
+		// an existing file is rotated on the first line of a new day
+		let mut file = RotatingFile::open(path.clone(), rotate).unwrap();
+		file.write_all(b"new\n").unwrap();
+		assert_eq!(
+			rotated(&dir),
+			[format!(
+				"ld.{}.log",
+				yesterday.format("%Y-%m-%d_%H-%M-%S%.3f")
+			)]
+		);
+		assert_eq!(std::fs::read_to_string(&path).unwrap(), "new\n");
+		file.write_all(b"newer\n").unwrap();
+		assert_eq!(rotated(&dir).len(), 1);
+		std::fs::remove_dir_all(&dir).ok();
+	}
+
+	#[test]
+	fn prunesTheOldestRotatedFiles() {
+		let dir = dir("prune");
+		for name in [
+			"ld.log",
+			"ld.2024-01-01_00-00-00.000.log.gz",
+			"ld.2024-01-02_00-00-00.000.log.gz",
+			"ld.2024-01-03_00-00-00.000.log",
+			"vendor.log",
+			"vendor.2024-01-01_00-00-00.000.log",
+		] {
+			std::fs::write(dir.join(name), "").unwrap();
+		}
+
+		prune(&dir.join("ld.log"), 2);
+		assert_eq!(
+			rotated(&dir),
+			[
+				"ld.2024-01-02_00-00-00.000.log.gz",
+				"ld.2024-01-03_00-00-00.000.log",
+				"vendor.2024-01-01_00-00-00.000.log",
+				"vendor.log",
+			]
+		);
+		std::fs::remove_dir_all(&dir).ok();
+	}
+
+	#[test]
+	fn compressesRotatedFiles() {
+		let dir = dir("compress");
+		let rotate = RotateConfig {
+			maxMb: Some(1),
+			keep: Some(1),
+			compress: Some(true),
+			..Default::default()
+		};
+		let mut file = RotatingFile::open(dir.join("ld.log"), rotate).unwrap();
+		let line = [b"x".repeat(1024 * 1024), b"\n".to_vec()].concat();
+		for _ in 0..3 {
+			file.write_all(&line).unwrap();
+			// compression and pruning run in the background
+			for _ in 0..100 {
+				let names = rotated(&dir);
+				if names.len() <= 1 && names.iter().all(|name| name.ends_with(".gz")) {
+					break;
+				}
+				std::thread::sleep(std::time::Duration::from_millis(20));
+			}
+		}
+
+		let names = rotated(&dir);
+		assert_eq!(names.len(), 1, "{names:?}");
+		assert!(names[0].ends_with(".log.gz"));
+		std::fs::remove_dir_all(&dir).ok();
+	}
+}
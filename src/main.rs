@@ -3,109 +3,74 @@
 use futures::StreamExt;
 use log::{debug, error, info, trace, warn};
 use std::{
-	fs,
 	path::PathBuf,
-	process::Stdio,
 	sync::{Arc, atomic::Ordering},
 	time::Duration,
 };
 use tokio::sync::Mutex;
 
 pub mod api;
+pub mod assets;
+pub mod backfill;
+pub mod catchup;
+pub mod chat;
+pub mod cli;
+pub mod config;
+pub mod control;
 pub mod data;
+pub mod db;
 pub mod err;
+pub mod hooks;
+pub mod irc;
+pub mod logging;
+pub mod metrics;
+pub mod notify;
+pub mod record;
+pub mod replay;
 pub mod socket;
+pub mod storage;
+pub mod subtitles;
+pub mod template;
 pub mod token;
+pub mod upload;
 
 use data::InternalMessage;
 use twitch_api::eventsub::EventType;
 
 use crate::{
 	api::Api,
-	data::{Config, ValidationResponse},
+	data::{AuthMode, Config, ValidationResponse},
+	template::RecordingInfo,
 };
 
-fn setup_logger(config: &Config) -> Result<(), fern::InitError> {
-	let mut chatChain = fern::Dispatch::new();
-
-	let mut chatRoot = "chat".to_string();
-	if let Some(specifiedRoot) = config.chatRoot.clone() {
-		chatRoot = specifiedRoot;
-	}
-	fs::create_dir_all(&chatRoot)?;
-
-	for broadcaster in &config.broadcasters {
-		let mut path = PathBuf::from(&chatRoot).join(broadcaster);
-		path.set_extension("log");
-
-		chatChain = chatChain.chain(
-			fern::Dispatch::new()
-				.level(log::LevelFilter::Off)
-				.level_for(format!("ld::chat::{broadcaster}"), log::LevelFilter::Trace)
-				.format(|out, message, record| {
-					out.finish(format_args!(
-						"[{} {}] {}",
-						chrono::Utc::now(),
-						record.target(),
-						message
-					))
-				})
-				.chain(fern::log_file(path)?),
-		);
-	}
-
-	let loggerChain = fern::Dispatch::new()
-		.format(|out, message, record| {
-			out.finish(format_args!(
-				"[{} {}] [{}] {}",
-				chrono::Utc::now(),
-				match record.level() {
-					log::Level::Error => "ERROR",
-					log::Level::Warn => "WARN_",
-					log::Level::Info => "INFO_",
-					log::Level::Debug => "DEBUG",
-					log::Level::Trace => "TRACE",
-				},
-				record.target(),
-				message
-			))
-		})
-		.chain(
-			fern::Dispatch::new()
-				.level(log::LevelFilter::Info)
-				.chain(std::io::stdout()),
-		)
-		.chain(
-			fern::Dispatch::new()
-				.level(log::LevelFilter::Off)
-				.level_for("ld", log::LevelFilter::Trace)
-				.chain(fern::log_file("live-downloader.log")?),
-		)
-		.chain(
-			fern::Dispatch::new()
-				.level(log::LevelFilter::Trace)
-				.level_for("ld", log::LevelFilter::Off)
-				.chain(fern::log_file("vendor.log")?),
-		);
-
-	fern::Dispatch::new()
-		.chain(chatChain)
-		.chain(loggerChain)
-		.apply()?;
-
-	Ok(())
-}
-
 async fn validateAndRefreshToken(
 	api: &std::sync::Arc<tokio::sync::Mutex<api::Api>>,
+	tokenStore: &dyn token::TokenStore,
+	tx: &tokio::sync::broadcast::Sender<InternalMessage>,
+	db: &db::Db,
 ) -> Option<ValidationResponse> {
 	info!("[VLDT] ");
 	let mut apilock: tokio::sync::MutexGuard<Api> = api.lock().await;
 	match apilock.validate().await {
 		Err(err) => match err {
 			crate::err::Error::UnAuthorised => {
-				let token = apilock.refreshToken().await.unwrap();
-				token::writeRefreshToken(&token).await.unwrap();
+				match apilock.refreshToken().await {
+					Ok(token) => {
+						db.tokenRefresh(None);
+						// the refreshed token is in use either way, only the next start would need to re-auth
+						if let Err(err) = token::writeRefreshToken(tokenStore, &token).await {
+							error!("[TKNR] failed to store refreshed token: {:?}", err);
+						}
+					}
+					Err(err) => {
+						error!("[TKNR] failed to refresh token: {:?}", err);
+						db.tokenRefresh(Some(&format!("{:?}", err)));
+						tx.send(InternalMessage::AuthFailed {
+							reason: format!("{:?}", err),
+						})
+						.ok();
+					}
+				}
 				None
 			}
 			_ => {
@@ -117,12 +82,201 @@ async fn validateAndRefreshToken(
 	}
 }
 
+/// Eventsub topics a channel is subscribed to
+#[derive(Debug, Clone, Copy, Default)]
+struct Topics {
+	live: bool,
+	chat: bool,
+}
+
+/// Topics of every channel to watch: broadcasters get their live status unless they're `video: false`,
+/// `chatChannels` and broadcasters get chat when there's an account to read it with
+fn planSubscriptions(
+	runtime: &control::Runtime,
+	chatChannels: &[String],
+	chat: bool,
+) -> Vec<(String, Topics)> {
+	let broadcasters = runtime.broadcasters().into_iter().map(|login| {
+		let live = runtime.recordsVideo(&login);
+		(login, Topics { live, chat })
+	});
+	let chats = chatChannels
+		.iter()
+		.map(|login| (login.clone(), Topics { live: false, chat }));
+
+	let mut plan: Vec<(String, Topics)> = Vec::new();
+	for (login, topics) in broadcasters.chain(chats) {
+		match plan.iter_mut().find(|(planned, _)| *planned == login) {
+			Some((_, planned)) => {
+				planned.live |= topics.live;
+				planned.chat |= topics.chat;
+			}
+			None => plan.push((login, topics)),
+		}
+	}
+	plan.retain(|(_, topics)| topics.live || topics.chat);
+
+	plan
+}
+
+/// Subscribes to the planned topics of the channel, chat is read as `account`;
+/// fails when the chat subscription is refused so chat can be read elsewhere.
+/// The api is locked per request, the pauses between them leave it to others
+async fn subscribeChannel(
+	api: &Mutex<Api>,
+	session: &str,
+	user: &data::UserData,
+	topics: Topics,
+	account: Option<&data::UserData>,
+) -> Result<(), err::Error> {
+	if topics.live {
+		// refusals are logged as they happen, there's nothing else to read live status from
+		api
+			.lock()
+			.await
+			.subscribe(
+				session,
+				EventType::StreamOnline,
+				serde_json::json!({ "broadcaster_user_id": user.id}),
+			)
+			.await
+			.ok();
+		tokio::time::sleep(Duration::from_millis(400)).await;
+
+		api
+			.lock()
+			.await
+			.subscribe(
+				session,
+				EventType::StreamOffline,
+				serde_json::json!({ "broadcaster_user_id": user.id}),
+			)
+			.await
+			.ok();
+		tokio::time::sleep(Duration::from_millis(400)).await;
+	}
+
+	if let Some(account) = account.filter(|_| topics.chat) {
+		let result = api
+			.lock()
+			.await
+			.subscribe(
+				session,
+				EventType::ChannelChatMessage,
+				serde_json::json!({ "broadcaster_user_id": user.id, "user_id": account.id }),
+			)
+			.await;
+		tokio::time::sleep(Duration::from_millis(400)).await;
+		return result;
+	}
+
+	Ok(())
+}
+
+/// Re-runs the device flow when the stored token was granted fewer scopes than enabled features need
+async fn ensureScopes(
+	api: &std::sync::Arc<tokio::sync::Mutex<api::Api>>,
+	tokenStore: &dyn token::TokenStore,
+	config: &Config,
+	tx: &tokio::sync::broadcast::Sender<InternalMessage>,
+	db: &db::Db,
+) -> Result<(), err::Error> {
+	let mut validation = validateAndRefreshToken(api, tokenStore, tx, db).await;
+	if validation.is_none() {
+		// token got refreshed, scopes carry over from the original grant
+		validation = validateAndRefreshToken(api, tokenStore, tx, db).await;
+	}
+	let Some(validation) = validation else {
+		error!("[SCPE] failed to validate token, skipping scope check");
+		return Ok(());
+	};
+
+	let missing = token::missingScopes(
+		&token::requiredScopes(config),
+		validation.scopes.as_deref().unwrap_or_default(),
+	);
+	if missing.is_empty() {
+		return Ok(());
+	}
+
+	warn!("[SCPE] token lacks scopes {:?}, re-authorising", missing);
+	let token = token::authorise(tokenStore, config)
+		.await
+		.inspect_err(|err| {
+			error!(
+				"[SCPE] failed to re-authorise ({:?}), the token is missing scopes {}",
+				err,
+				missing.join(", ")
+			)
+		})?;
+	api.lock().await.setToken(token);
+
+	Ok(())
+}
+
+/// Writes chat into the archive as it arrives, emotes that aren't archived yet are fetched on the side
+async fn archiveChat(
+	archive: chat::Archive,
+	store: Option<Arc<assets::Store>>,
+	mut rx: tokio::sync::mpsc::UnboundedReceiver<data::ChatMessage>,
+) {
+	while let Some(data::ChatMessage {
+		msg,
+		channel,
+		emotes,
+	}) = rx.recv().await
+	{
+		archive.write(&channel, &msg);
+		if let Some(store) = &store {
+			let emotes = emotes
+				.into_iter()
+				.filter(|(id, _)| !store.has(&format!("emote/{id}")))
+				.collect::<Vec<_>>();
+			if !emotes.is_empty() {
+				let store = store.clone();
+				tokio::spawn(async move { store.emotes(&emotes).await });
+			}
+		}
+	}
+}
+
+/// Writes eventsub messages into the state db on a blocking thread, sqlite calls would stall the socket
+fn archiveEvents(
+	db: Arc<db::Db>,
+	mut rx: tokio::sync::mpsc::UnboundedReceiver<db::Event>,
+) -> tokio::task::JoinHandle<()> {
+	tokio::task::spawn_blocking(move || {
+		while let Some(event) = rx.blocking_recv() {
+			db.event(&event);
+		}
+	})
+}
+
+/// Archives badges and third party emotes of the channels in the background
+fn collectAssets(store: Arc<assets::Store>, api: Arc<Mutex<Api>>, users: Vec<data::UserData>) {
+	tokio::spawn(async move {
+		store.badges(&api, &users).await;
+		store.thirdParty(&users).await;
+		info!("[ASST] assets of {} channels archived", users.len());
+	});
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
 enum ThreadType {
+	Backfill,
+	Chat,
+	Control,
+	Events,
+	Irc,
 	MainSocket,
+	Storage,
+	StreamPoll,
 	Validation,
 	Download(String),
 }
 
+#[allow(dead_code)]
 struct Thread {
 	active: bool,
 	label: ThreadType,
@@ -132,19 +286,20 @@ struct Thread {
 
 pub fn id() -> u32 {
 	static COUNTER: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(0);
-	return COUNTER.fetch_add(1, Ordering::Relaxed);
+	COUNTER.fetch_add(1, Ordering::Relaxed)
 }
 
 impl Thread {
 	fn new(label: ThreadType, handle: tokio::task::JoinHandle<()>) -> Arc<Self> {
 		Arc::new(Thread {
 			active: true,
-			label: label,
-			handle: handle,
+			label,
+			handle,
 			id: id(),
 		})
 	}
 }
+#[allow(dead_code)]
 struct ThreadSwap {
 	from: Arc<Thread>,
 	to: Arc<Thread>,
@@ -152,32 +307,143 @@ struct ThreadSwap {
 
 #[tokio::main]
 async fn main() {
-	let threadPool: Arc<Mutex<Vec<Arc<Thread>>>> = Arc::new(Mutex::new(Vec::new()));
+	let cli = <cli::Cli as clap::Parser>::parse();
+
+	let configPath = &cli.config;
+	let mut config: Config = match config::load(configPath) {
+		Ok(config) => config,
+		Err(errors) => {
+			eprintln!("invalid config {}:", configPath.display());
+			errors.iter().for_each(|err| eprintln!(" - {err}"));
+			std::process::exit(1);
+		}
+	};
+	cli.apply(&mut config);
+
+	let command = cli.command();
+	if let cli::Command::CheckConfig = command {
+		println!("config {} is valid", configPath.display());
+		return;
+	}
+	logging::setup(&config).expect("Failed to setup logging chain");
+
+	let result = match command {
+		cli::Command::Run => {
+			run(config).await;
+			Ok(())
+		}
+		cli::Command::Auth => cli::auth(&config).await,
+		cli::Command::ListSubscriptions => cli::listSubscriptions(&config).await,
+		cli::Command::Record { login } => cli::record(&config, &login).await,
+		cli::Command::Status => cli::status(&config).await,
+		cli::Command::History { login, limit } => cli::history(&config, login.as_deref(), limit).await,
+		cli::Command::ChatReplay {
+			login,
+			video_id,
+			output,
+			fixtures,
+			record_fixtures,
+		} => {
+			cli::chatReplay(
+				&config,
+				&login,
+				&video_id,
+				output,
+				fixtures,
+				record_fixtures,
+			)
+			.await
+		}
+		cli::Command::Backfill { login, dry_run } => {
+			cli::backfill(&config, login.as_deref(), dry_run).await
+		}
+		cli::Command::Subtitles {
+			video,
+			chat,
+			start,
+			end,
+		} => cli::subtitles(&config, &video, chat, start, end).await,
+		cli::Command::CheckConfig => unreachable!(),
+	};
+	if let Err(err) = result {
+		error!("{:?} failed: {:?}", cli.command(), err);
+		std::process::exit(1);
+	}
+}
 
-	let configPath = std::env::current_dir().unwrap().join("config.json");
-	let config: Config = serde_json::from_slice(&std::fs::read(configPath).unwrap()).unwrap();
-	setup_logger(&config).expect("Failed to setup logging chain");
+async fn run(config: Config) {
+	let threadPool: Arc<Mutex<Vec<Arc<Thread>>>> = Arc::new(Mutex::new(Vec::new()));
 
 	let (tx, mut rx) = tokio::sync::broadcast::channel::<InternalMessage>(32);
 	let wsTx = tx.clone();
 	let apiTx = tx.clone();
-	let token = token::fetchToken(&config).await.unwrap();
+	let tokenStore = token::store(&config);
+	let token = match token::fetchToken(tokenStore.as_ref(), &config).await {
+		Ok(token) => token,
+		Err(err) => {
+			error!("[AUTH] failed to obtain a token: {:?}", err);
+			std::process::exit(1);
+		}
+	};
 
-	let rootPath = PathBuf::from(&config.root);
+	let storage = Arc::new(storage::Storage::new(&config));
+	let hooks = Arc::new(hooks::Hooks::new(&config));
+	let notifier = Arc::new(notify::Notifier::new(&config));
+	let runtime = Arc::new(control::Runtime::new(&config));
+	let chatArchive = chat::Archive::new(&config);
+	let (chatTx, chatRx) = tokio::sync::mpsc::unbounded_channel::<data::ChatMessage>();
+	let (eventsTx, eventsRx) = tokio::sync::mpsc::unbounded_channel::<db::Event>();
+	let assetStore = assets::Store::new(&config).map(Arc::new);
+	let db = Arc::new(db::Db::open(&config).expect("failed to open state db"));
+	// the latest unfinished recording per channel, continued if the same broadcast is still live
+	let mut interrupted = std::collections::HashMap::<String, db::RecordingRow>::new();
+	for row in db.takeInterrupted() {
+		warn!(
+			"[STDB] recording {} of {} started at {} was interrupted",
+			row.id, row.channel, row.startedAt
+		);
+		if interrupted
+			.get(&row.channel)
+			.is_none_or(|kept| kept.startedAt < row.startedAt)
+		{
+			interrupted.insert(row.channel.clone(), row);
+		}
+	}
+	let uploader = config
+		.upload
+		.as_ref()
+		.map(|upload| Arc::new(upload::Uploader::new(upload, &PathBuf::from(&config.root))));
 
 	let api: std::sync::Arc<tokio::sync::Mutex<api::Api>> = std::sync::Arc::new(
 		tokio::sync::Mutex::new(api::Api::init(token.clone(), &config)),
 	);
 
-	let mut socketUrl = "wss://eventsub.wss.twitch.tv/ws?keepalive_timeout_seconds=300".to_string();
-	if let Some(s) = &config.socketUrl {
-		socketUrl = s.clone();
-	}
+	let socketUrl = config
+		.socketUrl
+		.clone()
+		.unwrap_or(config::DEFAULT_SOCKET_URL.to_string());
 
-	let socket = Arc::new(socket::Client { tx: wsTx });
+	let socket = Arc::new(socket::Client {
+		tx: wsTx,
+		chat: chatTx.clone(),
+		events: eventsTx,
+	});
 	let mut mainLock = threadPool.lock().await;
 
+	mainLock.push(Thread::new(
+		ThreadType::Events,
+		archiveEvents(db.clone(), eventsRx),
+	));
+
+	mainLock.push(Thread::new(
+		ThreadType::Chat,
+		tokio::spawn(archiveChat(chatArchive, assetStore.clone(), chatRx)),
+	));
+
 	let validateApi = api.clone();
+	let validateStore = tokenStore.clone();
+	let validateTx = tx.clone();
+	let validateDb = db.clone();
 	mainLock.push(Thread::new(
 		ThreadType::Validation,
 		tokio::spawn(async move {
@@ -189,44 +455,131 @@ async fn main() {
 				}
 				tokio::time::sleep(duration).await;
 
-				prevResult = validateAndRefreshToken(&validateApi).await
+				prevResult = validateAndRefreshToken(
+					&validateApi,
+					validateStore.as_ref(),
+					&validateTx,
+					&validateDb,
+				)
+				.await
 			}
 		}),
 	));
 
-	validateAndRefreshToken(&api.clone()).await;
+	let storageMonitor = storage.clone();
+	let storageTx = tx.clone();
+	let storageRuntime = runtime.clone();
+	mainLock.push(Thread::new(
+		ThreadType::Storage,
+		tokio::spawn(async move {
+			let retention = storageMonitor.clone();
+			let channels = storageRuntime.broadcasters();
+			tokio::task::spawn_blocking(move || retention.enforceRetentionAll(&channels))
+				.await
+				.ok();
 
-	let users = api
+			let mut prevLevel = storage::SpaceLevel::Ok;
+			loop {
+				match storageMonitor.freeBytes() {
+					Ok(free) => {
+						let level = storageMonitor.level(free);
+						// only report transitions to not spam on every check
+						if level != prevLevel && level != storage::SpaceLevel::Ok {
+							storageTx
+								.send(InternalMessage::StorageLow {
+									free,
+									critical: level == storage::SpaceLevel::Critical,
+								})
+								.ok();
+						}
+						prevLevel = level;
+					}
+					Err(err) => warn!("[DISK] failed to check free space: {err}"),
+				}
+				tokio::time::sleep(storageMonitor.checkInterval()).await;
+			}
+		}),
+	));
+
+	if ensureScopes(&api, tokenStore.as_ref(), &config, &tx, &db)
+		.await
+		.is_err()
+	{
+		std::process::exit(1);
+	}
+
+	let chatChannels = config.chatChannels.clone().unwrap_or_default();
+	let ircSource = config.chatSource == data::ChatSource::Irc;
+	let planned = planSubscriptions(
+		&runtime,
+		&chatChannels,
+		config.authMode == AuthMode::User || ircSource,
+	);
+
+	// idles until a channel is joined, with `chatSource: irc` that's every chat right away
+	// and otherwise the ones eventsub refuses chat of
+	let ircClient = Arc::new(irc::Client::new(&config, chatTx));
+	let ircRunner = ircClient.clone();
+	mainLock.push(Thread::new(
+		ThreadType::Irc,
+		tokio::spawn(async move { ircRunner.run().await }),
+	));
+	if ircSource {
+		planned
+			.iter()
+			.filter(|(_, topics)| topics.chat)
+			.for_each(|(login, _)| ircClient.join(login));
+	}
+	let mut users = api
 		.lock()
 		.await
 		.getUsers(
-			&config
-				.broadcasters
+			&planned
 				.iter()
-				.map(|br| br.as_str())
+				.map(|(login, _)| login.as_str())
 				.collect::<Vec<&str>>(),
 		)
 		.await
 		.expect("failed to get user details");
 
-	let account = api
-		.lock()
-		.await
-		.getUser(&config.account)
-		.await
-		.expect("failed to get account details");
-	let streams = api
-		.lock()
-		.await
-		.getStream(
-			&config
-				.broadcasters
-				.iter()
-				.map(String::as_str)
-				.collect::<Vec<_>>(),
-		)
-		.await
-		.unwrap();
+	let account = match config.authMode {
+		AuthMode::User => Some(
+			api
+				.lock()
+				.await
+				.getUser(
+					config
+						.account
+						.as_deref()
+						.expect("account is required in user auth mode"),
+				)
+				.await
+				.expect("failed to get account details"),
+		),
+		AuthMode::App => {
+			info!("[APPM] app token mode, chat logging disabled");
+			None
+		}
+	};
+	// helix lists top streams when asked for none
+	let videoBroadcasters = runtime.videoBroadcasters();
+	let streams = match videoBroadcasters.is_empty() {
+		true => Vec::new(),
+		false => api
+			.lock()
+			.await
+			.getStream(
+				&videoBroadcasters
+					.iter()
+					.map(String::as_str)
+					.collect::<Vec<_>>(),
+			)
+			.await
+			.unwrap_or_else(|err| {
+				error!("[STRT] failed to check which channels are live: {:?}", err);
+				Vec::new()
+			}),
+	};
 	streams
 		.iter()
 		.filter(|s| s.stream_type == "live")
@@ -238,62 +591,173 @@ async fn main() {
 				.expect("failed to send stream status update");
 		});
 
+	if let Some(store) = &assetStore {
+		collectAssets(store.clone(), api.clone(), users.clone());
+	}
+
 	let switch: Arc<Mutex<Option<ThreadSwap>>> = Arc::new(Mutex::new(None));
 
-	let tsSocket = socket.clone();
-	mainLock.push(Thread::new(
-		ThreadType::MainSocket,
-		tokio::spawn(async move {
-			use tungstenite::Message;
-			let (tungstenSocketStream, _connectionResponse) = tokio_tungstenite::connect_async(socketUrl)
-				.await
-				.expect("WebSocket connection failed to initialise");
-			tungstenSocketStream
-				.for_each(|msg| async {
-					// let messageType = determineType(&msg);
-					let t: Result<Option<tungstenite::Utf8Bytes>, tungstenite::Error> =
-						msg.map(|msg| match msg {
-							Message::Text(val) => Some(val),
-							Message::Frame(val) => {
-								info!("unexpected frame on socket: {:?}", val);
-								None
-							}
-							Message::Binary(bin) => {
-								trace!("received binary on websocket: {:?}", bin);
-								None
-							}
-							Message::Ping(val) => {
-								trace!("ping: {:?}", val);
-								None
-							}
-							Message::Pong(val) => {
-								trace!("pong: {:?}", val);
-								None
-							}
-							Message::Close(val) => {
-								warn!("got close frame: {:?}", val);
-								None
-							}
-						});
+	if config.authMode == AuthMode::User {
+		let tsSocket = socket.clone();
+		mainLock.push(Thread::new(
+			ThreadType::MainSocket,
+			tokio::spawn(async move {
+				use tungstenite::Message;
+				let (tungstenSocketStream, _connectionResponse) =
+					tokio_tungstenite::connect_async(socketUrl)
+						.await
+						.expect("WebSocket connection failed to initialise");
+				tungstenSocketStream
+					.for_each(|msg| async {
+						// let messageType = determineType(&msg);
+						let t: Result<Option<tungstenite::Utf8Bytes>, tungstenite::Error> =
+							msg.map(|msg| match msg {
+								Message::Text(val) => Some(val),
+								Message::Frame(val) => {
+									info!("unexpected frame on socket: {:?}", val);
+									None
+								}
+								Message::Binary(bin) => {
+									trace!("received binary on websocket: {:?}", bin);
+									None
+								}
+								Message::Ping(val) => {
+									trace!("ping: {:?}", val);
+									None
+								}
+								Message::Pong(val) => {
+									trace!("pong: {:?}", val);
+									None
+								}
+								Message::Close(val) => {
+									warn!("got close frame: {:?}", val);
+									None
+								}
+							});
 
-					match t {
-						Ok(val) => match val {
-							Some(text) => {
-								log::trace!("[MSGA] {:?}", text);
-								tsSocket.processFrame(text).unwrap();
-							}
-							None => {
-								// noop
+						match t {
+							Ok(val) => match val {
+								Some(text) => {
+									log::trace!("[MSGA] {:?}", text);
+									tsSocket.processFrame(text).unwrap();
+								}
+								None => {
+									// noop
+								}
+							},
+							Err(detail) => {
+								error!("{}", detail);
 							}
-						},
-						Err(detail) => {
-							error!("{}", detail);
 						}
+					})
+					.await
+			}),
+		));
+	} else {
+		let pollApi = api.clone();
+		let pollTx = apiTx.clone();
+		let pollRuntime = runtime.clone();
+		let interval =
+			Duration::from_secs(config.pollInterval.unwrap_or(config::DEFAULT_POLL_INTERVAL));
+		let mut live = streams
+			.iter()
+			.filter(|s| s.stream_type == "live")
+			.map(|s| s.user_login.clone())
+			.collect::<std::collections::HashSet<_>>();
+
+		mainLock.push(Thread::new(
+			ThreadType::StreamPoll,
+			tokio::spawn(async move {
+				loop {
+					tokio::time::sleep(interval).await;
+
+					let broadcasters = pollRuntime.videoBroadcasters();
+					if broadcasters.is_empty() {
+						continue;
 					}
-				})
-				.await
-		}),
-	));
+					let current = match pollApi
+						.lock()
+						.await
+						.getStream(&broadcasters.iter().map(String::as_str).collect::<Vec<_>>())
+						.await
+					{
+						Ok(streams) => streams
+							.into_iter()
+							.filter(|s| s.stream_type == "live")
+							.map(|s| s.user_login)
+							.collect::<std::collections::HashSet<_>>(),
+						Err(err) => {
+							error!("[POLL] failed to poll streams: {:?}", err);
+							continue;
+						}
+					};
+
+					for channel in current.difference(&live) {
+						pollTx
+							.send(InternalMessage::StreamLive {
+								channel: channel.clone(),
+							})
+							.expect("failed to send stream status update");
+					}
+					for channel in live.difference(&current) {
+						pollTx
+							.send(InternalMessage::StreamStop {
+								channel: channel.clone(),
+							})
+							.expect("failed to send stream status update");
+					}
+					live = current;
+				}
+			}),
+		));
+	}
+	if let Some(hours) = config
+		.backfill
+		.as_ref()
+		.and_then(|backfill| backfill.intervalHours)
+	{
+		let backfillConfig = config.clone();
+		let backfillApi = api.clone();
+		let backfillDb = db.clone();
+		let backfillStorage = storage.clone();
+		let backfillRuntime = runtime.clone();
+		mainLock.push(Thread::new(
+			ThreadType::Backfill,
+			tokio::spawn(async move {
+				loop {
+					match backfill::run(
+						&backfillConfig,
+						&backfillApi,
+						&backfillDb,
+						&backfillStorage,
+						&backfillRuntime.videoBroadcasters(),
+						false,
+					)
+					.await
+					{
+						Ok(summary) => info!(
+							"[BKFL] downloaded {}, skipped {}, failed {}",
+							summary.downloaded, summary.skipped, summary.failed
+						),
+						Err(err) => error!("[BKFL] backfill failed: {:?}", err),
+					}
+					tokio::time::sleep(Duration::from_secs(hours * 3600)).await;
+				}
+			}),
+		));
+	}
+	if let Some(control) = config.control.clone() {
+		mainLock.push(Thread::new(
+			ThreadType::Control,
+			tokio::spawn(control::serve(
+				control,
+				runtime.clone(),
+				api.clone(),
+				threadPool.clone(),
+				tx.clone(),
+			)),
+		));
+	}
 	drop(mainLock);
 
 	debug!(
@@ -316,10 +780,26 @@ async fn main() {
 	// handle streamlink output and/or investigate custom downloader since priority is shifted toward reliably getting everything of a vod, and streamlink drops segments on flaky connection
 
 	loop {
-		use InternalMessage::{Chat, Debug, DontHandle, Init, Reconnect, StreamLive, StreamStop};
+		use InternalMessage::{
+			AddBroadcaster, AuthFailed, Debug, DontHandle, Init, Reconnect, RemoveBroadcaster,
+			StopRecording, StorageLow, StreamLive, StreamStop, WatchBroadcaster,
+		};
 
-		match rx.recv().await.unwrap() {
+		let message = match rx.recv().await {
+			Ok(message) => message,
+			Err(tokio::sync::broadcast::error::RecvError::Lagged(skipped)) => {
+				warn!("[MAIN] fell behind, {skipped} messages were dropped");
+				continue;
+			}
+			Err(tokio::sync::broadcast::error::RecvError::Closed) => {
+				error!("[MAIN] every sender is gone, stopping");
+				return;
+			}
+		};
+		match message {
 			Init { session } => {
+				runtime.session.lock().unwrap().replace(session.clone());
+				db.set("session", &session);
 				let val = switch.lock().await.take();
 				if let Some(swap) = val {
 					info!("[RNIT] session: {session}");
@@ -341,83 +821,335 @@ async fn main() {
 				} else {
 					info!("[INIT] session: {session}");
 
-					let futures = users
-						.iter()
-						.map(|user| async {
-							let apilock = api.lock().await;
-							apilock
-								.subscribe(
-									&session,
-									EventType::StreamOnline,
-									serde_json::json!({ "broadcaster_user_id": user.id}),
-								)
-								.await
-								.expect(&format!("failed to sub to {}", &user.login));
-							tokio::time::sleep(Duration::from_millis(400)).await;
-
-							apilock
-								.subscribe(
-									&session,
-									EventType::StreamOffline,
-									serde_json::json!({ "broadcaster_user_id": user.id}),
-								)
-								.await
-								.expect(&format!("failed to sub to {}", &user.login));
-							tokio::time::sleep(Duration::from_millis(400)).await;
-
-							apilock
-								.subscribe(
-									&session,
-									EventType::ChannelChatMessage,
-									serde_json::json!({ "broadcaster_user_id": user.id, "user_id": account.id }),
-								)
-								.await
-								.expect(&format!("failed to sub to {}", &user.login));
-							tokio::time::sleep(Duration::from_millis(400)).await;
+					// subscribing paces itself, the loop keeps handling messages meanwhile
+					let channels = planSubscriptions(&runtime, &chatChannels, account.is_some())
+						.into_iter()
+						.filter_map(|(login, topics)| {
+							let user = users.iter().find(|user| user.login == login)?.clone();
+							let topics = Topics {
+								live: topics.live,
+								chat: topics.chat && !ircSource,
+							};
+							Some((user, topics))
 						})
 						.collect::<Vec<_>>();
-					futures::future::join_all(futures).await;
+					let (api, account, ircClient) = (api.clone(), account.clone(), ircClient.clone());
+					tokio::spawn(async move {
+						let futures = channels.iter().map(|(user, topics)| {
+							let (api, session, account, ircClient) = (&api, &session, &account, &ircClient);
+							async move {
+								let result = subscribeChannel(api, session, user, *topics, account.as_ref()).await;
+								if let Err(err) = result {
+									warn!(
+										"[IRCC] {}: chat subscription refused ({:?}), falling back to irc",
+										user.login, err
+									);
+									ircClient.join(&user.login);
+								}
+							}
+						});
+						futures::future::join_all(futures).await;
+					});
 				}
 			}
 
 			StreamLive { channel } => {
 				info!("[STRT] channel: {channel}");
+				if !runtime.recordsVideo(&channel) {
+					info!("[STRT] {channel} is chat only, not recording");
+					continue;
+				}
+				if runtime.isRecording(&channel) {
+					warn!("[STRT] {channel} is already recording, skipping");
+					continue;
+				}
 
-				let mut path = rootPath
-					.join(&channel)
-					.join(chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true));
-				path.set_extension(&"mp4");
-				let token = config.streamlinkToken.clone();
+				// stream metadata can lag behind the online event, fall back to bare channel info
+				let mut info = match api.lock().await.getStream(&[channel.as_str()]).await {
+					Ok(streams) if !streams.is_empty() => RecordingInfo::fromStream(&streams[0]),
+					Ok(_) => RecordingInfo::bare(&channel),
+					Err(err) => {
+						warn!(
+							"[STRT] {channel}: failed to fetch stream info, recording without it: {:?}",
+							err
+						);
+						RecordingInfo::bare(&channel)
+					}
+				};
+				let resumed = interrupted.remove(&channel).filter(|row| {
+					!row.streamId.is_empty() && row.streamId == info.streamId && !row.paths.is_empty()
+				});
+				if let Some(row) = &resumed {
+					info!(
+						"[RSME] {channel}: continuing recording {} started at {}",
+						row.id, row.startedAt
+					);
+					// same start keeps templated part names and durations continuous
+					info.startedAt = row.startedAt;
+				}
+				notifier.fire(
+					data::Notification::new(
+						data::NotifyEvent::StreamLive,
+						Some(&channel),
+						format!("{} is live: {}", info.displayName, info.title),
+					)
+					.details(serde_json::json!({
+						"streamId": info.streamId,
+						"title": info.title,
+						"category": info.category,
+					})),
+				);
+				if let Err(free) = storage.canRecord() {
+					error!(
+						"[DISK] refusing to record {channel}, only {} MiB free",
+						free / 1024 / 1024
+					);
+					continue;
+				}
+
+				let recordConfig = config.clone();
+				let recordStorage = storage.clone();
+				let recordUploader = uploader.clone();
+				let recordHooks = hooks.clone();
+				let recordNotifier = notifier.clone();
+				let recordRuntime = runtime.clone();
+				let recordDb = db.clone();
+				let recordApi = api.clone();
+				let userId = users
+					.iter()
+					.find(|user| user.login == channel)
+					.map(|user| user.id.clone());
+				db.broadcastSeen(&info);
+				let recordId = match &resumed {
+					Some(row) => {
+						db.recordingResumed(row.id);
+						Some(row.id)
+					}
+					None => db.recordingStarted(&info),
+				};
+				let previous = resumed.map(|row| row.paths).unwrap_or_default();
+				let recording = Arc::new(control::ActiveRecording {
+					channel: channel.clone(),
+					startedAt: info.startedAt,
+					paths: std::sync::Mutex::new(previous.clone()),
+					handle: record::Handle::default(),
+					catchUp: record::Handle::default(),
+				});
+				runtime
+					.recordings
+					.lock()
+					.unwrap()
+					.insert(channel.clone(), recording.clone());
 
 				threadPool.lock().await.push(Thread::new(
 					ThreadType::Download(channel.clone()),
 					tokio::spawn(async move {
-						let path = path.to_str().unwrap();
-						// switch ytdlp --add-headers "Authorization:OAuth {token}" "twitch.tv/negnasu"
-						std::process::Command::new("streamlink")
-							.args(&[
-								"--http-header",
-								&format!("Authorization=OAuth {}", token),
-								"--hls-live-restart",
-								"--hls-playlist-reload-time",
-								"3",
-								"--twitch-supported-codecs",
-								"h264,h265,av1",
-								"--retry-streams",
-								"5",
-								&format!("twitch.tv/{}", channel),
-								"best",
-								"-o",
-								path,
-							])
-							.stdout(Stdio::inherit())
-							.stderr(Stdio::inherit())
-							.spawn()
-							.expect("there was an error processing streamlink");
-
-						info!("[DLDN] ");
-
-						()
+						// an earlier catch-up is redone from scratch, only live parts are continued
+						let previousParts = previous
+							.iter()
+							.filter(|path| !catchup::isCatchUp(path))
+							.cloned()
+							.collect::<Vec<_>>();
+						let firstPath = previousParts
+							.first()
+							.cloned()
+							.unwrap_or_else(|| record::firstPath(&recordConfig, &info));
+						let startHooks = recordHooks.clone();
+						let start = hooks::payload(
+							data::HookEvent::Start,
+							&info,
+							std::slice::from_ref(&firstPath),
+							None,
+						);
+						tokio::spawn(async move { startHooks.run(&start).await });
+
+						previous.iter().for_each(|path| recordStorage.begin(path));
+						let catchUpPath = catchup::path(&firstPath);
+						let catchUp = match (recordConfig.catchUp.unwrap_or(false), userId) {
+							(true, Some(userId)) => {
+								recordStorage.begin(&catchUpPath);
+								let config = recordConfig.clone();
+								let info = info.clone();
+								let path = catchUpPath.clone();
+								let previous = previousParts.clone();
+								let recording = recording.clone();
+								Some(tokio::spawn(async move {
+									catchup::run(
+										&config, &recordApi, &info, &userId, &path, &previous, &recording,
+									)
+									.await
+								}))
+							}
+							_ => None,
+						};
+
+						let mut parts = previous.clone();
+						let result = record::record(
+							&recordConfig,
+							&info,
+							&previousParts,
+							&recording.handle,
+							|path| {
+								debug!("[STRT] recording into {}", path.display());
+								recordStorage.begin(path);
+								parts.push(path.to_path_buf());
+								recording.paths.lock().unwrap().push(path.to_path_buf());
+								if let Some(id) = recordId {
+									recordDb.recordingPaths(id, &parts);
+								}
+							},
+						)
+						.await;
+
+						match &result {
+							Ok(status) => info!("[DLDN] {:?}", status),
+							Err(err) => error!("[DLDN] there was an error processing streamlink: {err}"),
+						}
+						let mut info = info;
+						info.mediaStartedAt = recording.handle.mediaStartedAt();
+						// an unsplit recording continued after a restart goes back into one file,
+						// the gaps between its parts are only known until the manifest is gone
+						let live = parts
+							.iter()
+							.filter(|path| !catchup::isCatchUp(path))
+							.cloned()
+							.collect::<Vec<_>>();
+						let mut joinedGaps = None;
+						if recordConfig.split.is_none() && live.len() > 1 {
+							let gaps = hooks::gaps(&info, &live);
+							match record::joinParts(&live).await {
+								Ok(listed) => {
+									// the joined file begins with the frames of its first part
+									if let Some(first) = listed.first() {
+										info.mediaStartedAt = Some(first.startedAt);
+									}
+									info!(
+										"[RSME] {channel}: joined {} parts into {}",
+										live.len(),
+										live[0].display()
+									);
+									live[1..].iter().for_each(|path| recordStorage.finish(path));
+									parts.retain(|path| !live[1..].contains(path));
+									if let Some(id) = recordId {
+										recordDb.recordingPaths(id, &parts);
+									}
+									joinedGaps = Some(gaps);
+								}
+								Err(err) => {
+									error!("[RSME] {channel}: failed to join the parts of the recording: {err}")
+								}
+							}
+						}
+						// the vod download can still be on its way to where live recording started
+						let caughtUp = match catchUp {
+							Some(task) => task.await.ok().flatten(),
+							None => None,
+						};
+						if caughtUp.is_some() && catchUpPath.exists() && !parts.contains(&catchUpPath) {
+							parts.push(catchUpPath.clone());
+							if let Some(id) = recordId {
+								recordDb.recordingPaths(id, &parts);
+							}
+						}
+						if let Some(settings) = &recordConfig.subtitles {
+							let before = parts.clone();
+							// players skip the downtime inside a joined file, the chat has to as well
+							let holes = joinedGaps
+								.iter()
+								.flatten()
+								.filter(|gap| info.mediaStartedAt.is_some_and(|start| gap.from >= start))
+								.cloned()
+								.collect::<Vec<_>>();
+							subtitles::forRecording(
+								settings,
+								&chat::logPath(&recordConfig, &channel),
+								&info,
+								&holes,
+								&mut parts,
+							)
+							.await;
+							before.iter().for_each(|path| recordStorage.finish(path));
+							if let Some(id) = recordId.filter(|_| before != parts) {
+								recordDb.recordingPaths(id, &parts);
+							}
+						}
+						recordStorage.finish(&catchUpPath);
+						parts.iter().for_each(|path| recordStorage.finish(path));
+						// a manual stop kills streamlink, that's not a failure
+						let event = match hooks::failed(&result) && !recording.handle.stopped() {
+							true => data::HookEvent::Fail,
+							false => data::HookEvent::Finish,
+						};
+						let mut payload = hooks::payload(event, &info, &parts, Some(&result));
+						if let Some(gaps) = joinedGaps {
+							payload.gaps = gaps;
+						}
+						if caughtUp.is_some_and(|result| !hooks::failed(&result)) {
+							// the vod covers the start that aired before recording began
+							payload
+								.gaps
+								.retain(|gap| Some(gap.from) != info.streamStartedAt);
+						}
+						if let Some(id) = recordId {
+							let status = match event {
+								_ if recording.handle.stopped() => db::RecordingStatus::Stopped,
+								data::HookEvent::Fail => db::RecordingStatus::Failed,
+								_ => db::RecordingStatus::Finished,
+							};
+							recordDb.recordingEnded(
+								id,
+								status,
+								payload.exitCode,
+								payload.error.as_deref(),
+								&payload.gaps,
+							);
+						}
+						// under the registry lock so /metrics never counts the files twice or not at all
+						{
+							let mut active = recordRuntime.recordings.lock().unwrap();
+							metrics::metrics().addDownloaded(
+								&channel,
+								parts
+									.iter()
+									.filter_map(|path| std::fs::metadata(path).ok())
+									.map(|meta| meta.len())
+									.sum(),
+							);
+							active.remove(&channel);
+						}
+						metrics::metrics().addGap(
+							&channel,
+							payload.gaps.iter().map(|gap| gap.seconds as u64).sum(),
+						);
+						recordNotifier.fire(
+							data::Notification::new(
+								match event {
+									data::HookEvent::Fail => data::NotifyEvent::RecordingFailed,
+									_ => data::NotifyEvent::RecordingFinished,
+								},
+								Some(&channel),
+								match &payload.error {
+									Some(err) => format!("recording {channel} failed: {err}"),
+									None if event == data::HookEvent::Fail => format!(
+										"recording {channel} failed, streamlink exited with {:?}",
+										payload.exitCode
+									),
+									None => format!(
+										"recording {channel} finished after {} min",
+										payload.durationSeconds.unwrap_or_default() / 60
+									),
+								},
+							)
+							.details(serde_json::to_value(&payload).unwrap()),
+						);
+						recordHooks.run(&payload).await;
+						if let Some(uploader) = recordUploader {
+							let files = storage::recordingFiles(&parts);
+							uploader.uploadRecording(&files).await.ok();
+						}
+						tokio::task::spawn_blocking(move || recordStorage.enforceRetention(&channel))
+							.await
+							.ok();
 					}),
 				));
 			}
@@ -429,12 +1161,145 @@ async fn main() {
 				debug!("[DEBG] {}", info);
 			}
 
-			Chat { msg, channel } => {
-				trace!(target: &format!("ld::chat::{}", channel), "[CHAT] {:?}", msg);
+			StorageLow { free, critical } => {
+				let free = free / 1024 / 1024;
+				if critical {
+					error!("[DISK] {free} MiB free, new recordings are refused");
+				} else {
+					warn!("[DISK] {free} MiB free");
+				}
+				notifier.fire(
+					data::Notification::new(
+						data::NotifyEvent::StorageLow,
+						None,
+						match critical {
+							true => format!("{free} MiB free, new recordings are refused"),
+							false => format!("{free} MiB free"),
+						},
+					)
+					.details(serde_json::json!({ "freeMib": free, "critical": critical })),
+				);
+
+				let retention = storage.clone();
+				let channels = runtime.broadcasters();
+				tokio::task::spawn_blocking(move || retention.enforceRetentionAll(&channels));
+			}
+
+			StopRecording { channel } => match runtime.recordings.lock().unwrap().get(&channel) {
+				Some(recording) => {
+					recording.handle.stop();
+					recording.catchUp.stop();
+				}
+				None => warn!("[STOP] {channel} is not recording"),
+			},
+
+			AddBroadcaster { login } => {
+				// the lookup goes through the api lock, the loop keeps handling messages meanwhile
+				let (api, tx) = (api.clone(), tx.clone());
+				tokio::spawn(async move {
+					match api.lock().await.getUser(&login).await {
+						Ok(user) => {
+							tx.send(WatchBroadcaster { user }).ok();
+						}
+						Err(err) => error!("[BRDC] failed to add {login}: {:?}", err),
+					}
+				});
+			}
+
+			WatchBroadcaster { user } => {
+				let login = user.login.clone();
+				if runtime.broadcasters().contains(&login) {
+					continue;
+				}
+				runtime.broadcasters.write().unwrap().push(login.clone());
+				info!("[BRDC] watching {login}");
+
+				// chat of a chat channel is read already
+				let chat = !chatChannels.contains(&login);
+				if chat && ircSource {
+					ircClient.join(&login);
+				}
+				let topics = Topics {
+					live: runtime.recordsVideo(&login),
+					chat: chat && !ircSource,
+				};
+				if let Some(store) = &assetStore {
+					collectAssets(store.clone(), api.clone(), vec![user.clone()]);
+				}
+				if !users.iter().any(|known| known.login == login) {
+					users.push(user.clone());
+				}
+
+				let session = runtime.session.lock().unwrap().clone();
+				let (api, account, ircClient, tx) =
+					(api.clone(), account.clone(), ircClient.clone(), tx.clone());
+				tokio::spawn(async move {
+					if let Some(session) = session {
+						let result = subscribeChannel(&api, &session, &user, topics, account.as_ref()).await;
+						if let Err(err) = result {
+							warn!(
+								"[IRCC] {login}: chat subscription refused ({:?}), falling back to irc",
+								err
+							);
+							ircClient.join(&login);
+						}
+					}
+
+					let live = api.lock().await.getStream(&[login.as_str()]).await;
+					if live.is_ok_and(|streams| streams.iter().any(|s| s.stream_type == "live")) {
+						tx.send(StreamLive { channel: login }).ok();
+					}
+				});
+			}
+
+			RemoveBroadcaster { login } => {
+				runtime
+					.broadcasters
+					.write()
+					.unwrap()
+					.retain(|broadcaster| *broadcaster != login);
+				info!("[BRDC] no longer watching {login}");
+
+				let Some(pos) = users.iter().position(|user| user.login == login) else {
+					continue;
+				};
+				// a chat channel keeps its chat
+				let keepChat = chatChannels.contains(&login);
+				if !keepChat {
+					ircClient.part(&login);
+				}
+				let user = match keepChat {
+					true => users[pos].clone(),
+					false => users.remove(pos),
+				};
+				let api = api.clone();
+				tokio::spawn(async move {
+					let apilock = api.lock().await;
+					match apilock.getSubscribtions().await {
+						Ok(subscriptions) => {
+							for subscription in subscriptions.data.iter().filter(|subscription| {
+								subscription.condition["broadcaster_user_id"].as_str() == Some(user.id.as_str())
+									&& !(keepChat && subscription.sub_type == "channel.chat.message")
+							}) {
+								apilock.deleteSubscription(&subscription.id).await.ok();
+							}
+						}
+						Err(err) => error!("[BRDC] failed to list subscriptions: {:?}", err),
+					}
+				});
+			}
+
+			AuthFailed { reason } => {
+				notifier.fire(data::Notification::new(
+					data::NotifyEvent::AuthFailed,
+					None,
+					format!("token refresh failed ({reason}), run `ld auth` to log in again"),
+				));
 			}
 
 			Reconnect { session, url } => {
 				info!("[RCNT] session: {session}; url: {url}");
+				metrics::metrics().addReconnect();
 
 				let tsSocket = socket.clone();
 
@@ -456,7 +1321,6 @@ async fn main() {
 
 									Err(detail) => {
 										error!("{}", detail);
-										()
 									}
 								}
 							})
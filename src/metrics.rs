@@ -0,0 +1,286 @@
+use std::{
+	collections::{BTreeMap, HashMap},
+	fmt::Write,
+	sync::{
+		LazyLock, Mutex,
+		atomic::{AtomicI64, AtomicU64, Ordering},
+	},
+	time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+
+use crate::control::Runtime;
+
+/// Upper bounds of helix latency buckets, in seconds
+const LATENCY_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Default)]
+struct Histogram {
+	buckets: Vec<u64>,
+	count: u64,
+	sum: f64,
+}
+
+impl Histogram {
+	fn observe(&mut self, value: f64) {
+		if self.buckets.is_empty() {
+			self.buckets = vec![0; LATENCY_BUCKETS.len()];
+		}
+		for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+			if value <= *bound {
+				*bucket += 1;
+			}
+		}
+		self.count += 1;
+		self.sum += value;
+	}
+}
+
+/// Process wide counters, rendered in prometheus text format on `/metrics`
+#[derive(Default)]
+pub struct Metrics {
+	/// bytes of finished recordings, in-progress ones are added at render time
+	downloadedBytes: Mutex<HashMap<String, u64>>,
+	segmentsFetched: Mutex<HashMap<String, u64>>,
+	segmentsFailed: Mutex<HashMap<String, u64>>,
+	gapSeconds: Mutex<HashMap<String, u64>>,
+	reconnects: AtomicU64,
+	lastEventsubMessage: Mutex<Option<Instant>>,
+	/// keyed by endpoint and status
+	helix: Mutex<BTreeMap<(String, u16), Histogram>>,
+	/// -1 until the first helix response
+	rateLimitRemaining: AtomicI64,
+	tokenExpiresAt: Mutex<Option<DateTime<Utc>>>,
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(|| Metrics {
+	rateLimitRemaining: AtomicI64::new(-1),
+	..Default::default()
+});
+
+pub fn metrics() -> &'static Metrics {
+	&METRICS
+}
+
+fn add(map: &Mutex<HashMap<String, u64>>, channel: &str, value: u64) {
+	*map.lock().unwrap().entry(channel.to_string()).or_default() += value;
+}
+
+/// Escapes a label value as the text format wants it
+fn label(value: &str) -> String {
+	value
+		.replace('\\', "\\\\")
+		.replace('"', "\\\"")
+		.replace('\n', "\\n")
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+	writeln!(out, "# HELP {name} {help}").unwrap();
+	writeln!(out, "# TYPE {name} {kind}").unwrap();
+}
+
+fn perChannel(out: &mut String, name: &str, help: &str, values: &HashMap<String, u64>) {
+	header(out, name, "counter", help);
+	let mut values = values.iter().collect::<Vec<_>>();
+	values.sort();
+	for (channel, value) in values {
+		writeln!(out, "{name}{{channel=\"{}\"}} {value}", label(channel)).unwrap();
+	}
+}
+
+impl Metrics {
+	pub fn addDownloaded(&self, channel: &str, bytes: u64) {
+		add(&self.downloadedBytes, channel, bytes);
+	}
+
+	pub fn addSegmentFetched(&self, channel: &str) {
+		add(&self.segmentsFetched, channel, 1);
+	}
+
+	pub fn addSegmentFailed(&self, channel: &str) {
+		add(&self.segmentsFailed, channel, 1);
+	}
+
+	pub fn addGap(&self, channel: &str, seconds: u64) {
+		add(&self.gapSeconds, channel, seconds);
+	}
+
+	pub fn addReconnect(&self) {
+		self.reconnects.fetch_add(1, Ordering::Relaxed);
+	}
+
+	/// Any eventsub message counts as a keepalive
+	pub fn eventsubMessage(&self) {
+		self
+			.lastEventsubMessage
+			.lock()
+			.unwrap()
+			.replace(Instant::now());
+	}
+
+	pub fn helixRequest(&self, endpoint: &str, status: u16, elapsed: Duration) {
+		self
+			.helix
+			.lock()
+			.unwrap()
+			.entry((endpoint.to_string(), status))
+			.or_default()
+			.observe(elapsed.as_secs_f64());
+	}
+
+	pub fn rateLimitRemaining(&self, remaining: i64) {
+		self.rateLimitRemaining.store(remaining, Ordering::Relaxed);
+	}
+
+	pub fn tokenExpiresIn(&self, seconds: u64) {
+		self
+			.tokenExpiresAt
+			.lock()
+			.unwrap()
+			.replace(Utc::now() + chrono::Duration::seconds(seconds as i64));
+	}
+
+	pub fn render(&self, runtime: &Runtime) -> String {
+		let mut out = String::new();
+
+		let recordings = runtime.recordings.lock().unwrap();
+		header(
+			&mut out,
+			"ld_active_recordings",
+			"gauge",
+			"Recordings in progress",
+		);
+		writeln!(out, "ld_active_recordings {}", recordings.len()).unwrap();
+
+		let mut downloaded = self.downloadedBytes.lock().unwrap().clone();
+		for recording in recordings.values() {
+			let bytes = recording
+				.paths
+				.lock()
+				.unwrap()
+				.iter()
+				.filter_map(|path| std::fs::metadata(path).ok())
+				.map(|meta| meta.len())
+				.sum::<u64>();
+			*downloaded.entry(recording.channel.clone()).or_default() += bytes;
+		}
+		drop(recordings);
+
+		perChannel(
+			&mut out,
+			"ld_downloaded_bytes_total",
+			"Bytes recorded per channel",
+			&downloaded,
+		);
+		perChannel(
+			&mut out,
+			"ld_segments_fetched_total",
+			"Hls segments fetched per channel",
+			&self.segmentsFetched.lock().unwrap(),
+		);
+		perChannel(
+			&mut out,
+			"ld_segments_failed_total",
+			"Hls segments that failed to download per channel",
+			&self.segmentsFailed.lock().unwrap(),
+		);
+		perChannel(
+			&mut out,
+			"ld_gap_seconds_total",
+			"Seconds of stream missing from recordings per channel",
+			&self.gapSeconds.lock().unwrap(),
+		);
+
+		header(
+			&mut out,
+			"ld_eventsub_reconnects_total",
+			"counter",
+			"Eventsub websocket reconnects",
+		);
+		writeln!(
+			out,
+			"ld_eventsub_reconnects_total {}",
+			self.reconnects.load(Ordering::Relaxed)
+		)
+		.unwrap();
+
+		if let Some(last) = *self.lastEventsubMessage.lock().unwrap() {
+			header(
+				&mut out,
+				"ld_eventsub_keepalive_age_seconds",
+				"gauge",
+				"Seconds since the last eventsub message",
+			);
+			writeln!(
+				out,
+				"ld_eventsub_keepalive_age_seconds {:.3}",
+				last.elapsed().as_secs_f64()
+			)
+			.unwrap();
+		}
+
+		header(
+			&mut out,
+			"ld_helix_request_duration_seconds",
+			"histogram",
+			"Twitch api request latency by endpoint and status",
+		);
+		for ((endpoint, status), histogram) in self.helix.lock().unwrap().iter() {
+			let labels = format!("endpoint=\"{}\",status=\"{status}\"", label(endpoint));
+			for (bucket, bound) in histogram.buckets.iter().zip(LATENCY_BUCKETS) {
+				writeln!(
+					out,
+					"ld_helix_request_duration_seconds_bucket{{{labels},le=\"{bound}\"}} {bucket}"
+				)
+				.unwrap();
+			}
+			writeln!(
+				out,
+				"ld_helix_request_duration_seconds_bucket{{{labels},le=\"+Inf\"}} {}",
+				histogram.count
+			)
+			.unwrap();
+			writeln!(
+				out,
+				"ld_helix_request_duration_seconds_sum{{{labels}}} {}",
+				histogram.sum
+			)
+			.unwrap();
+			writeln!(
+				out,
+				"ld_helix_request_duration_seconds_count{{{labels}}} {}",
+				histogram.count
+			)
+			.unwrap();
+		}
+
+		let remaining = self.rateLimitRemaining.load(Ordering::Relaxed);
+		if remaining >= 0 {
+			header(
+				&mut out,
+				"ld_helix_ratelimit_remaining",
+				"gauge",
+				"Points left in the current helix rate limit window",
+			);
+			writeln!(out, "ld_helix_ratelimit_remaining {remaining}").unwrap();
+		}
+
+		if let Some(expiresAt) = *self.tokenExpiresAt.lock().unwrap() {
+			header(
+				&mut out,
+				"ld_token_expiry_seconds",
+				"gauge",
+				"Seconds until the twitch token expires",
+			);
+			writeln!(
+				out,
+				"ld_token_expiry_seconds {}",
+				(expiresAt - Utc::now()).num_seconds().max(0)
+			)
+			.unwrap();
+		}
+
+		out
+	}
+}
@@ -0,0 +1,128 @@
+use std::sync::Arc;
+
+use log::{debug, error};
+
+use crate::{
+	api::try_request,
+	data::{Config, Notification, NotifyConfig, NotifyEvent, NotifyFormat},
+};
+
+pub const DEFAULT_RETRIES: i32 = 3;
+
+/// Sends recorder events to configured webhooks
+pub struct Notifier {
+	c: reqwest::Client,
+	targets: Vec<NotifyConfig>,
+}
+
+impl Notification {
+	pub fn new(event: NotifyEvent, channel: Option<&str>, message: impl Into<String>) -> Self {
+		Notification {
+			event,
+			channel: channel.map(str::to_string),
+			message: message.into(),
+			timestamp: chrono::Utc::now(),
+			details: serde_json::Value::Null,
+		}
+	}
+
+	pub fn details(mut self, details: serde_json::Value) -> Self {
+		self.details = details;
+		self
+	}
+}
+
+fn isUrgent(event: NotifyEvent) -> bool {
+	matches!(
+		event,
+		NotifyEvent::RecordingFailed | NotifyEvent::AuthFailed | NotifyEvent::StorageLow
+	)
+}
+
+impl Notifier {
+	pub fn new(config: &Config) -> Self {
+		Notifier {
+			c: reqwest::Client::new(),
+			targets: config.notify.clone().unwrap_or_default(),
+		}
+	}
+
+	fn request(&self, target: &NotifyConfig, notification: &Notification) -> reqwest::RequestBuilder {
+		let req = self.c.post(&target.url);
+		match target.format {
+			NotifyFormat::Generic => req
+				.header("Content-Type", "application/json")
+				.body(serde_json::to_string(notification).unwrap()),
+			NotifyFormat::Discord => req
+				.header("Content-Type", "application/json")
+				.body(serde_json::json!({ "content": notification.message }).to_string()),
+			NotifyFormat::Ntfy => req
+				.header(
+					"Title",
+					format!("live-downloader: {:?}", notification.event),
+				)
+				.header("Tags", notification.channel.as_deref().unwrap_or("ld"))
+				.header(
+					"Priority",
+					if isUrgent(notification.event) {
+						"high"
+					} else {
+						"default"
+					},
+				)
+				.body(notification.message.clone()),
+		}
+	}
+
+	async fn send(&self, target: &NotifyConfig, notification: &Notification) {
+		let result = try_request(
+			|| {
+				let req = self.request(target, notification);
+				async move {
+					let resp = req.send().await.map_err(|err| err.to_string())?;
+					match resp.status() {
+						status if status.is_success() => Ok(()),
+						status => Err(format!(
+							"{status} {}",
+							resp.text().await.unwrap_or_default().trim()
+						)),
+					}
+				}
+			},
+			target.retries.unwrap_or(DEFAULT_RETRIES),
+		)
+		.await;
+
+		match result {
+			Ok(()) => debug!("[NTFY] {:?} sent to {}", notification.event, target.url),
+			Err(err) => error!(
+				"[NTFY] failed to send {:?} to {}: {err}",
+				notification.event, target.url
+			),
+		}
+	}
+
+	/// Delivers to every target subscribed to the event
+	pub async fn notify(&self, notification: &Notification) {
+		let futures = self
+			.targets
+			.iter()
+			.filter(|target| {
+				target
+					.events
+					.as_ref()
+					.is_none_or(|events| events.contains(&notification.event))
+			})
+			.map(|target| self.send(target, notification));
+		futures::future::join_all(futures).await;
+	}
+
+	/// Delivers in the background so slow or retrying endpoints don't hold up the caller
+	pub fn fire(self: &Arc<Self>, notification: Notification) {
+		if self.targets.is_empty() {
+			return;
+		}
+		let notifier = self.clone();
+		tokio::spawn(async move { notifier.notify(&notification).await });
+	}
+}
@@ -0,0 +1,973 @@
+use crate::{
+	data::{Config, ManifestPart, RecordingManifest, SplitConfig},
+	template::RecordingInfo,
+};
+use chrono::{DateTime, Utc};
+use std::{
+	path::{Path, PathBuf},
+	process::{ExitStatus, Stdio},
+	sync::{
+		Mutex,
+		atomic::{AtomicBool, AtomicU32, Ordering},
+	},
+	time::{Duration, Instant},
+};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+
+const TS_PACKET: usize = 188;
+
+pub const BACKEND: &str = "streamlink";
+
+/// Shared with whoever wants to see the recorder process or stop it early
+#[derive(Default)]
+pub struct Handle {
+	pid: AtomicU32,
+	stop: tokio::sync::Notify,
+	stopped: AtomicBool,
+	/// when the first recorded frame aired, see `mediaStartedAt`
+	mediaStart: Mutex<Option<DateTime<Utc>>>,
+}
+
+impl Handle {
+	pub fn pid(&self) -> Option<u32> {
+		Some(self.pid.load(Ordering::Relaxed)).filter(|pid| *pid != 0)
+	}
+
+	/// Kills the recorder, files written so far are kept and finished as usual
+	pub fn stop(&self) {
+		self.stopped.store(true, Ordering::Relaxed);
+		self.stop.notify_one();
+	}
+
+	/// Whether the recording ended because it was asked to
+	pub fn stopped(&self) -> bool {
+		self.stopped.load(Ordering::Relaxed)
+	}
+
+	/// When the first recorded frame aired, estimated as the latest time the media covered so far could have begun;
+	/// settles once streamlink has caught up with the live edge, earlier than the recording start when it rewound
+	pub fn mediaStartedAt(&self) -> Option<DateTime<Utc>> {
+		*self.mediaStart.lock().unwrap()
+	}
+
+	fn media(&self, elapsed: Duration) {
+		let start = Utc::now() - chrono::Duration::from_std(elapsed).unwrap_or_default();
+		let mut mediaStart = self.mediaStart.lock().unwrap();
+		*mediaStart = Some(mediaStart.map_or(start, |known| known.min(start)));
+	}
+
+	fn attach(&self, child: &tokio::process::Child) {
+		self
+			.pid
+			.store(child.id().unwrap_or_default(), Ordering::Relaxed);
+	}
+}
+
+pub fn recordingPath(root: &Path, template: Option<&str>, info: &RecordingInfo) -> PathBuf {
+	let relative =
+		crate::template::render(template.unwrap_or(crate::template::DEFAULT_TEMPLATE), info)
+			.expect("filename template is validated with config");
+
+	root.join(relative)
+}
+
+/// Path of a part, templates without `{part}` get `.partNNN` inserted before the extension
+pub fn partPath(root: &Path, template: Option<&str>, info: &RecordingInfo, part: u32) -> PathBuf {
+	let first = recordingPath(
+		root,
+		template,
+		&RecordingInfo {
+			part: 1,
+			..info.clone()
+		},
+	);
+	let path = recordingPath(
+		root,
+		template,
+		&RecordingInfo {
+			part,
+			..info.clone()
+		},
+	);
+	if part == 1 || path != first {
+		return path;
+	}
+
+	let mut name = path.file_stem().unwrap_or_default().to_os_string();
+	name.push(format!(".part{part:03}"));
+	if let Some(ext) = path.extension() {
+		name.push(".");
+		name.push(ext);
+	}
+	path.with_file_name(name)
+}
+
+/// Where the recording (or its first part when split) is written
+pub fn firstPath(config: &Config, info: &RecordingInfo) -> PathBuf {
+	let root = PathBuf::from(&config.root);
+	let template = config.filenameTemplate.as_deref();
+	match config.split {
+		Some(_) => partPath(&root, template, info, 1),
+		None => recordingPath(&root, template, info),
+	}
+}
+
+/// `<first part without extension>.manifest.json`, shares the retention group of the recording
+pub fn manifestPath(firstPart: &Path) -> PathBuf {
+	firstPart.with_extension("manifest.json")
+}
+
+fn baseCommand(token: Option<&str>) -> tokio::process::Command {
+	// switch ytdlp --add-headers "Authorization:OAuth {token}" "twitch.tv/negnasu"
+	let mut command = tokio::process::Command::new("streamlink");
+	if let Some(token) = token {
+		command.args(["--http-header", &format!("Authorization=OAuth {}", token)]);
+	}
+	// segment progress is logged at debug, `watchLog` counts it and demotes it to trace
+	command.args(["--loglevel", "debug"]);
+
+	command
+}
+
+/// `restart` rewinds to the start of the dvr window, only wanted on a fresh recording:
+/// a resumed one would fetch everything its earlier parts hold again
+fn streamlinkCommand(token: Option<&str>, channel: &str, restart: bool) -> tokio::process::Command {
+	let mut command = baseCommand(token);
+	if restart {
+		command.arg("--hls-live-restart");
+	}
+	command.args([
+		"--hls-playlist-reload-time",
+		"3",
+		"--twitch-supported-codecs",
+		"h264,h265,av1",
+		"--retry-streams",
+		"5",
+		&format!("twitch.tv/{}", channel),
+		"best",
+	]);
+
+	command
+}
+
+/// Counts segments from streamlink log lines and passes them on to the log at their own level,
+/// the debug output asked for to see segments goes to trace
+fn watchLog(child: &mut tokio::process::Child, channel: &str) {
+	let Some(stderr) = child.stderr.take() else {
+		return;
+	};
+	let channel = channel.to_string();
+	tokio::spawn(async move {
+		let mut lines = tokio::io::BufReader::new(stderr).lines();
+		while let Ok(Some(line)) = lines.next_line().await {
+			let lower = line.to_lowercase();
+			if lower.contains("segment") {
+				// "segment 12: completed" in current streamlink, "download of segment 12 complete" in older ones
+				if lower.contains("complete") {
+					crate::metrics::metrics().addSegmentFetched(&channel);
+				} else if lower.contains("failed") {
+					crate::metrics::metrics().addSegmentFailed(&channel);
+				}
+			}
+			// lines look like `[cli][info] Opening stream: 1080p60 (hls)`
+			let level = match line
+				.split_once("][")
+				.map(|(_, rest)| rest.split(']').next())
+			{
+				Some(Some("debug" | "trace" | "all")) => log::Level::Trace,
+				Some(Some("error" | "critical")) => log::Level::Error,
+				Some(Some("warning")) => log::Level::Warn,
+				_ => log::Level::Info,
+			};
+			log::log!(level, "[STLK] {channel}: {line}");
+		}
+	});
+}
+
+/// Downloads a vod or clip, or only its first `duration`; an in-progress vod works too
+pub async fn streamlinkVideo(
+	token: Option<&str>,
+	channel: &str,
+	url: &str,
+	duration: Option<Duration>,
+	path: &Path,
+	handle: &Handle,
+) -> std::io::Result<ExitStatus> {
+	let mut command = baseCommand(token);
+	command.arg("--force");
+	if let Some(duration) = duration {
+		command.args(["--hls-duration", &duration.as_secs().to_string()]);
+	}
+	command.args(["--twitch-supported-codecs", "h264,h265,av1", url, "best"]);
+
+	runToFile(command, channel, path, handle).await
+}
+
+async fn runToFile(
+	mut command: tokio::process::Command,
+	channel: &str,
+	path: &Path,
+	handle: &Handle,
+) -> std::io::Result<ExitStatus> {
+	let mut child = command
+		.args(["-o", path.to_str().unwrap()])
+		.stdout(Stdio::inherit())
+		.stderr(Stdio::piped())
+		.spawn()?;
+	handle.attach(&child);
+	watchLog(&mut child, channel);
+
+	tokio::select! {
+		status = child.wait() => status,
+		_ = handle.stop.notified() => {
+			log::info!("[STOP] {channel}: stopping streamlink");
+			child.kill().await?;
+			child.wait().await
+		}
+	}
+}
+
+fn pid(packet: &[u8]) -> u16 {
+	(((packet[1] & 0x1f) as u16) << 8) | packet[2] as u16
+}
+
+/// Twitch hls segments open with a keyframe carrying the random access indicator, cutting there keeps parts playable;
+/// audio packets carry the flag too, only `videoPid` counts
+fn isSegmentStart(packet: &[u8], videoPid: Option<u16>) -> bool {
+	let pusi = packet[1] & 0x40 != 0;
+	let hasAdaptation = packet[3] & 0x20 != 0;
+	packet[0] == 0x47
+		&& videoPid == Some(pid(packet))
+		&& pusi
+		&& hasAdaptation
+		&& packet[4] > 0
+		&& packet[5] & 0x40 != 0
+}
+
+/// Payload of the packet, after the adaptation field when there is one
+fn payload(packet: &[u8]) -> Option<&[u8]> {
+	if packet[3] & 0x10 == 0 {
+		return None;
+	}
+	match packet[3] & 0x20 != 0 {
+		true => packet.get(5 + packet[4] as usize..),
+		false => packet.get(4..),
+	}
+}
+
+/// Table section starting in the packet, past the pointer field
+fn section(packet: &[u8]) -> Option<&[u8]> {
+	if packet[1] & 0x40 == 0 {
+		return None;
+	}
+	let payload = payload(packet)?;
+	payload.get(1 + *payload.first()? as usize..)
+}
+
+/// End of the entries in a table section, the crc closes it
+fn sectionEnd(section: &[u8]) -> usize {
+	let length = (((section[1] & 0x0f) as usize) << 8) | section[2] as usize;
+	(3 + length).saturating_sub(4).min(section.len())
+}
+
+/// Pid of the video stream in a PMT packet, the first stream of any kind when there's no video (audio only)
+fn videoPid(packet: &[u8]) -> Option<u16> {
+	let section = section(packet)?;
+	let header = section.get(..12)?;
+	let infoLength = (((header[10] & 0x0f) as usize) << 8) | header[11] as usize;
+	let end = sectionEnd(section);
+
+	let mut streams = Vec::new();
+	let mut offset = 12 + infoLength;
+	while offset + 5 <= end {
+		let streamType = section[offset];
+		let pid = (((section[offset + 1] & 0x1f) as u16) << 8) | section[offset + 2] as u16;
+		streams.push((streamType, pid));
+		offset += 5 + ((((section[offset + 3] & 0x0f) as usize) << 8) | section[offset + 4] as usize);
+	}
+	// mpeg-1/2, mpeg-4, h264, h265
+	streams
+		.iter()
+		.find(|(streamType, _)| matches!(streamType, 0x01 | 0x02 | 0x10 | 0x1b | 0x24))
+		.or(streams.first())
+		.map(|(_, pid)| *pid)
+}
+
+/// First pmt pid listed in a PAT packet, program 0 points at the network table instead
+fn pmtPid(packet: &[u8]) -> Option<u16> {
+	let section = section(packet)?;
+	section.get(..8)?;
+	let end = sectionEnd(section);
+
+	let mut offset = 8;
+	while offset + 4 <= end {
+		let program = ((section[offset] as u16) << 8) | section[offset + 1] as u16;
+		if program != 0 {
+			return Some((((section[offset + 2] & 0x1f) as u16) << 8) | section[offset + 3] as u16);
+		}
+		offset += 4;
+	}
+
+	None
+}
+
+/// Latest program tables, replayed at the top of every new part so it stands on its own
+#[derive(Default)]
+struct Tables {
+	pat: Option<Vec<u8>>,
+	pmt: Option<Vec<u8>>,
+	pmtPid: Option<u16>,
+	videoPid: Option<u16>,
+}
+
+impl Tables {
+	fn observe(&mut self, packet: &[u8]) {
+		if packet[0] != 0x47 {
+			return;
+		}
+		match pid(packet) {
+			0 => {
+				self.pmtPid = pmtPid(packet).or(self.pmtPid);
+				self.pat = Some(packet.to_vec());
+			}
+			pid if Some(pid) == self.pmtPid && packet[1] & 0x40 != 0 => {
+				self.videoPid = videoPid(packet).or(self.videoPid);
+				self.pmt = Some(packet.to_vec());
+			}
+			_ => {}
+		}
+	}
+
+	fn header(&self) -> Vec<u8> {
+		[self.pat.as_deref(), self.pmt.as_deref()]
+			.into_iter()
+			.flatten()
+			.flatten()
+			.copied()
+			.collect()
+	}
+}
+
+/// 90 kHz ticks wrap at 33 bits
+const PTS_WRAP: u64 = 1 << 33;
+/// A bigger step between frames is a discontinuity (ad break, reconnect) rather than media time
+const MAX_PTS_STEP: u64 = 10 * 90_000;
+
+/// Presentation timestamp of a PES starting in the packet
+fn pts(packet: &[u8]) -> Option<u64> {
+	if packet[1] & 0x40 == 0 {
+		return None;
+	}
+	let pes = payload(packet)?.get(..14)?;
+	if pes[..3] != [0, 0, 1] || pes[7] & 0x80 == 0 {
+		return None;
+	}
+	let ts = &pes[9..14];
+	Some(
+		((ts[0] as u64 >> 1) & 0x07) << 30
+			| (ts[1] as u64) << 22
+			| (ts[2] as u64 >> 1) << 15
+			| (ts[3] as u64) << 7
+			| ts[4] as u64 >> 1,
+	)
+}
+
+/// Media time covered by the video stream so far, only forward steps count
+/// so reordered frames and discontinuities don't inflate it
+#[derive(Default)]
+struct Clock {
+	latest: Option<u64>,
+	ticks: u64,
+}
+
+impl Clock {
+	fn observe(&mut self, packet: &[u8], videoPid: Option<u16>) {
+		if videoPid != Some(pid(packet)) {
+			return;
+		}
+		let Some(pts) = pts(packet) else {
+			return;
+		};
+		let step = pts.wrapping_sub(self.latest.unwrap_or(pts)) % PTS_WRAP;
+		match step {
+			0 => self.latest = Some(pts),
+			step if step <= MAX_PTS_STEP => {
+				self.ticks += step;
+				self.latest = Some(pts);
+			}
+			// a frame shown before the latest one
+			step if PTS_WRAP - step <= MAX_PTS_STEP => {}
+			_ => self.latest = Some(pts),
+		}
+	}
+
+	fn elapsed(&self) -> Duration {
+		Duration::from_micros(self.ticks * 100 / 9)
+	}
+}
+
+/// Puts the parts written in this run on the media timeline, `offsets` are where each of them begins in it
+fn retime(
+	parts: &mut [ManifestPart],
+	offsets: &[Duration],
+	start: DateTime<Utc>,
+	end: Option<Duration>,
+) {
+	let at = |offset: Duration| start + chrono::Duration::from_std(offset).unwrap_or_default();
+	for (i, part) in parts.iter_mut().enumerate() {
+		part.startedAt = at(offsets[i]);
+		if let Some(next) = offsets.get(i + 1).copied().or(end) {
+			part.endedAt = Some(at(next));
+		}
+	}
+}
+
+struct Limits {
+	duration: Option<Duration>,
+	bytes: Option<u64>,
+}
+
+impl Limits {
+	fn from(split: SplitConfig) -> Self {
+		Limits {
+			duration: split.maxMinutes.map(|m| Duration::from_secs(m * 60)),
+			bytes: split.maxGb.map(|gb| (gb * 1024.0 * 1024.0 * 1024.0) as u64),
+		}
+	}
+}
+
+async fn writeManifest(path: &Path, manifest: &RecordingManifest) {
+	let text = serde_json::to_string_pretty(manifest).unwrap();
+	if let Err(err) = tokio::fs::write(path, text).await {
+		log::error!("[MNFS] failed to write {}: {err}", path.display());
+	}
+}
+
+/// Retimes the parts written in this run (from `run` on) once the media clock is known and saves the manifest, if there is one
+async fn saveManifest(
+	path: Option<&Path>,
+	manifest: &mut RecordingManifest,
+	run: usize,
+	offsets: &[Duration],
+	handle: &Handle,
+	end: Option<Duration>,
+) {
+	if let Some(start) = handle.mediaStartedAt() {
+		retime(&mut manifest.parts[run..], offsets, start, end);
+	}
+	if let Some(path) = path {
+		writeManifest(path, manifest).await;
+	}
+}
+
+/// Pipes streamlink output into consecutive parts, rolling over at the first segment boundary past a limit;
+/// the manifest is rewritten on every rollover so a crash still leaves the parts tied together.
+/// Without `split` it's a single file and no manifest, the media clock still ends up on `handle`
+pub async fn streamlinkParts(
+	token: Option<&str>,
+	info: &RecordingInfo,
+	split: Option<&SplitConfig>,
+	mut nextPath: impl FnMut(u32) -> PathBuf,
+	mut manifest: RecordingManifest,
+	handle: &Handle,
+) -> std::io::Result<ExitStatus> {
+	let limits = Limits::from(split.cloned().unwrap_or_default());
+	let mut child = streamlinkCommand(token, &info.login, manifest.parts.is_empty())
+		.arg("--stdout")
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.spawn()?;
+	handle.attach(&child);
+	watchLog(&mut child, &info.login);
+	let mut stdout = child.stdout.take().unwrap();
+
+	// a resumed recording already lists its earlier parts, the manifest stays next to the very first one
+	let run = manifest.parts.len();
+	let firstPart = manifest.parts.last().map_or(1, |part| part.part + 1);
+	let firstPath = nextPath(firstPart);
+	let manifestFile = split.map(|_| {
+		manifestPath(
+			manifest
+				.parts
+				.first()
+				.map_or(firstPath.as_path(), |part| Path::new(&part.path)),
+		)
+	});
+	if let Some(parent) = firstPath.parent() {
+		tokio::fs::create_dir_all(parent).await?;
+	}
+	let mut file = tokio::fs::File::create(&firstPath).await?;
+	let mut partStart = Instant::now();
+	manifest.parts.push(ManifestPart {
+		part: firstPart,
+		path: firstPath.to_string_lossy().to_string(),
+		bytes: 0,
+		startedAt: chrono::Utc::now(),
+		endedAt: None,
+	});
+	let mut offsets = vec![Duration::ZERO];
+	saveManifest(
+		manifestFile.as_deref(),
+		&mut manifest,
+		run,
+		&offsets,
+		handle,
+		None,
+	)
+	.await;
+
+	let mut buf = vec![0u8; TS_PACKET * 256];
+	let mut pending: Vec<u8> = Vec::new();
+	let mut tables = Tables::default();
+	let mut clock = Clock::default();
+	let mut receiving = false;
+	loop {
+		let read = tokio::select! {
+			read = stdout.read(&mut buf) => read?,
+			_ = handle.stop.notified() => {
+				log::info!("[STOP] {}: stopping streamlink", info.login);
+				child.kill().await?;
+				0
+			}
+		};
+		if read == 0 {
+			break;
+		}
+		// streamlink takes a moment to open the stream, the gap to an earlier part lasts until data flows
+		if !receiving {
+			receiving = true;
+			manifest.parts.last_mut().unwrap().startedAt = chrono::Utc::now();
+		}
+		pending.extend_from_slice(&buf[..read]);
+
+		let whole = pending.len() / TS_PACKET * TS_PACKET;
+		let mut timeDue = limits
+			.duration
+			.is_some_and(|limit| partStart.elapsed() >= limit);
+		let mut start = 0;
+
+		for offset in (0..whole).step_by(TS_PACKET) {
+			let packet = &pending[offset..offset + TS_PACKET];
+			tables.observe(packet);
+			clock.observe(packet, tables.videoPid);
+
+			let current = manifest.parts.last_mut().unwrap();
+			let sizeDue = limits
+				.bytes
+				.is_some_and(|limit| current.bytes + (offset - start) as u64 >= limit);
+			if (offset == start && current.bytes == 0)
+				|| !(timeDue || sizeDue)
+				|| !isSegmentStart(packet, tables.videoPid)
+			{
+				continue;
+			}
+
+			file.write_all(&pending[start..offset]).await?;
+			file.flush().await?;
+			current.bytes += (offset - start) as u64;
+			current.endedAt = Some(chrono::Utc::now());
+			start = offset;
+
+			let part = current.part + 1;
+			let path = nextPath(part);
+			log::info!("[PART] {} part {part}: {}", info.login, path.display());
+			file = tokio::fs::File::create(&path).await?;
+			let header = tables.header();
+			file.write_all(&header).await?;
+			partStart = Instant::now();
+			timeDue = false;
+			manifest.parts.push(ManifestPart {
+				part,
+				path: path.to_string_lossy().to_string(),
+				bytes: header.len() as u64,
+				startedAt: chrono::Utc::now(),
+				endedAt: None,
+			});
+			offsets.push(clock.elapsed());
+			saveManifest(
+				manifestFile.as_deref(),
+				&mut manifest,
+				run,
+				&offsets,
+				handle,
+				None,
+			)
+			.await;
+		}
+		if clock.ticks > 0 {
+			handle.media(clock.elapsed());
+		}
+
+		file.write_all(&pending[start..whole]).await?;
+		manifest.parts.last_mut().unwrap().bytes += (whole - start) as u64;
+		pending.drain(..whole);
+	}
+
+	// trailing partial packet, keep it rather than silently dropping data
+	file.write_all(&pending).await?;
+	file.flush().await?;
+	let last = manifest.parts.last_mut().unwrap();
+	last.bytes += pending.len() as u64;
+	last.endedAt = Some(chrono::Utc::now());
+	let end = (clock.ticks > 0).then(|| clock.elapsed());
+	saveManifest(
+		manifestFile.as_deref(),
+		&mut manifest,
+		run,
+		&offsets,
+		handle,
+		end,
+	)
+	.await;
+
+	child.wait().await
+}
+
+/// Manifest of an interrupted recording, parts it never got to list are added from `previous`
+/// and every unfinished part ends when its file was last written
+async fn resumedManifest(fresh: RecordingManifest, previous: &[PathBuf]) -> RecordingManifest {
+	let mut manifest = tokio::fs::read(manifestPath(&previous[0]))
+		.await
+		.ok()
+		.and_then(|bytes| serde_json::from_slice::<RecordingManifest>(&bytes).ok())
+		.unwrap_or(fresh);
+
+	for (i, path) in previous.iter().enumerate() {
+		if i >= manifest.parts.len() {
+			let startedAt = manifest
+				.parts
+				.last()
+				.and_then(|part| part.endedAt)
+				.unwrap_or(manifest.startedAt);
+			manifest.parts.push(ManifestPart {
+				part: i as u32 + 1,
+				path: path.to_string_lossy().to_string(),
+				bytes: 0,
+				startedAt,
+				endedAt: None,
+			});
+		}
+		let part = &mut manifest.parts[i];
+		if let Ok(meta) = tokio::fs::metadata(&part.path).await {
+			part.bytes = meta.len();
+			if part.endedAt.is_none() {
+				part.endedAt = meta.modified().ok().map(chrono::DateTime::from);
+			}
+		}
+	}
+
+	manifest
+}
+
+/// Appends the later parts of a continued unsplit recording to its first file and removes them with the manifest,
+/// transport streams can be joined as they are and the timestamps keep the downtime; returns the parts the manifest listed
+pub async fn joinParts(parts: &[PathBuf]) -> std::io::Result<Vec<ManifestPart>> {
+	let Some((first, rest)) = parts.split_first() else {
+		return Ok(Vec::new());
+	};
+	let listed = tokio::fs::read(manifestPath(first))
+		.await
+		.ok()
+		.and_then(|bytes| serde_json::from_slice::<RecordingManifest>(&bytes).ok())
+		.map(|manifest| manifest.parts)
+		.unwrap_or_default();
+	let mut file = tokio::fs::OpenOptions::new()
+		.append(true)
+		.open(first)
+		.await?;
+	let length = file.metadata().await?.len();
+	for part in rest {
+		let copied = match tokio::fs::File::open(part).await {
+			Ok(mut part) => tokio::io::copy(&mut part, &mut file).await,
+			Err(err) => Err(err),
+		};
+		if let Err(err) = copied {
+			// the parts stay as they were rather than partly twice
+			file.set_len(length).await.ok();
+			return Err(err);
+		}
+	}
+	file.flush().await?;
+
+	for part in rest {
+		tokio::fs::remove_file(part).await?;
+	}
+	tokio::fs::remove_file(manifestPath(first)).await.ok();
+
+	Ok(listed)
+}
+
+/// Records a stream according to config, split into parts when `split` is set;
+/// `previous` are the files of an interrupted recording of the same broadcast, recording continues with the part after them;
+/// `onPart` is called with every new file before anything is written to it
+pub async fn record(
+	config: &Config,
+	info: &RecordingInfo,
+	previous: &[PathBuf],
+	handle: &Handle,
+	mut onPart: impl FnMut(&Path),
+) -> std::io::Result<ExitStatus> {
+	let root = PathBuf::from(&config.root);
+	let template = config.filenameTemplate.as_deref();
+	let token = config.streamlinkToken.as_deref();
+
+	let split = match &config.split {
+		Some(split) => Some(split.clone()),
+		// an unsplit file can't be reopened mid-stream, it's continued as a second part without limits
+		None if !previous.is_empty() => Some(SplitConfig::default()),
+		None => None,
+	};
+
+	let chat = config.chatRoot.as_ref().map(|_| {
+		crate::chat::logPath(config, &info.login)
+			.to_string_lossy()
+			.to_string()
+	});
+	let manifest = RecordingManifest {
+		channel: info.login.clone(),
+		streamId: info.streamId.clone(),
+		title: info.title.clone(),
+		category: info.category.clone(),
+		startedAt: info.startedAt,
+		chat,
+		parts: Vec::new(),
+	};
+	let manifest = match previous.is_empty() {
+		true => manifest,
+		false => resumedManifest(manifest, previous).await,
+	};
+
+	streamlinkParts(
+		token,
+		info,
+		split.as_ref(),
+		|part| {
+			let path = partPath(&root, template, info, part);
+			onPart(&path);
+			path
+		},
+		manifest,
+		handle,
+	)
+	.await
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// A 188 byte packet, `adaptation` is the adaptation field after its length byte
+	fn packet(pid: u16, pusi: bool, adaptation: Option<&[u8]>, payload: &[u8]) -> Vec<u8> {
+		let mut packet = vec![0x47, (pusi as u8) << 6 | (pid >> 8) as u8, pid as u8, 0x10];
+		if let Some(adaptation) = adaptation {
+			packet[3] |= 0x20;
+			packet.push(adaptation.len() as u8);
+			packet.extend_from_slice(adaptation);
+		}
+		packet.extend_from_slice(payload);
+		packet.resize(TS_PACKET, 0xff);
+		packet
+	}
+
+	/// Table section behind a zero pointer field, `length` covers the entries and the crc
+	fn table(id: u8, header: &[u8], entries: &[u8]) -> Vec<u8> {
+		let length = header.len() + entries.len() + 4;
+		let mut section = vec![0, id, 0xb0 | (length >> 8) as u8, length as u8];
+		section.extend_from_slice(header);
+		section.extend_from_slice(entries);
+		section.extend_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+		section
+	}
+
+	fn pat(programs: &[(u16, u16)]) -> Vec<u8> {
+		let entries = programs
+			.iter()
+			.flat_map(|&(program, pid)| {
+				[
+					(program >> 8) as u8,
+					program as u8,
+					0xe0 | (pid >> 8) as u8,
+					pid as u8,
+				]
+			})
+			.collect::<Vec<_>>();
+		table(0x00, &[0, 1, 0xc1, 0, 0], &entries)
+	}
+
+	fn pmt(streams: &[(u8, u16)]) -> Vec<u8> {
+		let entries = streams
+			.iter()
+			.flat_map(|&(streamType, pid)| [streamType, 0xe0 | (pid >> 8) as u8, pid as u8, 0xf0, 0])
+			.collect::<Vec<_>>();
+		table(0x02, &[0, 1, 0xc1, 0, 0, 0xe1, 0, 0xf0, 0], &entries)
+	}
+
+	fn pes(pts: u64) -> Vec<u8> {
+		vec![
+			0,
+			0,
+			1,
+			0xe0,
+			0,
+			0,
+			0x80,
+			0x80,
+			5,
+			0x21 | ((pts >> 30) as u8 & 0x07) << 1,
+			(pts >> 22) as u8,
+			(pts >> 14) as u8 | 1,
+			(pts >> 7) as u8,
+			(pts << 1) as u8 | 1,
+		]
+	}
+
+	#[test]
+	fn pmtPidSkipsTheNetworkProgram() {
+		assert_eq!(
+			pmtPid(&packet(0, true, None, &pat(&[(1, 0x100)]))),
+			Some(0x100)
+		);
+		assert_eq!(
+			pmtPid(&packet(0, true, None, &pat(&[(0, 0x10), (1, 0x1000)]))),
+			Some(0x1000)
+		);
+		assert_eq!(pmtPid(&packet(0, true, None, &pat(&[(0, 0x10)]))), None);
+		// a table only starts in a packet with the payload unit start indicator
+		assert_eq!(pmtPid(&packet(0, false, None, &pat(&[(1, 0x100)]))), None);
+	}
+
+	#[test]
+	fn readsTablesBehindAnAdaptationField() {
+		let pat = packet(
+			0,
+			true,
+			Some(&[0x00, 0xff, 0xff]),
+			&pat(&[(0, 0x10), (1, 0x100)]),
+		);
+		assert_eq!(pmtPid(&pat), Some(0x100));
+		let pmt = packet(0x100, true, Some(&[0x00]), &pmt(&[(0x1b, 0x101)]));
+		assert_eq!(videoPid(&pmt), Some(0x101));
+	}
+
+	#[test]
+	fn videoPidPrefersVideo() {
+		let pmt = |streams: &[(u8, u16)]| packet(0x100, true, None, &pmt(streams));
+		assert_eq!(videoPid(&pmt(&[(0x0f, 0x102), (0x1b, 0x101)])), Some(0x101));
+		assert_eq!(videoPid(&pmt(&[(0x0f, 0x102), (0x24, 0x103)])), Some(0x103));
+		// audio only
+		assert_eq!(videoPid(&pmt(&[(0x0f, 0x102)])), Some(0x102));
+		assert_eq!(videoPid(&pmt(&[])), None);
+	}
+
+	#[test]
+	fn tablesFollowThePatToTheVideoStream() {
+		let pat = packet(0, true, None, &pat(&[(0, 0x10), (1, 0x100)]));
+		let pmt = packet(0x100, true, None, &pmt(&[(0x0f, 0x102), (0x1b, 0x101)]));
+		let mut tables = Tables::default();
+		tables.observe(&pmt);
+		assert_eq!(tables.videoPid, None);
+		tables.observe(&pat);
+		tables.observe(&pmt);
+		tables.observe(&packet(0x101, true, None, &pes(0)));
+
+		assert_eq!(tables.pmtPid, Some(0x100));
+		assert_eq!(tables.videoPid, Some(0x101));
+		assert_eq!(tables.header(), [pat, pmt].concat());
+	}
+
+	#[test]
+	fn readsPts() {
+		let pts = 0x1_2345_6789;
+		assert_eq!(super::pts(&packet(0x101, true, None, &pes(pts))), Some(pts));
+		assert_eq!(
+			super::pts(&packet(0x101, true, Some(&[0x40, 0, 0]), &pes(pts))),
+			Some(pts)
+		);
+		// continuation of a pes
+		assert_eq!(super::pts(&packet(0x101, false, None, &pes(pts))), None);
+		// adaptation field only
+		let mut stuffing = packet(0x101, true, Some(&[0; 183]), &[]);
+		stuffing[3] &= !0x10;
+		assert_eq!(super::pts(&stuffing), None);
+	}
+
+	#[test]
+	fn segmentsStartAtVideoKeyframes() {
+		let keyframe = packet(0x101, true, Some(&[0x40]), &pes(0));
+		assert!(isSegmentStart(&keyframe, Some(0x101)));
+		// audio packets carry the flag too
+		assert!(!isSegmentStart(&keyframe, Some(0x102)));
+		assert!(!isSegmentStart(&keyframe, None));
+		assert!(!isSegmentStart(
+			&packet(0x101, false, Some(&[0x40]), &pes(0)),
+			Some(0x101)
+		));
+		assert!(!isSegmentStart(
+			&packet(0x101, true, Some(&[0x00]), &pes(0)),
+			Some(0x101)
+		));
+		assert!(!isSegmentStart(
+			&packet(0x101, true, Some(&[]), &pes(0)),
+			Some(0x101)
+		));
+		assert!(!isSegmentStart(
+			&packet(0x101, true, None, &pes(0)),
+			Some(0x101)
+		));
+	}
+
+	#[tokio::test]
+	async fn joinsPartsIntoTheFirst() {
+		let dir = std::env::temp_dir().join(format!("ld-record-{}-join", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		let parts = ["x.ts", "x.part002.ts", "x.part003.ts"].map(|name| dir.join(name));
+		for (i, part) in parts.iter().enumerate() {
+			std::fs::write(part, [i as u8; TS_PACKET]).unwrap();
+		}
+		std::fs::write(manifestPath(&parts[0]), "{}").unwrap();
+
+		assert!(joinParts(&parts).await.unwrap().is_empty());
+		let joined = std::fs::read(&parts[0]).unwrap();
+		std::fs::remove_dir_all(&dir).ok();
+		assert_eq!(
+			joined,
+			[[0; TS_PACKET], [1; TS_PACKET], [2; TS_PACKET]].concat()
+		);
+		assert!(!parts[1].exists() && !parts[2].exists());
+		assert!(!manifestPath(&parts[0]).exists());
+	}
+
+	#[tokio::test]
+	async fn leavesPartsAloneWhenJoiningFails() {
+		let dir = std::env::temp_dir().join(format!("ld-record-{}-missing", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		let parts = ["x.ts", "x.part002.ts", "x.part003.ts"].map(|name| dir.join(name));
+		std::fs::write(&parts[0], [0; TS_PACKET]).unwrap();
+		std::fs::write(&parts[1], [1; TS_PACKET]).unwrap();
+
+		assert!(joinParts(&parts).await.is_err());
+		let first = std::fs::read(&parts[0]).unwrap();
+		let second = parts[1].exists();
+		std::fs::remove_dir_all(&dir).ok();
+		assert_eq!(first, [0; TS_PACKET]);
+		assert!(second);
+	}
+
+	#[test]
+	fn clockCountsForwardAcrossTheWrap() {
+		let mut clock = Clock::default();
+		for pts in [PTS_WRAP - 3000, PTS_WRAP - 1500, 1500, 0, 3000] {
+			clock.observe(&packet(0x101, true, None, &pes(pts)), Some(0x101));
+		}
+		// the reordered frame at 0 doesn't count
+		assert_eq!(clock.ticks, 6000);
+		// other streams don't count either
+		clock.observe(&packet(0x102, true, None, &pes(6000)), Some(0x101));
+		assert_eq!(clock.ticks, 6000);
+		// a jump past a discontinuity restarts from the new timestamp
+		clock.observe(&packet(0x101, true, None, &pes(90_000_000)), Some(0x101));
+		clock.observe(&packet(0x101, true, None, &pes(90_003_000)), Some(0x101));
+		assert_eq!(clock.ticks, 9000);
+		assert_eq!(clock.elapsed(), Duration::from_millis(100));
+	}
+}
@@ -0,0 +1,222 @@
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+use serde_json::json;
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+	chat,
+	data::{Config, ReplayComments, ReplayResponse},
+	err::Error,
+};
+
+/// Client id of the twitch web player, gql only serves comments to first party clients
+const WEB_CLIENT_ID: &str = "kimne78kx3ncx6brgo4mv6wki5h1ko";
+/// Persisted `VideoCommentsByOffsetOrCursor` query
+const COMMENTS_QUERY_HASH: &str =
+	"b70a3591ff0f4e0313d126c6a1502d79a1c02baebb288227c582044aa76adf6a";
+
+/// Where pages of vod comments come from
+pub enum Source {
+	/// the live gql api, optionally saving every raw page into `record` for later use as fixtures
+	Gql {
+		c: reqwest::Client,
+		url: String,
+		record: Option<PathBuf>,
+	},
+	/// pages saved earlier, `<dir>/<videoId>.<page>.json`
+	Fixtures(PathBuf),
+}
+
+fn fixturePath(dir: &Path, videoId: &str, page: usize) -> PathBuf {
+	dir.join(format!("{videoId}.{page}.json"))
+}
+
+impl Source {
+	pub fn gql(config: &Config, record: Option<PathBuf>) -> Self {
+		Source::Gql {
+			c: reqwest::Client::new(),
+			url: config
+				.gqlUrl
+				.clone()
+				.unwrap_or(crate::config::DEFAULT_GQL_URL.to_string()),
+			record,
+		}
+	}
+
+	/// Raw json of a page, the first one is asked for by offset and the rest by the cursor of the previous
+	async fn page(&self, videoId: &str, cursor: Option<&str>, page: usize) -> Result<String, Error> {
+		let failed = |err: String| Error::ChatReplay(format!("{videoId} page {page}: {err}"));
+		match self {
+			Source::Fixtures(dir) => tokio::fs::read_to_string(fixturePath(dir, videoId, page))
+				.await
+				.map_err(|err| failed(err.to_string())),
+			Source::Gql { c, url, record } => {
+				let variables = match cursor {
+					Some(cursor) => json!({ "videoID": videoId, "cursor": cursor }),
+					None => json!({ "videoID": videoId, "contentOffsetSeconds": 0 }),
+				};
+				let body = json!({
+					"operationName": "VideoCommentsByOffsetOrCursor",
+					"variables": variables,
+					"extensions": {
+						"persistedQuery": { "version": 1, "sha256Hash": COMMENTS_QUERY_HASH },
+					},
+				});
+				let text = crate::api::try_request(
+					|| async {
+						c.post(url)
+							.header("Client-Id", WEB_CLIENT_ID)
+							.header("Content-Type", "application/json")
+							.body(body.to_string())
+							.send()
+							.await?
+							.error_for_status()?
+							.text()
+							.await
+					},
+					3,
+				)
+				.await
+				.map_err(|err| failed(err.to_string()))?;
+
+				if let Some(dir) = record {
+					tokio::fs::create_dir_all(dir).await.ok();
+					if let Err(err) = tokio::fs::write(fixturePath(dir, videoId, page), &text).await {
+						warn!("[RPLY] failed to save page {page} of {videoId}: {err}");
+					}
+				}
+				Ok(text)
+			}
+		}
+	}
+}
+
+fn parse(videoId: &str, text: &str) -> Result<ReplayComments, Error> {
+	let resp: ReplayResponse = serde_json::from_str(text)
+		.map_err(|err| Error::ChatReplay(format!("{videoId}: unexpected response: {err}")))?;
+	if !resp.errors.is_empty() {
+		return Err(Error::ChatReplay(format!(
+			"{videoId}: {}",
+			serde_json::Value::from(resp.errors)
+		)));
+	}
+
+	resp
+		.data
+		.and_then(|data| data.video)
+		.and_then(|video| video.comments)
+		.ok_or_else(|| Error::ChatReplay(format!("{videoId}: no such video or its chat is gone")))
+}
+
+/// `<video without extension>.chat.log`, shares the retention group of the video
+pub fn path(video: &Path) -> PathBuf {
+	video.with_extension("chat.log")
+}
+
+/// Writes the whole chat of a vod into `out` as the live chat archive would have, returns the message count
+pub async fn fetch(
+	source: &Source,
+	channel: &str,
+	videoId: &str,
+	out: &Path,
+) -> Result<usize, Error> {
+	// a missing video or chat shouldn't leave an empty log behind
+	let mut comments = parse(videoId, &source.page(videoId, None, 0).await?)?;
+
+	if let Some(parent) = out.parent() {
+		tokio::fs::create_dir_all(parent).await.ok();
+	}
+	let io = |err: std::io::Error| Error::ChatReplay(format!("{}: {err}", out.display()));
+	let mut file = tokio::io::BufWriter::new(tokio::fs::File::create(out).await.map_err(io)?);
+
+	let mut count = 0;
+	for page in 1.. {
+		for edge in &comments.edges {
+			let comment = &edge.node;
+			let text = comment
+				.message
+				.fragments
+				.iter()
+				.map(|fragment| fragment.text.as_str())
+				.collect::<String>();
+			let chatter = comment
+				.commenter
+				.as_ref()
+				.map_or("", |commenter| commenter.displayName.as_str());
+			file
+				.write_all(
+					chat::line(comment.createdAt, channel, &chat::message(chatter, &text)).as_bytes(),
+				)
+				.await
+				.map_err(io)?;
+		}
+		count += comments.edges.len();
+
+		let cursor = comments.edges.last().and_then(|edge| edge.cursor.clone());
+		if !comments.pageInfo.hasNextPage || cursor.is_none() {
+			break;
+		}
+		comments = parse(
+			videoId,
+			&source.page(videoId, cursor.as_deref(), page).await?,
+		)?;
+	}
+	file.flush().await.map_err(io)?;
+
+	info!(
+		"[RPLY] {channel} {videoId}: {count} messages into {}",
+		out.display()
+	);
+	Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn fixtures() -> Source {
+		Source::Fixtures("fixtures/replay".into())
+	}
+
+	fn output(name: &str) -> PathBuf {
+		std::env::temp_dir().join(format!("ld-replay-{}-{name}", std::process::id()))
+	}
+
+	#[tokio::test]
+	async fn fetchesEveryPage() {
+		let out = output("2000000001.chat.log");
+		let count = fetch(&fixtures(), "streamer", "2000000001", &out)
+			.await
+			.unwrap();
+		let written = std::fs::read_to_string(&out).unwrap();
+		std::fs::remove_file(&out).ok();
+
+		// two comments on the first page, the third only comes from the page its cursor leads to
+		assert_eq!(count, 3);
+		assert_eq!(
+			written,
+			[
+				"[2026-10-01 18:00:04.120 UTC ld::chat::streamer] [CHAT] \"Viewer_One: first Kappa\"\n",
+				"[2026-10-01 18:00:09.500 UTC ld::chat::streamer] [CHAT] \": message from a deleted account\"\n",
+				"[2026-10-01 19:00:11 UTC ld::chat::streamer] [CHAT] \"viewer_two: quotes \\\"and\\\" unicode ✓\"\n",
+			]
+			.concat()
+		);
+	}
+
+	#[tokio::test]
+	async fn missingChatIsAnError() {
+		let out = output("2000000002.chat.log");
+		let result = fetch(&fixtures(), "streamer", "2000000002", &out).await;
+
+		match result {
+			Err(Error::ChatReplay(message)) => {
+				assert_eq!(message, "2000000002: no such video or its chat is gone")
+			}
+			other => panic!("expected a chat replay error, got {other:?}"),
+		}
+		// no empty log is left behind
+		assert!(!out.exists());
+	}
+}
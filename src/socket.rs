@@ -1,15 +1,54 @@
 use log::{error, trace, warn};
-use twitch_api::eventsub::{Event, EventsubWebsocketData, Message, Payload};
+use twitch_api::eventsub::{
+	Event, EventsubWebsocketData, Message, Payload, channel::chat::Fragment,
+};
 
-use crate::{data::InternalMessage, err::Error};
+use crate::{
+	data::{ChatMessage, InternalMessage},
+	err::Error,
+};
 
 pub struct Client {
 	pub tx: tokio::sync::broadcast::Sender<InternalMessage>,
+	pub chat: tokio::sync::mpsc::UnboundedSender<ChatMessage>,
+	/// eventsub messages to keep, written to the db by `archiveEvents` off the socket task
+	pub events: tokio::sync::mpsc::UnboundedSender<crate::db::Event>,
 	// pub rx: tokio::sync::broadcast::Receiver<InternalMessage>,
 }
 
 impl Client {
+	/// Keeps every eventsub message but keepalives in the state db
+	fn archive(&self, data: &str) {
+		let Ok(frame) = serde_json::from_str::<serde_json::Value>(data) else {
+			return;
+		};
+		let kind = frame["metadata"]["message_type"]
+			.as_str()
+			.unwrap_or("unknown");
+		if kind == "session_keepalive" {
+			return;
+		}
+		let session = frame["payload"]["session"]["id"]
+			.as_str()
+			.or(frame["payload"]["subscription"]["transport"]["session_id"].as_str());
+
+		let event = crate::db::Event {
+			receivedAt: chrono::Utc::now(),
+			session: session.map(str::to_string),
+			kind: kind.to_string(),
+			subscription: frame["metadata"]["subscription_type"]
+				.as_str()
+				.map(str::to_string),
+			payload: data.to_string(),
+		};
+		if self.events.send(event).is_err() {
+			error!("failed to queue eventsub message for the state db");
+		}
+	}
+
 	pub fn processFrame(&self, data: tungstenite::Utf8Bytes) -> Result<(), Error> {
+		crate::metrics::metrics().eventsubMessage();
+		self.archive(&data);
 		if let Err(detail) = twitch_api::eventsub::Event::parse_websocket(&data) {
 			warn!("unexpected socket error {}", detail);
 			return Ok(());
@@ -43,12 +82,22 @@ impl Client {
 					message: Message::Notification(d),
 					..
 				}) => self
-					.tx
-					.send(InternalMessage::Chat {
-						msg: format!("{}: {}", d.chatter_user_name, d.message.text),
+					.chat
+					.send(ChatMessage {
+						msg: crate::chat::message(d.chatter_user_name.as_str(), &d.message.text),
 						channel: d.broadcaster_user_login.to_string(),
+						emotes: d
+							.message
+							.fragments
+							.iter()
+							.filter_map(|fragment| match fragment {
+								Fragment::Emote { text, emote } => Some((emote.id.to_string(), text.clone())),
+								_ => None,
+							})
+							.collect(),
 					})
-					.map_err(|detail| error!("failed to broadcast chat message: {detail}")),
+					.map(|_| 1)
+					.map_err(|detail| error!("failed to pass on chat message: {detail}")),
 
 				_ => self
 					.tx
@@ -0,0 +1,507 @@
+use std::{
+	collections::{BTreeMap, HashMap, HashSet},
+	path::{Path, PathBuf},
+	sync::Mutex,
+	time::{Duration, SystemTime},
+};
+
+use log::{debug, error, info, warn};
+
+use crate::data::{Config, RecordingManifest, RetentionPolicy, StorageConfig};
+
+pub const DEFAULT_WARN_FREE_GB: f64 = 10.0;
+pub const DEFAULT_MIN_FREE_GB: f64 = 1.0;
+pub const DEFAULT_CHECK_INTERVAL: u64 = 60;
+
+const GB: f64 = 1024.0 * 1024.0 * 1024.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpaceLevel {
+	Ok,
+	Low,
+	Critical,
+}
+
+/// Guards free space under `root` and applies retention policies to the recordings of each channel
+pub struct Storage {
+	root: PathBuf,
+	config: StorageConfig,
+	/// recordings are told apart by channel through the names it gives them
+	template: String,
+	/// recordings currently being written, never touched by retention
+	active: Mutex<HashSet<PathBuf>>,
+}
+
+/// A recording and its sidecars, see `groupKey`
+#[derive(Debug)]
+struct Group {
+	files: Vec<PathBuf>,
+	size: u64,
+	modified: SystemTime,
+}
+
+/// Suffixes this tool puts between a recording's name and the extension of its parts and sidecars
+fn isKnownSuffix(suffix: &str) -> bool {
+	match suffix.strip_prefix("part") {
+		Some(number) => number.len() == 3 && number.bytes().all(|b| b.is_ascii_digit()),
+		None => matches!(suffix, "manifest" | "chat" | "catchup"),
+	}
+}
+
+/// Name of the recording a file belongs to: the extension and the known suffixes before it stripped,
+/// so `x.part002.ts`, `x.manifest.json`, `x.chat.ass` and `x.catchup.ts` all group under `x`
+/// while dots in a title stay part of the name
+fn groupKey(path: &Path) -> PathBuf {
+	let name = path
+		.file_name()
+		.map(|name| name.to_string_lossy().to_string())
+		.unwrap_or_default();
+	// backfill downloads land under `.partial` until they're done
+	let name = name.strip_suffix(".partial").unwrap_or(&name);
+	let mut stem = name.rsplit_once('.').map_or(name, |(stem, _)| stem);
+	while let Some((rest, suffix)) = stem.rsplit_once('.') {
+		if !isKnownSuffix(suffix) {
+			break;
+		}
+		stem = rest;
+	}
+
+	path.with_file_name(stem)
+}
+
+/// Group keys of parts that don't share the stem of their recording, taken from the manifests among `files`;
+/// a `{part}` template names every part differently
+fn manifestGroups(files: &[PathBuf]) -> HashMap<PathBuf, PathBuf> {
+	let mut groups = HashMap::new();
+	for manifest in files
+		.iter()
+		.filter(|file| file.to_string_lossy().ends_with(".manifest.json"))
+	{
+		let Some(parsed) = std::fs::read(manifest)
+			.ok()
+			.and_then(|bytes| serde_json::from_slice::<RecordingManifest>(&bytes).ok())
+		else {
+			continue;
+		};
+		let key = groupKey(manifest);
+		for part in parsed.parts {
+			if let Some(name) = Path::new(&part.path).file_name() {
+				groups.insert(groupKey(&manifest.with_file_name(name)), key.clone());
+			}
+		}
+	}
+
+	groups
+}
+
+fn keyOf(manifests: &HashMap<PathBuf, PathBuf>, path: &Path) -> PathBuf {
+	let key = groupKey(path);
+	manifests.get(&key).cloned().unwrap_or(key)
+}
+
+/// Every file below `dir`, folders in `skip` left out
+fn collectFiles(dir: &Path, skip: &HashSet<PathBuf>, out: &mut Vec<PathBuf>) {
+	let Ok(entries) = std::fs::read_dir(dir) else {
+		return;
+	};
+	for entry in entries.flatten() {
+		let path = entry.path();
+		if skip.contains(&path) {
+			continue;
+		} else if path.is_dir() {
+			collectFiles(&path, skip, out);
+		} else {
+			out.push(path);
+		}
+	}
+}
+
+/// Every file belonging to the recordings the given parts are in, sidecars included
+pub fn recordingFiles(parts: &[PathBuf]) -> Vec<PathBuf> {
+	let dirs = parts
+		.iter()
+		.filter_map(|part| part.parent())
+		.collect::<HashSet<_>>();
+	let candidates = dirs
+		.into_iter()
+		.filter_map(|dir| std::fs::read_dir(dir).ok())
+		.flat_map(|entries| entries.flatten().map(|entry| entry.path()))
+		.filter(|path| path.is_file())
+		.collect::<Vec<_>>();
+
+	let manifests = manifestGroups(&candidates);
+	let keys = parts
+		.iter()
+		.map(|part| keyOf(&manifests, part))
+		.collect::<HashSet<_>>();
+	let mut files = candidates
+		.into_iter()
+		.filter(|path| keys.contains(&keyOf(&manifests, path)))
+		.collect::<Vec<_>>();
+	files.sort();
+
+	files
+}
+
+/// Moves across filesystems as well, where a plain rename fails
+fn moveFile(from: &Path, to: &Path) -> std::io::Result<()> {
+	if let Some(parent) = to.parent() {
+		std::fs::create_dir_all(parent)?;
+	}
+	if std::fs::rename(from, to).is_ok() {
+		return Ok(());
+	}
+	std::fs::copy(from, to)?;
+	std::fs::remove_file(from)
+}
+
+impl Storage {
+	pub fn new(config: &Config) -> Self {
+		Storage {
+			root: PathBuf::from(&config.root),
+			config: config.storage.clone().unwrap_or_default(),
+			template: config
+				.filenameTemplate
+				.clone()
+				.unwrap_or_else(|| crate::template::DEFAULT_TEMPLATE.to_string()),
+			active: Mutex::new(HashSet::new()),
+		}
+	}
+
+	pub fn checkInterval(&self) -> Duration {
+		Duration::from_secs(self.config.checkInterval.unwrap_or(DEFAULT_CHECK_INTERVAL))
+	}
+
+	/// Free bytes on the filesystem holding root, walks up to the closest existing folder
+	pub fn freeBytes(&self) -> std::io::Result<u64> {
+		let mut path = self.root.as_path();
+		while !path.exists() {
+			path = path.parent().unwrap_or(Path::new("."));
+		}
+		fs2::available_space(path)
+	}
+
+	pub fn level(&self, free: u64) -> SpaceLevel {
+		let minFree = self.config.minFreeGb.unwrap_or(DEFAULT_MIN_FREE_GB) * GB;
+		let warnFree = self.config.warnFreeGb.unwrap_or(DEFAULT_WARN_FREE_GB) * GB;
+
+		match free as f64 {
+			free if free < minFree => SpaceLevel::Critical,
+			free if free < warnFree => SpaceLevel::Low,
+			_ => SpaceLevel::Ok,
+		}
+	}
+
+	/// Refuses new recordings below the hard floor, returns free bytes in both cases
+	pub fn canRecord(&self) -> Result<u64, u64> {
+		match self.freeBytes() {
+			Ok(free) if self.level(free) == SpaceLevel::Critical => Err(free),
+			Ok(free) => Ok(free),
+			Err(err) => {
+				// rather record than miss a stream over a failed stat
+				warn!("[DISK] failed to check free space: {err}");
+				Ok(0)
+			}
+		}
+	}
+
+	pub fn begin(&self, path: &Path) {
+		self.active.lock().unwrap().insert(path.to_path_buf());
+	}
+
+	pub fn finish(&self, path: &Path) {
+		self.active.lock().unwrap().remove(path);
+	}
+
+	fn policy(&self, channel: &str) -> Option<&RetentionPolicy> {
+		let retention = self.config.retention.as_ref()?;
+		retention.get(channel).or(retention.get("*"))
+	}
+
+	/// Applies retention to every given channel, the ones watched right now
+	pub fn enforceRetentionAll(&self, channels: &[String]) {
+		let mut groups = self.groups(channels);
+		for channel in channels {
+			let Some(policy) = self.policy(channel) else {
+				continue;
+			};
+			self.expire(channel, policy, groups.remove(channel).unwrap_or_default());
+		}
+	}
+
+	/// Deletes or moves the oldest recordings of the channel until its policy is satisfied
+	pub fn enforceRetention(&self, channel: &str) {
+		self.enforceRetentionAll(&[channel.to_string()]);
+	}
+
+	/// Channel a recording belongs to: the one its name was rendered with, or the folder below root it sits in,
+	/// which also covers backfilled videos
+	fn channelOf(&self, key: &Path, channels: &[String]) -> Option<String> {
+		let relative = key.strip_prefix(&self.root).ok()?;
+		if let Some(channel) = crate::template::channelOf(&self.template, &relative.to_string_lossy()) {
+			return Some(channel);
+		}
+		let first = relative.components().next()?.as_os_str().to_string_lossy();
+		channels.iter().find(|channel| **channel == first).cloned()
+	}
+
+	/// Finished recordings under root by channel, a recording being written is left out as a whole
+	fn groups(&self, channels: &[String]) -> HashMap<String, Vec<Group>> {
+		// moved recordings are out of retention, even when moved somewhere below root
+		let skip = self
+			.config
+			.retention
+			.iter()
+			.flatten()
+			.filter_map(|(_, policy)| policy.moveTo.as_ref())
+			.map(PathBuf::from)
+			.collect::<HashSet<_>>();
+		let mut files = Vec::new();
+		collectFiles(&self.root, &skip, &mut files);
+
+		let manifests = manifestGroups(&files);
+		let activeKeys = self
+			.active
+			.lock()
+			.unwrap()
+			.iter()
+			.map(|path| keyOf(&manifests, path))
+			.collect::<HashSet<_>>();
+
+		let mut groups: BTreeMap<PathBuf, Group> = BTreeMap::new();
+		for file in files {
+			let key = keyOf(&manifests, &file);
+			if activeKeys.contains(&key) {
+				continue;
+			}
+			let Ok(meta) = std::fs::metadata(&file) else {
+				continue;
+			};
+			let modified = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+			let group = groups.entry(key).or_insert(Group {
+				files: Vec::new(),
+				size: 0,
+				modified,
+			});
+			group.files.push(file);
+			group.size += meta.len();
+			group.modified = group.modified.max(modified);
+		}
+
+		let mut byChannel: HashMap<String, Vec<Group>> = HashMap::new();
+		for (key, group) in groups {
+			if let Some(channel) = self.channelOf(&key, channels) {
+				byChannel.entry(channel).or_default().push(group);
+			}
+		}
+
+		byChannel
+	}
+
+	fn expire(&self, channel: &str, policy: &RetentionPolicy, mut groups: Vec<Group>) {
+		// newest first, everything past the point a rule is violated goes
+		groups.sort_by_key(|group| std::cmp::Reverse(group.modified));
+
+		let maxAge = policy
+			.maxAgeDays
+			.map(|days| Duration::from_secs(days * 24 * 3600));
+		let maxTotal = policy.maxTotalGb.map(|gb| (gb * GB) as u64);
+		let now = SystemTime::now();
+		let mut total = 0u64;
+
+		for (i, group) in groups.iter().enumerate() {
+			total += group.size;
+			let tooMany = policy.keepLast.is_some_and(|keep| i >= keep);
+			let tooOld = maxAge.is_some_and(|maxAge| {
+				now
+					.duration_since(group.modified)
+					.is_ok_and(|age| age > maxAge)
+			});
+			let tooBig = maxTotal.is_some_and(|maxTotal| total > maxTotal);
+			if !(tooMany || tooOld || tooBig) {
+				continue;
+			}
+
+			debug!(
+				"[RTNT] {channel}: expiring {:?} (count: {tooMany}, age: {tooOld}, size: {tooBig})",
+				group.files
+			);
+			for file in &group.files {
+				let result = match &policy.moveTo {
+					Some(target) => {
+						let relative = file.strip_prefix(&self.root).unwrap_or(file);
+						moveFile(file, &PathBuf::from(target).join(relative))
+					}
+					None => std::fs::remove_file(file),
+				};
+				match result {
+					Ok(()) => info!("[RTNT] {channel}: removed {}", file.display()),
+					Err(err) => error!(
+						"[RTNT] {channel}: failed to remove {}: {err}",
+						file.display()
+					),
+				}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn storage(name: &str, template: &str, policy: RetentionPolicy) -> Storage {
+		let root = std::env::temp_dir().join(format!("ld-storage-{}-{name}", std::process::id()));
+		std::fs::remove_dir_all(&root).ok();
+		std::fs::create_dir_all(&root).unwrap();
+		Storage {
+			root,
+			config: StorageConfig {
+				retention: Some(HashMap::from([("*".to_string(), policy)])),
+				..Default::default()
+			},
+			template: template.to_string(),
+			active: Mutex::new(HashSet::new()),
+		}
+	}
+
+	/// Writes the file under root, `age` hours old
+	fn file(storage: &Storage, name: &str, age: u64) -> PathBuf {
+		let path = storage.root.join(name);
+		std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+		let file = std::fs::File::create(&path).unwrap();
+		file
+			.set_modified(SystemTime::now() - Duration::from_secs(age * 3600))
+			.unwrap();
+		path
+	}
+
+	fn keepLast(keep: usize) -> RetentionPolicy {
+		RetentionPolicy {
+			keepLast: Some(keep),
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn groupsPartsAndSidecars() {
+		for (file, key) in [
+			("a/x.ts", "a/x"),
+			("a/x.part002.ts", "a/x"),
+			("a/x.manifest.json", "a/x"),
+			("a/x.chat.ass", "a/x"),
+			("a/x.catchup.ts", "a/x"),
+			("a/x.ts.partial", "a/x"),
+			("a/v1.2 final.ts", "a/v1.2 final"),
+			("a/x.part2.ts", "a/x.part2"),
+		] {
+			assert_eq!(groupKey(Path::new(file)), PathBuf::from(key), "{file}");
+		}
+	}
+
+	#[test]
+	fn groupsPartsListedInTheManifest() {
+		let storage = storage("manifest", "{login}/{utc}.ts", keepLast(1));
+		let manifest = file(&storage, "a/first.manifest.json", 1);
+		std::fs::write(
+			&manifest,
+			serde_json::json!({
+				"channel": "a",
+				"streamId": "1",
+				"title": "",
+				"category": "",
+				"startedAt": "2024-01-01T00:00:00Z",
+				"chat": null,
+				"parts": [
+					{ "part": 1, "path": "a/first.ts", "bytes": 0, "startedAt": "2024-01-01T00:00:00Z", "endedAt": null },
+					{ "part": 2, "path": "a/second.ts", "bytes": 0, "startedAt": "2024-01-01T01:00:00Z", "endedAt": null },
+				],
+			})
+			.to_string(),
+		)
+		.unwrap();
+		let files = [
+			manifest.clone(),
+			file(&storage, "a/first.ts", 1),
+			file(&storage, "a/second.ts", 1),
+		];
+
+		let manifests = manifestGroups(&files);
+		for file in &files {
+			assert_eq!(keyOf(&manifests, file), storage.root.join("a/first"));
+		}
+		std::fs::remove_dir_all(&storage.root).ok();
+	}
+
+	#[test]
+	fn keepsTheNewestOfEachChannel() {
+		let storage = storage("template", "{utc}_{login}.ts", keepLast(1));
+		let oldA = file(&storage, "1_alpha.ts", 3);
+		let oldChat = file(&storage, "1_alpha.chat.ass", 3);
+		let newA = file(&storage, "2_alpha.ts", 1);
+		let oldB = file(&storage, "1_beta.ts", 2);
+		let newB = file(&storage, "2_beta.ts", 1);
+		// not a recording of a watched channel
+		let other = file(&storage, "1_gamma.ts", 5);
+
+		storage.enforceRetentionAll(&["alpha".to_string(), "beta".to_string()]);
+		assert!(!oldA.exists());
+		assert!(!oldChat.exists());
+		assert!(newA.exists());
+		assert!(!oldB.exists());
+		assert!(newB.exists());
+		assert!(other.exists());
+		std::fs::remove_dir_all(&storage.root).ok();
+	}
+
+	#[test]
+	fn countsBackfilledVideos() {
+		let storage = storage("backfill", "{utc}_{login}.ts", keepLast(1));
+		let live = file(&storage, "3_alpha.ts", 1);
+		let archive = file(&storage, "alpha/archive/2024_123.ts", 2);
+
+		storage.enforceRetention("alpha");
+		assert!(live.exists());
+		assert!(!archive.exists());
+		std::fs::remove_dir_all(&storage.root).ok();
+	}
+
+	#[test]
+	fn skipsActiveRecordings() {
+		let storage = storage("active", "{login}/{utc}.ts", keepLast(0));
+		let active = file(&storage, "alpha/1.part001.ts", 2);
+		let sidecar = file(&storage, "alpha/1.chat.log", 2);
+		let done = file(&storage, "alpha/0.ts", 3);
+		storage.begin(&storage.root.join("alpha/1.ts"));
+
+		storage.enforceRetention("alpha");
+		assert!(active.exists());
+		assert!(sidecar.exists());
+		assert!(!done.exists());
+		std::fs::remove_dir_all(&storage.root).ok();
+	}
+
+	#[test]
+	fn leavesMovedRecordingsAlone() {
+		let mut storage = storage("move", "{login}/{utc}.ts", keepLast(1));
+		let target = storage.root.join("archive");
+		storage
+			.config
+			.retention
+			.as_mut()
+			.unwrap()
+			.get_mut("*")
+			.unwrap()
+			.moveTo = Some(target.to_string_lossy().to_string());
+		let old = file(&storage, "alpha/1.ts", 2);
+		file(&storage, "alpha/2.ts", 1);
+
+		storage.enforceRetention("alpha");
+		assert!(!old.exists());
+		assert!(target.join("alpha/1.ts").exists());
+		// a second pass doesn't count what was moved already
+		storage.enforceRetention("alpha");
+		assert!(target.join("alpha/1.ts").exists());
+		std::fs::remove_dir_all(&storage.root).ok();
+	}
+}
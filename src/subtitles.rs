@@ -0,0 +1,648 @@
+use std::{
+	fmt::Write,
+	io::BufRead,
+	path::{Path, PathBuf},
+	process::Stdio,
+};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use log::{error, info, warn};
+
+use crate::{
+	catchup,
+	data::{RecordingGap, RecordingManifest, SubtitleConfig, SubtitleFormat, SubtitlePosition},
+	record,
+	template::RecordingInfo,
+};
+
+pub const DEFAULT_MESSAGE_SECONDS: f64 = 8.0;
+pub const DEFAULT_MAX_LINES: usize = 6;
+
+/// Colours twitch hands out to users who never picked one
+const PALETTE: &[&str] = &[
+	"#FF0000", "#0000FF", "#008000", "#B22222", "#FF7F50", "#9ACD32", "#FF4500", "#2E8B57",
+	"#DAA520", "#D2691E", "#5F9EA0", "#1E90FF", "#FF69B4", "#8A2BE2", "#00FF7F",
+];
+
+/// A message of the chat archive
+#[derive(Debug, Clone)]
+pub struct ChatLine {
+	pub time: DateTime<Utc>,
+	pub chatter: String,
+	pub text: String,
+}
+
+/// Reverses the `{:?}` escaping chat lines are written with
+fn unescape(quoted: &str) -> Option<String> {
+	let inner = quoted.strip_prefix('"')?.strip_suffix('"')?;
+	let mut out = String::with_capacity(inner.len());
+	let mut chars = inner.chars();
+	while let Some(c) = chars.next() {
+		if c != '\\' {
+			out.push(c);
+			continue;
+		}
+		match chars.next()? {
+			'n' => out.push('\n'),
+			'r' => out.push('\r'),
+			't' => out.push('\t'),
+			'0' => out.push('\0'),
+			'u' => {
+				let hex = chars
+					.by_ref()
+					.skip(1)
+					.take_while(|c| *c != '}')
+					.collect::<String>();
+				out.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+			}
+			other => out.push(other),
+		}
+	}
+
+	Some(out)
+}
+
+/// Reads back a line written by `chat::line`
+pub fn parseLine(line: &str) -> Option<ChatLine> {
+	let (time, rest) = line.strip_prefix('[')?.split_once(" UTC ld::chat::")?;
+	let (_channel, message) = rest.split_once("] [CHAT] ")?;
+	let message = unescape(message.trim_end())?;
+	let (chatter, text) = message.split_once(": ")?;
+
+	Some(ChatLine {
+		time: NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M:%S%.f")
+			.ok()?
+			.and_utc(),
+		chatter: chatter.to_string(),
+		text: text.to_string(),
+	})
+}
+
+/// Messages of a chat log within `[from, to)`
+pub fn readChat(
+	path: &Path,
+	from: DateTime<Utc>,
+	to: DateTime<Utc>,
+) -> std::io::Result<Vec<ChatLine>> {
+	let file = std::io::BufReader::new(std::fs::File::open(path)?);
+	let mut lines = file
+		.lines()
+		.map_while(Result::ok)
+		.filter_map(|line| parseLine(&line))
+		.filter(|line| line.time >= from && line.time < to)
+		.collect::<Vec<_>>();
+	lines.sort_by_key(|line| line.time);
+
+	Ok(lines)
+}
+
+/// Chat on the timeline of a video the `holes` were cut out of, messages sent during one are dropped
+fn closeHoles(lines: Vec<ChatLine>, holes: &[RecordingGap]) -> Vec<ChatLine> {
+	lines
+		.into_iter()
+		.filter_map(|mut line| {
+			let mut shift = chrono::Duration::zero();
+			for hole in holes {
+				if line.time >= hole.to {
+					shift += hole.to - hole.from;
+				} else if line.time >= hole.from {
+					return None;
+				}
+			}
+			line.time -= shift;
+			Some(line)
+		})
+		.collect()
+}
+
+fn colour(chatter: &str) -> &'static str {
+	let hash = chatter.bytes().fold(0usize, |hash, byte| {
+		hash.wrapping_mul(31).wrapping_add(byte as usize)
+	});
+	PALETTE[hash % PALETTE.len()]
+}
+
+/// Stretches of time with what's on screen during them, in seconds from `start`
+fn frames<'a>(
+	settings: &SubtitleConfig,
+	lines: &'a [ChatLine],
+	start: DateTime<Utc>,
+) -> Vec<(f64, f64, &'a [ChatLine])> {
+	let duration = settings.messageSeconds.unwrap_or(DEFAULT_MESSAGE_SECONDS);
+	let maxLines = settings.maxLines.unwrap_or(DEFAULT_MAX_LINES);
+	let offsets = lines
+		.iter()
+		.map(|line| (line.time - start).num_milliseconds() as f64 / 1000.0)
+		.collect::<Vec<_>>();
+
+	// the screen only changes when a message comes in or runs out
+	let mut points = offsets
+		.iter()
+		.flat_map(|offset| [*offset, offset + duration])
+		.collect::<Vec<_>>();
+	points.sort_by(f64::total_cmp);
+	points.dedup();
+
+	let mut frames: Vec<(f64, f64, &[ChatLine])> = Vec::new();
+	for window in points.windows(2) {
+		let (from, to) = (window[0], window[1]);
+		let shown = offsets.partition_point(|offset| *offset <= from);
+		let expired = offsets.partition_point(|offset| *offset <= from - duration);
+		let first = expired.max(shown.saturating_sub(maxLines));
+		if first >= shown {
+			continue;
+		}
+		// a message expiring out of view of `maxLines` leaves the screen as it was
+		match frames.last_mut() {
+			Some(last) if last.1 == from && std::ptr::eq(last.2, &lines[first..shown]) => last.1 = to,
+			_ => frames.push((from, to, &lines[first..shown])),
+		}
+	}
+
+	frames
+}
+
+fn assTime(seconds: f64) -> String {
+	let centis = (seconds * 100.0).round() as u64;
+	format!(
+		"{}:{:02}:{:02}.{:02}",
+		centis / 360000,
+		centis / 6000 % 60,
+		centis / 100 % 60,
+		centis % 100
+	)
+}
+
+fn vttTime(seconds: f64) -> String {
+	let millis = (seconds * 1000.0).round() as u64;
+	format!(
+		"{:02}:{:02}:{:02}.{:03}",
+		millis / 3600000,
+		millis / 60000 % 60,
+		millis / 1000 % 60,
+		millis % 1000
+	)
+}
+
+/// Override blocks and escapes can't be switched off in ass, braces and backslashes are defused instead
+fn assText(text: &str) -> String {
+	text
+		.replace('\\', "\\\u{200b}")
+		.replace('{', "(")
+		.replace('}', ")")
+		.replace(['\n', '\r'], " ")
+}
+
+fn renderAss(settings: &SubtitleConfig, frames: &[(f64, f64, &[ChatLine])]) -> String {
+	// numpad layout, chat keeps to a column on its side of the screen
+	let (alignment, marginL, marginR) = match settings.position {
+		SubtitlePosition::TopLeft => (7, 30, 1200),
+		SubtitlePosition::TopRight => (9, 1200, 30),
+		SubtitlePosition::BottomLeft => (1, 30, 1200),
+		SubtitlePosition::BottomRight => (3, 1200, 30),
+	};
+	let mut out = format!(
+		"[Script Info]
+ScriptType: v4.00+
+PlayResX: 1920
+PlayResY: 1080
+WrapStyle: 0
+ScaledBorderAndShadow: yes
+
+[V4+ Styles]
+Format: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding
+Style: Chat,Arial,36,&H00FFFFFF,&H00FFFFFF,&H00000000,&H80000000,0,0,0,0,100,100,0,0,1,2,0,{alignment},{marginL},{marginR},30,1
+
+[Events]
+Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text
+"
+	);
+	let colors = settings.colors.unwrap_or(true);
+	for (from, to, lines) in frames {
+		let text = lines
+			.iter()
+			.map(|line| match colors {
+				true => {
+					// ass colours are &HBBGGRR&
+					let hex = colour(&line.chatter);
+					format!(
+						"{{\\c&H{}{}{}&}}{}{{\\r}}: {}",
+						&hex[5..7],
+						&hex[3..5],
+						&hex[1..3],
+						assText(&line.chatter),
+						assText(&line.text)
+					)
+				}
+				false => format!("{}: {}", assText(&line.chatter), assText(&line.text)),
+			})
+			.collect::<Vec<_>>()
+			.join("\\N");
+		writeln!(
+			out,
+			"Dialogue: 0,{},{},Chat,,0,0,0,,{text}",
+			assTime(*from),
+			assTime(*to)
+		)
+		.unwrap();
+	}
+
+	out
+}
+
+fn vttText(text: &str) -> String {
+	text
+		.replace('&', "&amp;")
+		.replace('<', "&lt;")
+		.replace('>', "&gt;")
+		.replace(['\n', '\r'], " ")
+}
+
+fn renderVtt(settings: &SubtitleConfig, frames: &[(f64, f64, &[ChatLine])]) -> String {
+	let placement = match settings.position {
+		SubtitlePosition::TopLeft => "line:0 position:2% align:left size:35%",
+		SubtitlePosition::TopRight => "line:0 position:98% align:right size:35%",
+		SubtitlePosition::BottomLeft => "line:-1 position:2% align:left size:35%",
+		SubtitlePosition::BottomRight => "line:-1 position:98% align:right size:35%",
+	};
+	let colors = settings.colors.unwrap_or(true);
+
+	let mut out = "WEBVTT\n\n".to_string();
+	if colors {
+		out.push_str("STYLE\n");
+		for (i, hex) in PALETTE.iter().enumerate() {
+			writeln!(out, "::cue(.c{i}) {{ color: {hex}; }}").unwrap();
+		}
+		out.push('\n');
+	}
+	for (from, to, lines) in frames {
+		writeln!(out, "{} --> {} {placement}", vttTime(*from), vttTime(*to)).unwrap();
+		for line in lines.iter() {
+			match colors {
+				true => {
+					let class = PALETTE
+						.iter()
+						.position(|hex| *hex == colour(&line.chatter))
+						.unwrap();
+					writeln!(
+						out,
+						"<c.c{class}>{}</c>: {}",
+						vttText(&line.chatter),
+						vttText(&line.text)
+					)
+				}
+				false => writeln!(out, "{}: {}", vttText(&line.chatter), vttText(&line.text)),
+			}
+			.unwrap();
+		}
+		out.push('\n');
+	}
+
+	out
+}
+
+/// Subtitle track of the chat lines, timed from `start`
+pub fn render(settings: &SubtitleConfig, lines: &[ChatLine], start: DateTime<Utc>) -> String {
+	let frames = frames(settings, lines, start);
+	match settings.format {
+		SubtitleFormat::Ass => renderAss(settings, &frames),
+		SubtitleFormat::Vtt => renderVtt(settings, &frames),
+	}
+}
+
+/// `<video without extension>.chat.ass` or `.chat.vtt`, shares the retention group of the video
+pub fn path(video: &Path, format: SubtitleFormat) -> PathBuf {
+	video.with_extension(match format {
+		SubtitleFormat::Ass => "chat.ass",
+		SubtitleFormat::Vtt => "chat.vtt",
+	})
+}
+
+/// Copies the video and subtitle into `<video>.mkv` and removes the original video on success
+async fn mux(video: &Path, subtitles: &Path) -> Option<PathBuf> {
+	let out = video.with_extension("mkv");
+	let status = tokio::process::Command::new("ffmpeg")
+		.args(["-hide_banner", "-loglevel", "error", "-y", "-i"])
+		.arg(video)
+		.arg("-i")
+		.arg(subtitles)
+		.args([
+			"-map",
+			"0",
+			"-map",
+			"1",
+			"-c",
+			"copy",
+			"-metadata:s:s:0",
+			"title=chat",
+		])
+		.arg(&out)
+		.stdout(Stdio::null())
+		.status()
+		.await;
+
+	match status {
+		Ok(status) if status.success() => {
+			if let Err(err) = tokio::fs::remove_file(video).await {
+				warn!("[SUBS] failed to remove {}: {err}", video.display());
+			}
+			Some(out)
+		}
+		Ok(status) => {
+			error!("[SUBS] ffmpeg {status} muxing {}", video.display());
+			tokio::fs::remove_file(&out).await.ok();
+			None
+		}
+		Err(err) => {
+			error!("[SUBS] failed to run ffmpeg: {err}");
+			None
+		}
+	}
+}
+
+/// Writes the chat of `[start, end)` next to the video and muxes it in when configured;
+/// returns the video path, which is the mkv after muxing
+pub async fn forVideo(
+	settings: &SubtitleConfig,
+	chat: &Path,
+	video: &Path,
+	start: DateTime<Utc>,
+	end: DateTime<Utc>,
+) -> PathBuf {
+	withHoles(settings, chat, video, start, end, &[]).await
+}
+
+/// `forVideo` for a video the `holes` are missing from
+async fn withHoles(
+	settings: &SubtitleConfig,
+	chat: &Path,
+	video: &Path,
+	start: DateTime<Utc>,
+	end: DateTime<Utc>,
+	holes: &[RecordingGap],
+) -> PathBuf {
+	let lines = {
+		let file = chat.to_path_buf();
+		match tokio::task::spawn_blocking(move || readChat(&file, start, end)).await {
+			Ok(Ok(lines)) => lines,
+			Ok(Err(err)) => {
+				warn!("[SUBS] failed to read {}: {err}", chat.display());
+				return video.to_path_buf();
+			}
+			Err(_) => return video.to_path_buf(),
+		}
+	};
+	let lines = closeHoles(lines, holes);
+	if lines.is_empty() {
+		info!("[SUBS] no chat for {}", video.display());
+		return video.to_path_buf();
+	}
+
+	let subtitles = path(video, settings.format);
+	if let Err(err) = tokio::fs::write(&subtitles, render(settings, &lines, start)).await {
+		error!("[SUBS] failed to write {}: {err}", subtitles.display());
+		return video.to_path_buf();
+	}
+	info!(
+		"[SUBS] {} messages into {}",
+		lines.len(),
+		subtitles.display()
+	);
+
+	match settings.mux.unwrap_or(false) {
+		true => mux(video, &subtitles)
+			.await
+			.unwrap_or_else(|| video.to_path_buf()),
+		false => video.to_path_buf(),
+	}
+}
+
+/// Subtitles for every live part of a finished recording, timed from when each part's first frame aired
+/// with the `holes` of a joined recording left out; muxed parts are swapped in `parts` and the manifest
+pub async fn forRecording(
+	settings: &SubtitleConfig,
+	chat: &Path,
+	info: &RecordingInfo,
+	holes: &[RecordingGap],
+	parts: &mut [PathBuf],
+) {
+	let Some(first) = parts.iter().find(|path| !catchup::isCatchUp(path)).cloned() else {
+		return;
+	};
+	let manifestFile = record::manifestPath(&first);
+	let mut manifest = tokio::fs::read(&manifestFile)
+		.await
+		.ok()
+		.and_then(|bytes| serde_json::from_slice::<RecordingManifest>(&bytes).ok());
+
+	let mut changed = false;
+	for path in parts.iter_mut().filter(|path| !catchup::isCatchUp(path)) {
+		let listed = path.to_string_lossy().to_string();
+		let part = manifest
+			.as_mut()
+			.and_then(|manifest| manifest.parts.iter_mut().find(|part| part.path == listed));
+		let (start, end) = match &part {
+			Some(part) => (part.startedAt, part.endedAt.unwrap_or_else(Utc::now)),
+			// a single file begins with its first frame, that's the stream start when streamlink rewound to it
+			None => (info.mediaStartedAt.unwrap_or(info.startedAt), Utc::now()),
+		};
+
+		let video = withHoles(settings, chat, path, start, end, holes).await;
+		if video != *path {
+			if let Some(part) = part {
+				part.path = video.to_string_lossy().to_string();
+				changed = true;
+			}
+			*path = video;
+		}
+	}
+
+	if let Some(manifest) = manifest.filter(|_| changed) {
+		let text = serde_json::to_string_pretty(&manifest).unwrap();
+		if let Err(err) = tokio::fs::write(&manifestFile, text).await {
+			error!("[SUBS] failed to update {}: {err}", manifestFile.display());
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn at(seconds: i64) -> DateTime<Utc> {
+		"2024-05-01T20:00:00Z".parse::<DateTime<Utc>>().unwrap() + chrono::Duration::seconds(seconds)
+	}
+
+	fn line(seconds: i64, chatter: &str, text: &str) -> ChatLine {
+		ChatLine {
+			time: at(seconds),
+			chatter: chatter.to_string(),
+			text: text.to_string(),
+		}
+	}
+
+	fn plain(format: SubtitleFormat) -> SubtitleConfig {
+		SubtitleConfig {
+			format,
+			colors: Some(false),
+			..Default::default()
+		}
+	}
+
+	/// Cue lines of a rendered track, headers left out
+	fn cues(track: &str) -> Vec<&str> {
+		track
+			.lines()
+			.filter(|line| line.starts_with("Dialogue:") || line.contains(" --> "))
+			.collect()
+	}
+
+	#[test]
+	fn readsBackArchivedLines() {
+		let written = crate::chat::line(
+			at(0),
+			"alpha",
+			&crate::chat::message("bob", "a \"quote\"\n\\ é"),
+		);
+		let line = parseLine(written.trim_end()).unwrap();
+		assert_eq!(line.time, at(0));
+		assert_eq!(line.chatter, "bob");
+		assert_eq!(line.text, "a \"quote\"\n\\ é");
+	}
+
+	#[test]
+	fn defusesAssOverrides() {
+		let track = render(
+			&plain(SubtitleFormat::Ass),
+			&[line(1, "{bob}", "{\\an8}big\\Nline\nnext")],
+			at(0),
+		);
+		let text = cues(&track)[0].split(",,").last().unwrap();
+		assert_eq!(text, "(bob): (\\\u{200b}an8)big\\\u{200b}Nline next");
+	}
+
+	#[test]
+	fn escapesVttMarkup() {
+		let track = render(
+			&plain(SubtitleFormat::Vtt),
+			&[line(1, "bob", "<b>a & b</b> --> c")],
+			at(0),
+		);
+		assert!(track.contains("\nbob: &lt;b&gt;a &amp; b&lt;/b&gt; --&gt; c\n"));
+		assert_eq!(cues(&track).len(), 1);
+	}
+
+	#[test]
+	fn timesCuesFromTheStart() {
+		let settings = SubtitleConfig {
+			messageSeconds: Some(5.0),
+			..plain(SubtitleFormat::Ass)
+		};
+		let lines = [
+			line(62, "a", "one"),
+			line(64, "b", "two"),
+			line(3725, "c", "three"),
+		];
+		let track = render(&settings, &lines, at(60));
+		assert_eq!(
+			cues(&track),
+			[
+				"Dialogue: 0,0:00:02.00,0:00:04.00,Chat,,0,0,0,,a: one",
+				"Dialogue: 0,0:00:04.00,0:00:07.00,Chat,,0,0,0,,a: one\\Nb: two",
+				"Dialogue: 0,0:00:07.00,0:00:09.00,Chat,,0,0,0,,b: two",
+				"Dialogue: 0,1:01:05.00,1:01:10.00,Chat,,0,0,0,,c: three",
+			]
+		);
+
+		let track = render(
+			&SubtitleConfig {
+				messageSeconds: Some(5.0),
+				..plain(SubtitleFormat::Vtt)
+			},
+			&lines,
+			at(60),
+		);
+		assert_eq!(
+			cues(&track)[0],
+			"00:00:02.000 --> 00:00:04.000 line:0 position:2% align:left size:35%"
+		);
+		assert!(cues(&track)[3].starts_with("01:01:05.000 --> 01:01:10.000"));
+	}
+
+	#[test]
+	fn scrollsOutPastMaxLines() {
+		let settings = SubtitleConfig {
+			messageSeconds: Some(10.0),
+			maxLines: Some(1),
+			..plain(SubtitleFormat::Ass)
+		};
+		let track = render(
+			&settings,
+			&[line(0, "a", "one"), line(2, "b", "two")],
+			at(0),
+		);
+		assert_eq!(
+			cues(&track),
+			[
+				"Dialogue: 0,0:00:00.00,0:00:02.00,Chat,,0,0,0,,a: one",
+				"Dialogue: 0,0:00:02.00,0:00:12.00,Chat,,0,0,0,,b: two",
+			]
+		);
+	}
+
+	#[test]
+	fn closesHolesOfJoinedRecordings() {
+		let hole = RecordingGap {
+			from: at(10),
+			to: at(70),
+			seconds: 60,
+		};
+		let lines = closeHoles(
+			vec![
+				line(5, "a", "before"),
+				line(30, "b", "during"),
+				line(75, "c", "after"),
+			],
+			&[hole],
+		);
+		assert_eq!(
+			lines
+				.iter()
+				.map(|line| (line.text.as_str(), line.time))
+				.collect::<Vec<_>>(),
+			[("before", at(5)), ("after", at(15))]
+		);
+	}
+
+	#[tokio::test]
+	async fn timesRecordingsFromTheirFirstFrame() {
+		let dir = std::env::temp_dir().join(format!("ld-subtitles-{}-recording", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		let chat = dir.join("alpha.log");
+		let log = [(-5, "early"), (3, "first"), (65, "second")]
+			.map(|(seconds, text)| {
+				crate::chat::line(at(seconds), "alpha", &crate::chat::message("bob", text))
+			})
+			.concat();
+		std::fs::write(&chat, log).unwrap();
+		let video = dir.join("alpha.ts");
+		std::fs::write(&video, "").unwrap();
+
+		let mut info = RecordingInfo::bare("alpha");
+		info.startedAt = at(-30);
+		info.mediaStartedAt = Some(at(0));
+		let mut parts = vec![video.clone()];
+		forRecording(&plain(SubtitleFormat::Ass), &chat, &info, &[], &mut parts).await;
+		let track = std::fs::read_to_string(path(&video, SubtitleFormat::Ass)).unwrap();
+		std::fs::remove_dir_all(&dir).ok();
+
+		assert_eq!(parts, [video]);
+		assert_eq!(
+			cues(&track),
+			[
+				"Dialogue: 0,0:00:03.00,0:00:11.00,Chat,,0,0,0,,bob: first",
+				"Dialogue: 0,0:01:05.00,0:01:13.00,Chat,,0,0,0,,bob: second",
+			]
+		);
+	}
+}
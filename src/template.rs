@@ -0,0 +1,212 @@
+use std::path::PathBuf;
+
+use chrono::{DateTime, Local, Utc};
+
+pub const DEFAULT_TEMPLATE: &str = "{login}/{utc:%Y-%m-%d_%H-%M-%S}.ts";
+
+/// Longest a single substituted value may get, keeps titles from blowing past filename limits
+const MAX_VALUE_LEN: usize = 100;
+
+/// Details of a recording available to filename templates
+#[derive(Debug, Clone)]
+pub struct RecordingInfo {
+	pub login: String,
+	pub displayName: String,
+	pub streamId: String,
+	pub title: String,
+	pub category: String,
+	pub startedAt: DateTime<Utc>,
+	/// when twitch says the broadcast went live, recordings usually start a bit later
+	pub streamStartedAt: Option<DateTime<Utc>>,
+	/// when the first recorded frame aired, known once recording is done; earlier than `startedAt` when streamlink rewound
+	pub mediaStartedAt: Option<DateTime<Utc>>,
+	pub part: u32,
+}
+
+impl RecordingInfo {
+	/// Info for a channel when no stream metadata is available
+	pub fn bare(login: &str) -> Self {
+		RecordingInfo {
+			login: login.to_string(),
+			displayName: login.to_string(),
+			streamId: String::new(),
+			title: String::new(),
+			category: String::new(),
+			startedAt: Utc::now(),
+			streamStartedAt: None,
+			mediaStartedAt: None,
+			part: 1,
+		}
+	}
+
+	pub fn fromStream(stream: &crate::data::StreamData) -> Self {
+		RecordingInfo {
+			login: stream.user_login.clone(),
+			displayName: stream.user_name.clone(),
+			streamId: stream.id.clone(),
+			title: stream.title.clone(),
+			category: stream.game_name.clone(),
+			startedAt: Utc::now(),
+			streamStartedAt: Some(stream.started_at),
+			mediaStartedAt: None,
+			part: 1,
+		}
+	}
+}
+
+enum Segment<'a> {
+	Literal(&'a str),
+	Placeholder { name: &'a str, arg: Option<&'a str> },
+}
+
+fn parse(template: &str) -> Result<Vec<Segment<'_>>, String> {
+	let mut segments = Vec::new();
+	let mut rest = template;
+
+	while let Some(start) = rest.find('{') {
+		if start > 0 {
+			segments.push(Segment::Literal(&rest[..start]));
+		}
+		let Some(end) = rest[start..].find('}') else {
+			return Err(format!("unclosed placeholder at `{}`", &rest[start..]));
+		};
+		let inner = &rest[start + 1..start + end];
+		let (name, arg) = match inner.split_once(':') {
+			Some((name, arg)) => (name, Some(arg)),
+			None => (inner, None),
+		};
+
+		match (name, arg) {
+			("login" | "displayName" | "streamId" | "title" | "category", None) => {}
+			("utc" | "local", Some(format)) => {
+				chrono::format::StrftimeItems::new(format)
+					.parse()
+					.map_err(|_| format!("invalid time format `{format}` in `{{{inner}}}`"))?;
+			}
+			("utc" | "local", None) => {}
+			("part", None) => {}
+			("part", Some(width)) if width.parse::<usize>().is_ok() => {}
+			_ => return Err(format!("unknown placeholder `{{{inner}}}`")),
+		}
+		segments.push(Segment::Placeholder { name, arg });
+		rest = &rest[start + end + 1..];
+	}
+	if !rest.is_empty() {
+		segments.push(Segment::Literal(rest));
+	}
+
+	Ok(segments)
+}
+
+/// Checks placeholders and the resulting path shape without rendering it
+pub fn validate(template: &str) -> Result<(), String> {
+	parse(template)?;
+	if template.starts_with('/') || template.split('/').any(|part| part == "..") {
+		return Err("template has to stay within root".to_string());
+	}
+	if template.trim().is_empty() || template.ends_with('/') {
+		return Err("template has to end with a file name".to_string());
+	}
+
+	Ok(())
+}
+
+/// Matches `text` against the segments, placeholders standing for anything but a path separator;
+/// the value in place of `{login}` or `{displayName}` is kept in `channel`
+fn matches(segments: &[Segment], text: &str, channel: &mut Option<String>) -> bool {
+	match segments.split_first() {
+		None => text.is_empty(),
+		Some((Segment::Literal(literal), rest)) => text
+			.strip_prefix(literal)
+			.is_some_and(|text| matches(rest, text, channel)),
+		Some((Segment::Placeholder { name, .. }, rest)) => {
+			let end = text.find('/').unwrap_or(text.len());
+			(1..=end).filter(|&i| text.is_char_boundary(i)).any(|i| {
+				if !matches(rest, &text[i..], channel) {
+					return false;
+				}
+				if matches!(*name, "login" | "displayName") {
+					channel.get_or_insert_with(|| text[..i].to_lowercase());
+				}
+				true
+			})
+		}
+	}
+}
+
+/// Channel of a recording the template rendered, `name` is its path relative to root with the extension
+/// left off; None when it doesn't fit the template or the template doesn't name the channel
+pub fn channelOf(template: &str, name: &str) -> Option<String> {
+	let mut segments = parse(template).ok()?;
+	// the extension of the template is stripped like the one of the recording
+	if let Some(Segment::Literal(last)) = segments.last_mut() {
+		let fileStart = last.rfind('/').map_or(0, |slash| slash + 1);
+		if let Some(dot) = last[fileStart..].rfind('.') {
+			*last = &last[..fileStart + dot];
+		}
+	}
+	let mut channel = None;
+
+	match matches(&segments, name, &mut channel) {
+		true => channel,
+		false => None,
+	}
+}
+
+/// Replaces characters that break on windows/smb shares, path separators and control characters
+pub fn sanitise(value: &str) -> String {
+	let cleaned = value
+		.chars()
+		.map(|c| match c {
+			'<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*' => '_',
+			c if c.is_control() => '_',
+			c => c,
+		})
+		.take(MAX_VALUE_LEN)
+		.collect::<String>();
+	let cleaned = cleaned.trim().trim_end_matches('.').trim_end();
+
+	if cleaned.is_empty() {
+		"_".to_string()
+	} else {
+		cleaned.to_string()
+	}
+}
+
+/// Renders the template into a path relative to root
+pub fn render(template: &str, info: &RecordingInfo) -> Result<PathBuf, String> {
+	let mut out = String::new();
+	// names follow the broadcast, a recording picked up late still carries the time it went live
+	let startedAt = info.streamStartedAt.unwrap_or(info.startedAt);
+
+	for segment in parse(template)? {
+		match segment {
+			Segment::Literal(text) => out.push_str(text),
+			Segment::Placeholder { name, arg } => {
+				let value = match (name, arg) {
+					("login", _) => info.login.clone(),
+					("displayName", _) => info.displayName.clone(),
+					("streamId", _) => info.streamId.clone(),
+					("title", _) => info.title.clone(),
+					("category", _) => info.category.clone(),
+					("utc", format) => startedAt
+						.format(format.unwrap_or("%Y-%m-%d_%H-%M-%S"))
+						.to_string(),
+					("local", format) => startedAt
+						.with_timezone(&Local)
+						.format(format.unwrap_or("%Y-%m-%d_%H-%M-%S"))
+						.to_string(),
+					("part", width) => format!(
+						"{:0width$}",
+						info.part,
+						width = width.and_then(|w| w.parse().ok()).unwrap_or(0)
+					),
+					_ => unreachable!("placeholders are checked while parsing"),
+				};
+				out.push_str(&sanitise(&value));
+			}
+		}
+	}
+
+	Ok(PathBuf::from(out))
+}
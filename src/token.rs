@@ -1,94 +1,555 @@
-use std::{path::PathBuf, time::Duration};
+use std::{
+	collections::BTreeSet,
+	io::Write,
+	path::{Path, PathBuf},
+	sync::Arc,
+	time::Duration,
+};
 
+use async_trait::async_trait;
+use base64::{Engine, engine::general_purpose::STANDARD as b64};
+use chacha20poly1305::{
+	ChaCha20Poly1305, Nonce,
+	aead::{Aead, AeadCore, KeyInit, OsRng, rand_core::RngCore},
+};
 use reqwest::StatusCode;
 
 use crate::{
-	data::{Config, DeviceCodeResponse, Token},
+	data::{AuthMode, ChatSource, Config, DeviceCodeResponse, OAuthErrorResponse, Token},
 	err::Error,
 };
 
-fn tokenPath() -> PathBuf {
-	return std::env::current_dir().unwrap().join("token.json");
+/// Persists the user token between runs, implementations must not leave a partially written token behind
+#[async_trait]
+pub trait TokenStore: Send + Sync {
+	async fn load(&self) -> Result<Option<Token>, Error>;
+	async fn save(&self, token: &Token) -> Result<(), Error>;
 }
 
-pub async fn getDeviceToken(clientId: &str, clientSecret: &str) -> Result<Token, Error> {
-	let scopes = "user:read:chat";
-	let authClient = reqwest::Client::new();
+/// Plain json token file
+pub struct FileStore {
+	path: PathBuf,
+}
+
+/// Keeps the token for the lifetime of the process, used for app tokens which are cheap to reissue
+#[derive(Default)]
+pub struct MemoryStore {
+	token: std::sync::Mutex<Option<Token>>,
+}
+
+/// Token file sealed with a key derived from a passphrase (argon2 + chacha20poly1305)
+pub struct EncryptedFileStore {
+	path: PathBuf,
+	passphrase: String,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+struct Sealed {
+	version: u8,
+	salt: String,
+	nonce: String,
+	data: String,
+}
+
+/// Scopes the user token needs for the features enabled in config, plus any `extraScopes`
+pub fn requiredScopes(config: &Config) -> BTreeSet<String> {
+	let mut scopes = BTreeSet::new();
+	if config.authMode == AuthMode::App {
+		// app tokens carry no user scopes
+		return scopes;
+	}
+	// chat logging via channel.chat.message, irc reads anonymously
+	if config.chatSource == ChatSource::Eventsub {
+		scopes.insert("user:read:chat".to_string());
+	}
+
+	if let Some(extra) = &config.extraScopes {
+		scopes.extend(extra.iter().cloned());
+	}
+
+	scopes
+}
+
+pub fn missingScopes(required: &BTreeSet<String>, granted: &[String]) -> Vec<String> {
+	required
+		.iter()
+		.filter(|scope| !granted.contains(scope))
+		.cloned()
+		.collect()
+}
+
+fn tokenPath(config: &Config) -> PathBuf {
+	if let Some(path) = &config.tokenPath {
+		return PathBuf::from(path);
+	}
+	std::env::current_dir().unwrap().join("token.json")
+}
+
+/// Picks the token backend from config, a passphrase switches to the encrypted file
+pub fn store(config: &Config) -> Arc<dyn TokenStore> {
+	if config.authMode == AuthMode::App {
+		return Arc::new(MemoryStore::default());
+	}
+
+	let path = tokenPath(config);
+	match config.tokenPassphrase.clone() {
+		Some(passphrase) => Arc::new(EncryptedFileStore { path, passphrase }),
+		None => Arc::new(FileStore { path }),
+	}
+}
+
+fn readFile(path: &Path) -> Result<Option<Vec<u8>>, Error> {
+	match std::fs::read(path) {
+		Ok(content) => Ok(Some(content)),
+		Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+		Err(err) => Err(Error::TokenStorage(format!(
+			"failed to read {}: {err}",
+			path.display()
+		))),
+	}
+}
+
+/// Writes into a sibling temp file and renames it over the target, so a crash mid-write keeps the previous token intact
+fn writeAtomic(path: &Path, content: &[u8]) -> Result<(), Error> {
+	let storageErr =
+		|err: std::io::Error| Error::TokenStorage(format!("failed to write {}: {err}", path.display()));
+
+	if let Some(parent) = path.parent() {
+		std::fs::create_dir_all(parent).map_err(storageErr)?;
+	}
+	let mut tmpPath = path.as_os_str().to_owned();
+	tmpPath.push(".tmp");
+	let tmpPath = PathBuf::from(tmpPath);
+
+	let mut options = std::fs::OpenOptions::new();
+	options.write(true).create(true).truncate(true);
+	#[cfg(unix)]
+	{
+		use std::os::unix::fs::OpenOptionsExt;
+		options.mode(0o600);
+	}
+	let mut file = options.open(&tmpPath).map_err(storageErr)?;
+	// mode only applies on creation, tighten leftovers from older runs as well
+	#[cfg(unix)]
+	{
+		use std::os::unix::fs::PermissionsExt;
+		file
+			.set_permissions(std::fs::Permissions::from_mode(0o600))
+			.map_err(storageErr)?;
+	}
+	file.write_all(content).map_err(storageErr)?;
+	file.sync_all().map_err(storageErr)?;
+	drop(file);
+
+	std::fs::rename(&tmpPath, path).map_err(storageErr)
+}
+
+#[async_trait]
+impl TokenStore for FileStore {
+	async fn load(&self) -> Result<Option<Token>, Error> {
+		readFile(&self.path)?
+			.map(|content| {
+				serde_json::from_slice::<Token>(&content).map_err(|err| {
+					Error::TokenStorage(format!("malformed token {}: {err}", self.path.display()))
+				})
+			})
+			.transpose()
+	}
+
+	async fn save(&self, token: &Token) -> Result<(), Error> {
+		writeAtomic(
+			&self.path,
+			serde_json::to_string_pretty(token).unwrap().as_bytes(),
+		)
+	}
+}
+
+#[async_trait]
+impl TokenStore for MemoryStore {
+	async fn load(&self) -> Result<Option<Token>, Error> {
+		Ok(self.token.lock().unwrap().clone())
+	}
+
+	async fn save(&self, token: &Token) -> Result<(), Error> {
+		self.token.lock().unwrap().replace(token.clone());
+		Ok(())
+	}
+}
+
+impl EncryptedFileStore {
+	fn cipher(&self, salt: &[u8]) -> Result<ChaCha20Poly1305, Error> {
+		let mut key = [0u8; 32];
+		argon2::Argon2::default()
+			.hash_password_into(self.passphrase.as_bytes(), salt, &mut key)
+			.map_err(|err| Error::TokenStorage(format!("failed to derive token key: {err}")))?;
+
+		Ok(ChaCha20Poly1305::new(&key.into()))
+	}
+}
+
+#[async_trait]
+impl TokenStore for EncryptedFileStore {
+	async fn load(&self) -> Result<Option<Token>, Error> {
+		let Some(content) = readFile(&self.path)? else {
+			return Ok(None);
+		};
+		let malformed = |detail: String| {
+			Error::TokenStorage(format!("malformed token {}: {detail}", self.path.display()))
+		};
+
+		let sealed: Sealed =
+			serde_json::from_slice(&content).map_err(|err| malformed(err.to_string()))?;
+		if sealed.version != 1 {
+			return Err(malformed(format!("unknown version {}", sealed.version)));
+		}
+		let salt = b64
+			.decode(&sealed.salt)
+			.map_err(|err| malformed(err.to_string()))?;
+		let nonce = b64
+			.decode(&sealed.nonce)
+			.map_err(|err| malformed(err.to_string()))?;
+		let data = b64
+			.decode(&sealed.data)
+			.map_err(|err| malformed(err.to_string()))?;
+		if nonce.len() != 12 {
+			return Err(malformed("bad nonce length".to_string()));
+		}
+
+		let plain = self
+			.cipher(&salt)?
+			.decrypt(Nonce::from_slice(&nonce), data.as_slice())
+			.map_err(|_| Error::TokenStorage("failed to decrypt token, wrong passphrase?".to_string()))?;
+
+		serde_json::from_slice(&plain)
+			.map(Some)
+			.map_err(|err| malformed(err.to_string()))
+	}
+
+	async fn save(&self, token: &Token) -> Result<(), Error> {
+		let mut salt = [0u8; 16];
+		OsRng.fill_bytes(&mut salt);
+		let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+		let data = self
+			.cipher(&salt)?
+			.encrypt(&nonce, serde_json::to_vec(token).unwrap().as_slice())
+			.map_err(|_| Error::TokenStorage("failed to encrypt token".to_string()))?;
+		let sealed = Sealed {
+			version: 1,
+			salt: b64.encode(salt),
+			nonce: b64.encode(nonce),
+			data: b64.encode(data),
+		};
+
+		writeAtomic(
+			&self.path,
+			serde_json::to_string_pretty(&sealed).unwrap().as_bytes(),
+		)
+	}
+}
+
+async fn requestDeviceCode(
+	authClient: &reqwest::Client,
+	clientId: &str,
+	clientSecret: &str,
+	scopes: &str,
+) -> Result<DeviceCodeResponse, Error> {
 	let req = authClient
 		.post("https://id.twitch.tv/oauth2/device")
 		.query(&[
 			("client_id", clientId),
 			("client_secret", clientSecret),
-			("scopes", &scopes),
+			("scopes", scopes),
 		])
 		.build()
 		.expect("failed to build auth request");
 
-	let deviceResp: DeviceCodeResponse = serde_json::from_str(
-		&authClient
-			.execute(req)
-			.await
-			.expect("failed to retrieve auth token")
-			.text()
-			.await
-			.unwrap(),
-	)
-	.unwrap();
+	let text = readAuthResponse(authClient, req).await?;
+	let deviceResp: DeviceCodeResponse = serde_json::from_str(&text)
+		.map_err(|err| Error::Auth(format!("malformed device code response: {err}")))?;
 	log::trace!("[DVCE] {:?}", &deviceResp);
-	println!("follow the link to auth: {}", &deviceResp.verification_uri);
+
+	Ok(deviceResp)
+}
+
+/// Sends an oauth request, anything but a success comes back as an error with the response body
+async fn readAuthResponse(
+	authClient: &reqwest::Client,
+	req: reqwest::Request,
+) -> Result<String, Error> {
+	let resp = authClient
+		.execute(req)
+		.await
+		.map_err(|err| Error::Auth(err.to_string()))?;
+	let status = resp.status();
+	let text = resp
+		.text()
+		.await
+		.map_err(|err| Error::Auth(err.to_string()))?;
+	if !status.is_success() {
+		return Err(Error::Auth(format!("{status}: {}", text.trim())));
+	}
+
+	Ok(text)
+}
+
+fn printActivation(deviceResp: &DeviceCodeResponse) {
+	println!(
+		"follow the link to auth: {} (code {}, expires in {}s)",
+		&deviceResp.verification_uri, &deviceResp.user_code, deviceResp.expires_in
+	);
+
+	match qrcode::QrCode::new(deviceResp.verification_uri.as_bytes()) {
+		Ok(code) => println!(
+			"{}",
+			code
+				.render::<qrcode::render::unicode::Dense1x2>()
+				.dark_color(qrcode::render::unicode::Dense1x2::Light)
+				.light_color(qrcode::render::unicode::Dense1x2::Dark)
+				.build()
+		),
+		Err(err) => log::warn!("[DVCE] failed to render qr code: {err}"),
+	}
+}
+
+enum DevicePoll {
+	Done(Token),
+	Pending,
+	SlowDown,
+	Expired,
+}
+
+async fn pollDeviceToken(
+	authClient: &reqwest::Client,
+	clientId: &str,
+	clientSecret: &str,
+	scopes: &str,
+	deviceCode: &str,
+) -> Result<DevicePoll, Error> {
+	let deviceConfirm = authClient
+		.post("https://id.twitch.tv/oauth2/token")
+		.query(&[
+			("client_id", clientId),
+			("client_secret", clientSecret),
+			("scopes", scopes),
+			("device_code", deviceCode),
+			("grant_type", "urn:ietf:params:oauth:grant-type:device_code"),
+		])
+		.build()
+		.expect("failed to build device confirm");
+
+	let resp = match authClient.execute(deviceConfirm).await {
+		Err(e) => {
+			log::error!("err fetching auth {:?}", e);
+			return Ok(DevicePoll::Pending);
+		}
+		Ok(r) => r,
+	};
+	let status = resp.status();
+	let text = match resp.text().await {
+		Err(e) => {
+			log::error!("err reading auth {:?}", e);
+			return Ok(DevicePoll::Pending);
+		}
+		Ok(text) => text,
+	};
+	if status.is_success() {
+		return serde_json::from_str(&text)
+			.map(DevicePoll::Done)
+			.map_err(|err| Error::Auth(format!("malformed device token: {err}")));
+	}
+
+	let message = serde_json::from_str::<OAuthErrorResponse>(&text)
+		.map(|err| err.message)
+		.unwrap_or(text);
+	log::trace!("[DVCP] {} {}", status, message);
+
+	match message.as_str() {
+		"authorization_pending" => Ok(DevicePoll::Pending),
+		"slow_down" => Ok(DevicePoll::SlowDown),
+		"access_denied" => Err(Error::AuthDenied),
+		// twitch reports an expired code as an invalid one
+		"expired_token" | "invalid device code" => Ok(DevicePoll::Expired),
+		_ if status == StatusCode::BAD_REQUEST => Ok(DevicePoll::Pending),
+		_ => {
+			log::error!(
+				"[DVCP] unexpected device token response {} {}",
+				status,
+				message
+			);
+			Ok(DevicePoll::Pending)
+		}
+	}
+}
+
+/// Runs the device code grant, restarting with a fresh code whenever the previous one expires
+pub async fn getDeviceToken(
+	clientId: &str,
+	clientSecret: &str,
+	scopes: &BTreeSet<String>,
+) -> Result<Token, Error> {
+	let scopes = &scopes.iter().cloned().collect::<Vec<_>>().join(" ");
+	let authClient = reqwest::Client::new();
 
 	loop {
-		tokio::time::sleep(Duration::from_secs(5)).await;
-		let deviceConfirm = authClient
-			.post("https://id.twitch.tv/oauth2/token")
-			.query(&[
-				("client_id", clientId),
-				("client_secret", clientSecret),
-				("scopes", &scopes),
-				("device_code", &deviceResp.device_code),
-				("grant_type", "urn:ietf:params:oauth:grant-type:device_code"),
-			])
-			.build()
-			.expect("failed to build device confirm");
-
-		let result = match authClient.execute(deviceConfirm).await {
-			Err(e) => {
-				log::error!("err fetching auth {:?}", e);
-				None
+		let deviceResp = requestDeviceCode(&authClient, clientId, clientSecret, scopes).await?;
+		printActivation(&deviceResp);
+
+		let deadline = tokio::time::Instant::now() + Duration::from_secs(deviceResp.expires_in);
+		let mut interval = Duration::from_secs(deviceResp.interval.max(1));
+
+		loop {
+			tokio::time::sleep(interval).await;
+			if tokio::time::Instant::now() >= deadline {
+				log::warn!("[DVCE] device code expired, requesting a new one");
+				break;
 			}
-			Ok(r) => match r.status() {
-				StatusCode::BAD_REQUEST => None,
-				_ => Some(serde_json::from_str(&r.text().await.unwrap()).unwrap()),
-			},
-		};
 
-		if let Some(token) = result {
-			return Ok(token);
+			match pollDeviceToken(
+				&authClient,
+				clientId,
+				clientSecret,
+				scopes,
+				&deviceResp.device_code,
+			)
+			.await?
+			{
+				DevicePoll::Done(token) => return Ok(token),
+				DevicePoll::Pending => {}
+				DevicePoll::SlowDown => {
+					interval += Duration::from_secs(5);
+					log::debug!("[DVCE] slowing down polling to {:?}", interval);
+				}
+				DevicePoll::Expired => {
+					log::warn!("[DVCE] device code expired, requesting a new one");
+					break;
+				}
+			}
 		}
 	}
 }
 
-pub async fn fetchToken(config: &Config) -> Result<Token, Error> {
-	let mut token = std::fs::read(tokenPath())
-		.map(|s| serde_json::from_slice::<Token>(&s).unwrap())
-		.ok();
-	if token.is_none() {
-		token = getDeviceToken(&config.clientId, &config.clientSecret)
-			.await
-			.ok();
-		if let Some(content) = &token {
-			let text = serde_json::to_string_pretty(content).unwrap();
-			std::fs::write(tokenPath(), text).expect("Failed to save token");
-		};
+/// Client credentials grant, the resulting token can only access public helix data
+pub async fn getAppToken(clientId: &str, clientSecret: &str) -> Result<Token, Error> {
+	let authClient = reqwest::Client::new();
+	let req = authClient
+		.post("https://id.twitch.tv/oauth2/token")
+		.query(&[
+			("client_id", clientId),
+			("client_secret", clientSecret),
+			("grant_type", "client_credentials"),
+		])
+		.build()
+		.expect("failed to build app token request");
+	let resp = authClient
+		.execute(req)
+		.await
+		.map_err(|err| Error::Auth(err.to_string()))?;
+	let status = resp.status();
+	let text = resp
+		.text()
+		.await
+		.map_err(|err| Error::Auth(err.to_string()))?;
+
+	log::trace!("[APTK] {}", status);
+	if status == StatusCode::BAD_REQUEST || status == StatusCode::FORBIDDEN {
+		log::error!("[APTK] {}", text.trim());
+		return Err(Error::UnAuthorised);
+	}
+	if !status.is_success() {
+		return Err(Error::Auth(format!("{status}: {}", text.trim())));
 	}
-	let token = token.expect("Failed to retrieve token");
+
+	serde_json::from_str(&text).map_err(|err| Error::Auth(format!("malformed app token: {err}")))
+}
+
+pub async fn fetchToken(store: &dyn TokenStore, config: &Config) -> Result<Token, Error> {
+	if let Some(token) = store.load().await? {
+		return Ok(token);
+	}
+
+	authorise(store, config).await
+}
+
+/// Obtains a fresh token for the configured auth mode and persists the result
+pub async fn authorise(store: &dyn TokenStore, config: &Config) -> Result<Token, Error> {
+	let token = match config.authMode {
+		AuthMode::User => {
+			getDeviceToken(
+				&config.clientId,
+				&config.clientSecret,
+				&requiredScopes(config),
+			)
+			.await?
+		}
+		AuthMode::App => getAppToken(&config.clientId, &config.clientSecret).await?,
+	};
+	store.save(&token).await?;
 
 	Ok(token)
 }
 
-pub async fn writeRefreshToken(token: &Token) -> Result<(), Error> {
-	std::fs::write(tokenPath(), serde_json::to_string_pretty(token).unwrap())
-		.expect("Failed to save token");
+pub async fn writeRefreshToken(store: &dyn TokenStore, token: &Token) -> Result<(), Error> {
+	store.save(token).await
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn store(name: &str, passphrase: &str) -> EncryptedFileStore {
+		EncryptedFileStore {
+			path: std::env::temp_dir().join(format!("ld-token-{}-{name}.json", std::process::id())),
+			passphrase: passphrase.to_string(),
+		}
+	}
+
+	fn token() -> Token {
+		Token {
+			access_token: "access".to_string(),
+			refresh_token: "refresh".to_string(),
+			expires_in: 3600,
+		}
+	}
 
-	Ok(())
+	#[tokio::test]
+	async fn encryptedRoundTrip() {
+		let store = store("roundtrip", "hunter2");
+		store.save(&token()).await.unwrap();
+		let content = std::fs::read_to_string(&store.path).unwrap();
+		let loaded = store.load().await.unwrap().unwrap();
+		std::fs::remove_file(&store.path).ok();
+
+		assert!(!content.contains("access") && !content.contains("refresh"));
+		assert_eq!(loaded.access_token, "access");
+		assert_eq!(loaded.refresh_token, "refresh");
+		assert_eq!(loaded.expires_in, 3600);
+	}
+
+	#[tokio::test]
+	async fn wrongPassphraseIsAnError() {
+		let saved = store("passphrase", "hunter2");
+		saved.save(&token()).await.unwrap();
+		let result = store("passphrase", "hunter3").load().await;
+		std::fs::remove_file(&saved.path).ok();
+
+		assert!(
+			matches!(result, Err(Error::TokenStorage(message)) if message.contains("wrong passphrase"))
+		);
+	}
+
+	#[tokio::test]
+	async fn tamperedCiphertextIsAnError() {
+		let store = store("tampered", "hunter2");
+		store.save(&token()).await.unwrap();
+		let mut sealed: Sealed = serde_json::from_slice(&std::fs::read(&store.path).unwrap()).unwrap();
+		let mut data = b64.decode(&sealed.data).unwrap();
+		data[0] ^= 1;
+		sealed.data = b64.encode(data);
+		std::fs::write(&store.path, serde_json::to_vec(&sealed).unwrap()).unwrap();
+		let result = store.load().await;
+		std::fs::remove_file(&store.path).ok();
+
+		assert!(matches!(result, Err(Error::TokenStorage(_))));
+	}
 }